@@ -63,7 +63,7 @@ pkgs.runCommandLocal "tere"
   rmdir --ignore-fail-on-non-empty $out/bin
 
   cp -a $administrivia/* $out/
-  for svc in $out/lib/systemd/system/*.service; do
+  for svc in $out/lib/systemd/system/*.service $out/lib/systemd/user/*.service; do
     substituteInPlace "$svc" --replace "/usr/libexec/tere/" "$out/libexec/"
   done
 ''
@@ -4,6 +4,8 @@ use std::os::unix::fs::OpenOptionsExt;
 fn hash_protocol_identity() -> String {
     let walk = ignore::WalkBuilder::new("src/ipc")
         .add("src/proto")
+        // Decides the limits of messages.
+        .add("derive/src")
         // TODO If we had well-defined state machines for our protocols, we wouldn't need to include services in the hash.
         .add("src/services")
         // Deterministic output.
@@ -0,0 +1,284 @@
+//! Derive macros for `tere_server::ipc`.
+//!
+//! Use them via their re-exports, `tere_server::ipc::Message` and `tere_server::ipc::bounded::Bounded`, which document them.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{quote, quote_spanned};
+use syn::parse::{Parse, ParseStream};
+use syn::spanned::Spanned;
+use syn::{
+    parse_macro_input, parse_quote, Data, DeriveInput, Error, Expr, Field, Fields, GenericArgument,
+    Generics, Ident, Lit, Meta, NestedMeta, PathArguments, Token, Type,
+};
+
+#[proc_macro_derive(Bounded, attributes(ipc))]
+pub fn derive_bounded(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    bounded(&input)
+        .unwrap_or_else(|error| error.to_compile_error())
+        .into()
+}
+
+#[proc_macro_derive(Message, attributes(ipc))]
+pub fn derive_message(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let bounded = match bounded(&input) {
+        Ok(bounded) => bounded,
+        Err(error) => return error.to_compile_error().into(),
+    };
+
+    let name = &input.ident;
+    let mut generics = with_bounds(&input.generics);
+    for param in generics.type_params_mut() {
+        param.bounds.push(parse_quote!(::std::fmt::Debug));
+    }
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let expanded = quote! {
+        #bounded
+
+        impl #impl_generics ::tere_server::ipc::Message for #name #ty_generics #where_clause {
+            const MAX_SIZE: usize = <Self as ::tere_server::ipc::bounded::Bounded>::MAX_SIZE;
+            const MAX_FDS: usize = <Self as ::tere_server::ipc::bounded::Bounded>::MAX_FDS;
+        }
+    };
+    expanded.into()
+}
+
+/// Implement `Bounded` for the type, from the bounds of its fields.
+fn bounded(input: &DeriveInput) -> Result<TokenStream2, Error> {
+    let (max_size, max_fds) = match &input.data {
+        Data::Struct(data) => fields(&data.fields)?,
+        Data::Enum(data) => {
+            let mut max_size = quote!(0);
+            let mut max_fds = quote!(0);
+            for variant in &data.variants {
+                let (size, fds) = fields(&variant.fields)?;
+                max_size = quote!(::tere_server::ipc::bounded::max(#max_size, #size));
+                max_fds = quote!(::tere_server::ipc::bounded::max(#max_fds, #fds));
+            }
+            // bincode encodes the variant index as a varint `u32`.
+            let last_index = data.variants.len().saturating_sub(1) as u64;
+            let max_size =
+                quote!(::tere_server::ipc::bounded::varint_size(#last_index) + #max_size);
+            (max_size, max_fds)
+        }
+        Data::Union(data) => {
+            return Err(Error::new(
+                data.union_token.span,
+                "unions cannot be IPC messages",
+            ))
+        }
+    };
+
+    let name = &input.ident;
+    let generics = with_bounds(&input.generics);
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::tere_server::ipc::bounded::Bounded for #name #ty_generics #where_clause {
+            const MAX_SIZE: usize = #max_size;
+            const MAX_FDS: usize = #max_fds;
+        }
+    })
+}
+
+/// Require every type parameter to be `Bounded` too.
+fn with_bounds(generics: &Generics) -> Generics {
+    let mut generics = generics.clone();
+    for param in generics.type_params_mut() {
+        param
+            .bounds
+            .push(parse_quote!(::tere_server::ipc::bounded::Bounded));
+    }
+    generics
+}
+
+/// Sum of the bounds of all the fields, as bincode encodes them one after another.
+fn fields(fields: &Fields) -> Result<(TokenStream2, TokenStream2), Error> {
+    let mut max_size = quote!(0);
+    let mut max_fds = quote!(0);
+    for field in fields {
+        let (size, fds) = field_bounds(field)?;
+        max_size = quote!(#max_size + #size);
+        max_fds = quote!(#max_fds + #fds);
+    }
+    Ok((max_size, max_fds))
+}
+
+fn field_bounds(field: &Field) -> Result<(TokenStream2, TokenStream2), Error> {
+    let ty = &field.ty;
+    if passes_fd(field)? {
+        // Encoded as a unit, with the FD passed alongside the message.
+        return Ok((quote!(0), quote!(1)));
+    }
+
+    let mut field_attr = None;
+    for attr in field.attrs.iter().filter(|attr| attr.path.is_ident("ipc")) {
+        field_attr = Some(attr.parse_args::<FieldAttr>()?);
+    }
+    let bounded = quote!(::tere_server::ipc::bounded);
+    match field_attr {
+        Some(FieldAttr {
+            max_len,
+            max_item_len,
+        }) => {
+            let item = quote_spanned!(ty.span()=> <#ty as #bounded::Sequence>::Item);
+            let (item_size, item_fds) = match max_item_len {
+                Some(max_item_len) => (
+                    quote_spanned! {ty.span()=>
+                        <#item as #bounded::Sequence>::OVERHEAD
+                            + #bounded::length_size(#max_item_len)
+                            + (#max_item_len)
+                                * <<#item as #bounded::Sequence>::Item as #bounded::Bounded>::MAX_SIZE
+                    },
+                    quote_spanned! {ty.span()=>
+                        (#max_item_len)
+                            * <<#item as #bounded::Sequence>::Item as #bounded::Bounded>::MAX_FDS
+                    },
+                ),
+                None => {
+                    if let Some(name) = sequence_item(ty).and_then(unbounded) {
+                        return Err(Error::new(
+                            ty.span(),
+                            format!(
+                                "`{}` items need a maximum length, as in `#[ipc(max_len = 10, max_item_len = 100)]`",
+                                name
+                            ),
+                        ));
+                    }
+                    (
+                        quote_spanned!(ty.span()=> <#item as #bounded::Bounded>::MAX_SIZE),
+                        quote_spanned!(ty.span()=> <#item as #bounded::Bounded>::MAX_FDS),
+                    )
+                }
+            };
+            Ok((
+                quote_spanned! {ty.span()=>
+                    <#ty as #bounded::Sequence>::OVERHEAD
+                        + #bounded::length_size(#max_len)
+                        + (#max_len) * (#item_size)
+                },
+                quote_spanned! {ty.span()=>
+                    (#max_len) * (#item_fds)
+                },
+            ))
+        }
+        None => {
+            if let Some(name) = unbounded(ty) {
+                return Err(Error::new(
+                    ty.span(),
+                    format!(
+                        "`{}` needs a maximum length, as in `#[ipc(max_len = 100)]`",
+                        name
+                    ),
+                ));
+            }
+            Ok((
+                quote_spanned!(ty.span()=> <#ty as #bounded::Bounded>::MAX_SIZE),
+                quote_spanned!(ty.span()=> <#ty as #bounded::Bounded>::MAX_FDS),
+            ))
+        }
+    }
+}
+
+/// Is the field marked for FD passing, with `#[serde(with = "ipc::passfd")]`.
+fn passes_fd(field: &Field) -> Result<bool, Error> {
+    for attr in field
+        .attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("serde"))
+    {
+        let list = match attr.parse_meta()? {
+            Meta::List(list) => list,
+            _ => continue,
+        };
+        for nested in list.nested {
+            if let NestedMeta::Meta(Meta::NameValue(pair)) = nested {
+                if let (true, Lit::Str(with)) = (pair.path.is_ident("with"), &pair.lit) {
+                    if with.value().rsplit("::").next() == Some("passfd") {
+                        return Ok(true);
+                    }
+                }
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Name of the type, if it is a `String` or `Vec` that needs a maximum length, possibly inside an `Option`.
+fn unbounded(ty: &Type) -> Option<String> {
+    let segment = match ty {
+        Type::Path(path) => path.path.segments.last()?,
+        _ => return None,
+    };
+    match segment.ident.to_string().as_str() {
+        "String" | "Vec" => Some(segment.ident.to_string()),
+        "Option" => match &segment.arguments {
+            PathArguments::AngleBracketed(args) => match args.args.first()? {
+                GenericArgument::Type(inner) => unbounded(inner),
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Type of the items of a `Vec`, possibly inside an `Option`.
+fn sequence_item(ty: &Type) -> Option<&Type> {
+    let segment = match ty {
+        Type::Path(path) => path.path.segments.last()?,
+        _ => return None,
+    };
+    let inner = match &segment.arguments {
+        PathArguments::AngleBracketed(args) => match args.args.first()? {
+            GenericArgument::Type(inner) => inner,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    match segment.ident.to_string().as_str() {
+        "Vec" => Some(inner),
+        "Option" => sequence_item(inner),
+        _ => None,
+    }
+}
+
+/// `#[ipc(max_len = N)]`, for a `String` (in bytes) or a `Vec` (in items), or either inside an `Option`.
+///
+/// A `Vec` of `String`s or `Vec`s also needs `max_item_len = M`, the maximum length of each item.
+struct FieldAttr {
+    max_len: Expr,
+    max_item_len: Option<Expr>,
+}
+
+impl Parse for FieldAttr {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut max_len = None;
+        let mut max_item_len = None;
+        loop {
+            let name: Ident = input.parse()?;
+            input.parse::<Token![=]>()?;
+            let value: Expr = input.parse()?;
+            if name == "max_len" {
+                max_len = Some(value);
+            } else if name == "max_item_len" {
+                max_item_len = Some(value);
+            } else {
+                return Err(Error::new(
+                    name.span(),
+                    "unknown ipc attribute, expected `max_len` or `max_item_len`",
+                ));
+            }
+            if input.is_empty() {
+                break;
+            }
+            input.parse::<Token![,]>()?;
+        }
+        let max_len = max_len.ok_or_else(|| input.error("missing `max_len`"))?;
+        Ok(Self {
+            max_len,
+            max_item_len,
+        })
+    }
+}
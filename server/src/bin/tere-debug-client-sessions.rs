@@ -18,8 +18,11 @@ fn main() {
             program: None,
             args: None,
             env: None,
+            pty_options: None,
         });
         conn.send_with_fds(&message).expect("send request");
+        let response: p::Response = conn.receive_with_fds().expect("receive response");
+        println!("response: {:?}", response);
         client_conn
     };
 
@@ -38,6 +41,11 @@ fn main() {
             println!("output: {:?}", message);
             match message {
                 p::Output::SessionOutput(b) => println!("output: {}", String::from_utf8_lossy(&b)),
+                p::Output::SlowConsumer(policy) => println!("slow consumer: {:?}", policy),
+                p::Output::SessionEnded { exit_status } => {
+                    println!("session ended: {:?}", exit_status);
+                    break;
+                }
             }
             {
                 let message = p::Input::KeyboardInput(b"\x04".to_vec());
@@ -0,0 +1,9 @@
+fn main() {
+    match tere_server::services::sessions::run_local() {
+        Ok(()) => {}
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
+    };
+}
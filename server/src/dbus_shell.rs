@@ -1,10 +1,16 @@
-//! Open new shell sessions via [`systemd-machined`](https://www.freedesktop.org/software/systemd/man/systemd-machined.service.html).
+//! Open new shell sessions via [`systemd-machined`](https://www.freedesktop.org/software/systemd/man/systemd-machined.service.html), and end them via [`systemd-logind`](https://www.freedesktop.org/software/systemd/man/systemd-logind.service.html).
+//! Run non-interactive commands as transient units via [`systemd`](https://www.freedesktop.org/wiki/Software/systemd/dbus/).
 //!
 //! The primary entry point is [Dbus::new].
 
-// TODO wait on signal MachineRemoved: https://github.com/systemd/systemd/blob/ed056c560b47f84a0aa0289151f4ec91f786d24a/src/machine/machinectl.c#L1403-L1408
-
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::fs::File;
 use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use thiserror::Error;
 
 use crate::pty_master::PtyMaster;
@@ -29,12 +35,123 @@ mod proxies {
             args: &[&str],
             environment: &[&str],
         ) -> zbus::Result<(zvariant::Fd, String)>;
+
+        /// Returns name, class, service and object path of each machine.
+        fn list_machines(
+            &self,
+        ) -> zbus::Result<Vec<(String, String, String, zvariant::OwnedObjectPath)>>;
+
+        #[dbus_proxy(signal)]
+        fn machine_removed(&self, name: &str, path: zvariant::ObjectPath) -> zbus::Result<()>;
+    }
+
+    #[dbus_proxy(
+        interface = "org.freedesktop.machine1.Machine",
+        default_service = "org.freedesktop.machine1",
+        // Always used with an explicit path, from `MachineManager`.
+        default_path = "/org/freedesktop/machine1/machine/_2ehost"
+    )]
+    trait Machine {
+        #[dbus_proxy(property)]
+        fn state(&self) -> zbus::fdo::Result<String>;
+    }
+
+    #[dbus_proxy(
+        interface = "org.freedesktop.systemd1.Manager",
+        default_service = "org.freedesktop.systemd1",
+        default_path = "/org/freedesktop/systemd1"
+    )]
+    trait SystemdManager {
+        fn start_transient_unit(
+            &self,
+            name: &str,
+            mode: &str,
+            properties: &[(&str, zvariant::Value)],
+            aux: &[(&str, &[(&str, zvariant::Value)])],
+        ) -> zbus::Result<zvariant::OwnedObjectPath>;
+
+        fn get_unit(&self, name: &str) -> zbus::Result<zvariant::OwnedObjectPath>;
+
+        fn stop_unit(&self, name: &str, mode: &str) -> zbus::Result<zvariant::OwnedObjectPath>;
+
+        fn reset_failed_unit(&self, name: &str) -> zbus::Result<()>;
+
+        /// Ask for signals about units, including `PropertiesChanged`, until `Unsubscribe` or the end of our connection.
+        fn subscribe(&self) -> zbus::Result<()>;
+    }
+
+    #[dbus_proxy(
+        interface = "org.freedesktop.systemd1.Unit",
+        default_service = "org.freedesktop.systemd1",
+        // Always used with an explicit path, from `SystemdManager`.
+        default_path = "/org/freedesktop/systemd1/unit/tere_2dsessions_2eservice"
+    )]
+    trait SystemdUnit {
+        /// Keep the unit from being garbage collected, until `Unref` or the end of our connection.
+        #[dbus_proxy(name = "Ref")]
+        fn reference(&self) -> zbus::Result<()>;
+
+        #[dbus_proxy(name = "Unref")]
+        fn unreference(&self) -> zbus::Result<()>;
+    }
+
+    #[dbus_proxy(
+        interface = "org.freedesktop.systemd1.Service",
+        default_service = "org.freedesktop.systemd1",
+        // Always used with an explicit path, from `SystemdManager`.
+        default_path = "/org/freedesktop/systemd1/unit/tere_2dsessions_2eservice"
+    )]
+    trait SystemdService {
+        /// `si_code` of the main process exiting, or 0 while it is still running.
+        #[dbus_proxy(property)]
+        fn exec_main_code(&self) -> zbus::fdo::Result<i32>;
+
+        #[dbus_proxy(property)]
+        fn exec_main_status(&self) -> zbus::fdo::Result<i32>;
+
+        /// PID of the main process, or 0 when there is none.
+        #[dbus_proxy(property, name = "MainPID")]
+        fn main_pid(&self) -> zbus::fdo::Result<u32>;
+    }
+
+    #[dbus_proxy(
+        interface = "org.freedesktop.login1.Manager",
+        default_service = "org.freedesktop.login1",
+        default_path = "/org/freedesktop/login1"
+    )]
+    trait LoginManager {
+        #[dbus_proxy(name = "GetSessionByPID")]
+        fn get_session_by_pid(&self, pid: u32) -> zbus::Result<zvariant::OwnedObjectPath>;
+    }
+
+    #[dbus_proxy(
+        interface = "org.freedesktop.login1.Session",
+        default_service = "org.freedesktop.login1",
+        // Always used with an explicit path, from `LoginManager`.
+        default_path = "/org/freedesktop/login1/session/auto"
+    )]
+    trait LoginSession {
+        fn terminate(&self) -> zbus::Result<()>;
+        fn kill(&self, who: &str, signal_number: i32) -> zbus::Result<()>;
+
+        /// PID of the process that created the session.
+        #[dbus_proxy(property)]
+        fn leader(&self) -> zbus::fdo::Result<u32>;
+
+        /// Terminal of the session, relative to `/dev`, or empty.
+        #[dbus_proxy(property, name = "TTY")]
+        fn tty(&self) -> zbus::fdo::Result<String>;
     }
 }
 
-/// Client to the [`systemd-machined`](https://www.freedesktop.org/software/systemd/man/systemd-machined.service.html) D-Bus API.
+/// Client to the [`systemd-machined`](https://www.freedesktop.org/software/systemd/man/systemd-machined.service.html), [`systemd-logind`](https://www.freedesktop.org/software/systemd/man/systemd-logind.service.html) and `systemd` D-Bus APIs.
 pub struct Dbus<'a> {
+    connection: zbus::Connection,
     proxy: proxies::MachineManagerProxy<'a>,
+    login: proxies::LoginManagerProxy<'a>,
+    systemd: proxies::SystemdManagerProxy<'a>,
+    /// Units of the host shells we're watching, see [Dbus::watch_shell].
+    shells: Arc<Mutex<HashSet<String>>>,
 }
 
 #[derive(Error, Debug)]
@@ -50,7 +167,16 @@ impl Dbus<'_> {
         let proxy = proxies::MachineManagerProxy::new(&connection)
             // zbus v1.9.1 Proxy::new never fails, but still returns a Result.
             .map_err(ConnectError::Connect)?;
-        Ok(Self { proxy })
+        let login = proxies::LoginManagerProxy::new(&connection).map_err(ConnectError::Connect)?;
+        let systemd =
+            proxies::SystemdManagerProxy::new(&connection).map_err(ConnectError::Connect)?;
+        Ok(Self {
+            connection,
+            proxy,
+            login,
+            systemd,
+            shells: Arc::new(Mutex::new(HashSet::new())),
+        })
     }
 }
 
@@ -58,6 +184,57 @@ impl Dbus<'_> {
 pub enum Error {
     #[error(transparent)]
     Dbus(#[from] zbus::Error),
+
+    #[error(transparent)]
+    Fdo(#[from] zbus::fdo::Error),
+
+    #[error("commands must run as a named user other than root, not {0:?}")]
+    ForbiddenUser(String),
+
+    #[error("no such user: {0:?}")]
+    NoSuchUser(String),
+
+    #[error("error looking up user: {0}")]
+    UserLookup(#[source] std::io::Error),
+
+    #[error("process {0} is not the shell of a host session we started")]
+    NotOurSession(u32),
+}
+
+/// Broad categories of errors, for telling clients what went wrong.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Not allowed, typically by polkit.
+    Denied,
+    NoSuchMachine,
+    NoSuchUser,
+    NoSuchSession,
+    Other,
+}
+
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        let name = match self {
+            Error::Dbus(zbus::Error::MethodError(name, _detail, _message)) => name.as_str(),
+            Error::Fdo(zbus::fdo::Error::AccessDenied(_)) => return ErrorKind::Denied,
+            Error::ForbiddenUser(_) => return ErrorKind::Denied,
+            Error::NoSuchUser(_) => return ErrorKind::NoSuchUser,
+            Error::NotOurSession(_) => return ErrorKind::Denied,
+            _ => return ErrorKind::Other,
+        };
+        match name {
+            "org.freedesktop.DBus.Error.AccessDenied"
+            | "org.freedesktop.DBus.Error.InteractiveAuthorizationRequired" => ErrorKind::Denied,
+            "org.freedesktop.machine1.NoSuchMachine" => ErrorKind::NoSuchMachine,
+            "org.freedesktop.machine1.NoSuchUser" | "org.freedesktop.login1.NoSuchUser" => {
+                ErrorKind::NoSuchUser
+            }
+            "org.freedesktop.login1.NoSuchSession" | "org.freedesktop.login1.NoSessionForPID" => {
+                ErrorKind::NoSuchSession
+            }
+            _ => ErrorKind::Other,
+        }
+    }
 }
 
 /// Specification for a shell session requested.
@@ -84,10 +261,12 @@ pub struct ShellSpec<'a> {
 impl Dbus<'_> {
     /// Create a new shell session.
     ///
-    /// Returns the PTY master.
-    pub fn create_shell(&self, spec: &ShellSpec) -> Result<PtyMaster, Error> {
-        // we don't have a use for the pty name
-        let (fd, _pty_name) = self.proxy.open_machine_shell(
+    /// Returns the PTY master, and for sessions on the host, the shell to wait for.
+    pub fn create_shell(
+        &self,
+        spec: &ShellSpec,
+    ) -> Result<(PtyMaster, Option<RunningCommand>), Error> {
+        let (fd, pty_name) = self.proxy.open_machine_shell(
             spec.machine,
             spec.user,
             spec.program,
@@ -95,6 +274,420 @@ impl Dbus<'_> {
             spec.environment,
         )?;
         let pty = unsafe { PtyMaster::from_raw_fd(fd.as_raw_fd()) };
-        Ok(pty)
+        // Units of sessions in containers are managed by the service manager in the container, out of our reach.
+        let shell = if spec.machine == ".host" {
+            self.watch_shell(&pty_name)
+        } else {
+            None
+        };
+        Ok((pty, shell))
+    }
+
+    /// Hold on to the unit `systemd-machined` started for a host shell on `pty_name`, so we can see its exit status.
+    ///
+    /// Failing that, the session works just the same, so only log errors; it can't be terminated or signaled though, see [Dbus::own_session].
+    fn watch_shell(&self, pty_name: &str) -> Option<RunningCommand> {
+        let unit = shell_unit(pty_name.strip_prefix("/dev/pts/")?)?;
+        let result = (|| -> Result<(), Error> {
+            let path = self.systemd.get_unit(&unit)?;
+            proxies::SystemdUnitProxy::new_for_owned(
+                self.connection.clone(),
+                "org.freedesktop.systemd1".to_string(),
+                path.as_str().to_string(),
+            )?
+            .reference()?;
+            Ok(())
+        })();
+        match result {
+            Ok(()) => {
+                self.shells
+                    .lock()
+                    .expect("internal: shells mutex poison")
+                    .insert(unit.clone());
+                Some(RunningCommand {
+                    connection: self.connection.clone(),
+                    unit,
+                    cleanup: Cleanup::Unref(self.shells.clone()),
+                })
+            }
+            Err(error) => {
+                // TODO Proper error logging.
+                eprintln!("cannot watch unit {}: {}", unit, error);
+                None
+            }
+        }
+    }
+
+    /// Find the logind session that process `pid` leads, if it is the shell of a host session we started.
+    ///
+    /// polkit lets us terminate and signal any logind session, so this is what keeps us to our own.
+    /// The session must be led by `pid`, on the terminal of a shell unit we're watching, whose main process is `pid`.
+    fn own_session(&self, pid: u32) -> Result<proxies::LoginSessionProxy<'static>, Error> {
+        let not_ours = || Error::NotOurSession(pid);
+        let path = self.login.get_session_by_pid(pid)?;
+        let session = proxies::LoginSessionProxy::new_for_owned(
+            self.connection.clone(),
+            "org.freedesktop.login1".to_string(),
+            path.as_str().to_string(),
+        )?;
+        if session.leader()? != pid {
+            return Err(not_ours());
+        }
+        let tty = session.tty()?;
+        let unit = tty
+            .strip_prefix("pts/")
+            .and_then(shell_unit)
+            .ok_or_else(not_ours)?;
+        if !self
+            .shells
+            .lock()
+            .expect("internal: shells mutex poison")
+            .contains(&unit)
+        {
+            return Err(not_ours());
+        }
+        let path = self.systemd.get_unit(&unit)?;
+        let service = proxies::SystemdServiceProxy::new_for_owned(
+            self.connection.clone(),
+            "org.freedesktop.systemd1".to_string(),
+            path.as_str().to_string(),
+        )?;
+        if service.main_pid()? != pid {
+            return Err(not_ours());
+        }
+        Ok(session)
+    }
+
+    /// Terminate the host session that process `pid` leads.
+    ///
+    /// This ends all processes of the session, not just `pid`.
+    pub fn terminate_session(&self, pid: u32) -> Result<(), Error> {
+        self.own_session(pid)?.terminate()?;
+        Ok(())
+    }
+
+    /// Send a signal to all processes in the host session that process `pid` leads.
+    pub fn signal_session(&self, pid: u32, signal: i32) -> Result<(), Error> {
+        self.own_session(pid)?.kill("all", signal)?;
+        Ok(())
+    }
+}
+
+/// Name of the unit `systemd-machined` runs a host shell on `/dev/pts/<number>` as.
+///
+/// See <https://github.com/systemd/systemd/blob/v249/src/machine/machine-dbus.c#L720>.
+fn shell_unit(number: &str) -> Option<String> {
+    if number.is_empty() || !number.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some(format!("container-shell@{}.service", number))
+}
+
+/// Specification for a non-interactive command to run on the host.
+pub struct CommandSpec<'a> {
+    /// Username to run the command as.
+    /// Must be an existing user other than root, see [check_command_user].
+    pub user: &'a str,
+    /// Absolute path to the program to run.
+    pub program: &'a str,
+    /// Arguments to the program.
+    /// First argument should be the name of the program.
+    pub args: &'a [&'a str],
+    /// Environment variables to pass.
+    pub environment: &'a [&'a str],
+    pub stdin: &'a File,
+    pub stdout: &'a File,
+    pub stderr: &'a File,
+}
+
+/// Check that commands may run as `user`.
+///
+/// Polkit can't see the properties of a transient unit, so it can't stop us from starting one as root; we must never do that ourselves.
+/// The user must exist, and must not be root.
+/// Numbers are refused too, as systemd would take them as UIDs.
+pub fn check_command_user(user: &str) -> Result<(), Error> {
+    let forbidden = || Error::ForbiddenUser(user.to_string());
+    if user.is_empty() || user.parse::<u32>().is_ok() {
+        return Err(forbidden());
+    }
+    let name = CString::new(user).map_err(|_| forbidden())?;
+    let mut buf: Vec<libc::c_char> = vec![0; 1024];
+    loop {
+        let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut result: *mut libc::passwd = std::ptr::null_mut();
+        let ret = unsafe {
+            libc::getpwnam_r(
+                name.as_ptr(),
+                &mut pwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        };
+        if ret == libc::ERANGE {
+            let len = buf.len() * 2;
+            buf.resize(len, 0);
+            continue;
+        }
+        if ret != 0 {
+            return Err(Error::UserLookup(std::io::Error::from_raw_os_error(ret)));
+        }
+        if result.is_null() {
+            return Err(Error::NoSuchUser(user.to_string()));
+        }
+        if pwd.pw_uid == 0 {
+            return Err(forbidden());
+        }
+        return Ok(());
+    }
+}
+
+impl Dbus<'_> {
+    /// Start a command as a transient service unit, named `tere-command-*.service`.
+    ///
+    /// The polkit rules only let us act on units named like that.
+    pub fn run_command(&self, spec: &CommandSpec) -> Result<RunningCommand, Error> {
+        check_command_user(spec.user)?;
+        let unit = format!("tere-command-{:016x}.service", rand::random::<u64>());
+        let args: Vec<String> = spec.args.iter().map(|arg| arg.to_string()).collect();
+        let exec_start = vec![(spec.program.to_string(), args, false)];
+        let properties: Vec<(&str, zvariant::Value)> = vec![
+            ("Description", "Command run by Tere".into()),
+            ("User", spec.user.into()),
+            ("Type", "exec".into()),
+            ("ExecStart", exec_start.into()),
+            ("Environment", spec.environment.to_vec().into()),
+            // Home directory of `User=`.
+            ("WorkingDirectory", "~".into()),
+            // Keep the unit around after the command exits, so we can see its exit status.
+            ("RemainAfterExit", true.into()),
+            (
+                "StandardInputFileDescriptor",
+                zvariant::Fd::from(spec.stdin.as_raw_fd()).into(),
+            ),
+            (
+                "StandardOutputFileDescriptor",
+                zvariant::Fd::from(spec.stdout.as_raw_fd()).into(),
+            ),
+            (
+                "StandardErrorFileDescriptor",
+                zvariant::Fd::from(spec.stderr.as_raw_fd()).into(),
+            ),
+        ];
+        self.systemd
+            .start_transient_unit(&unit, "fail", &properties, &[])?;
+        Ok(RunningCommand {
+            connection: self.connection.clone(),
+            unit,
+            cleanup: Cleanup::Stop,
+        })
+    }
+}
+
+/// What to do with a unit once its main process has exited.
+enum Cleanup {
+    /// Stop it, for units we started with `RemainAfterExit=yes`.
+    Stop,
+    /// Let go of our reference, for units started by someone else, and forget the shell.
+    Unref(Arc<Mutex<HashSet<String>>>),
+}
+
+/// A command started with [Dbus::run_command], or the shell of a host session.
+pub struct RunningCommand {
+    connection: zbus::Connection,
+    unit: String,
+    cleanup: Cleanup,
+}
+
+impl RunningCommand {
+    /// Wait for the main process of the unit to exit, and clean up the unit.
+    pub fn wait(self) -> Result<ExitStatus, Error> {
+        let systemd = proxies::SystemdManagerProxy::new(&self.connection)?;
+        let path = systemd.get_unit(&self.unit)?;
+        let result = wait_exit(&path);
+        match self.cleanup {
+            Cleanup::Stop => {
+                // Processes the command left behind are stopped along with the unit.
+                if let Err(error) = systemd.stop_unit(&self.unit, "replace") {
+                    // TODO Proper error logging.
+                    eprintln!("error stopping unit {}: {}", self.unit, error);
+                }
+                // Only failed units stick around after stopping; for others, this fails harmlessly.
+                let _ = systemd.reset_failed_unit(&self.unit);
+            }
+            Cleanup::Unref(shells) => {
+                shells
+                    .lock()
+                    .expect("internal: shells mutex poison")
+                    .remove(&self.unit);
+                let unref = proxies::SystemdUnitProxy::new_for_owned(
+                    self.connection.clone(),
+                    "org.freedesktop.systemd1".to_string(),
+                    path.as_str().to_string(),
+                )
+                .and_then(|unit| unit.unreference());
+                if let Err(error) = unref {
+                    // TODO Proper error logging.
+                    eprintln!("error releasing unit {}: {}", self.unit, error);
+                }
+            }
+        }
+        result
+    }
+}
+
+/// Wait for the main process of the unit at `path` to exit.
+///
+/// Uses its own D-Bus connection, like [MachineWatcher], since waiting for signals would block method calls.
+fn wait_exit(path: &zvariant::ObjectPath) -> Result<ExitStatus, Error> {
+    let connection = zbus::Connection::new_system()?;
+    // systemd only sends `PropertiesChanged` for units while someone is subscribed.
+    proxies::SystemdManagerProxy::new(&connection)?.subscribe()?;
+    let properties = zbus::fdo::PropertiesProxy::new_for_owned(
+        connection.clone(),
+        "org.freedesktop.systemd1".to_string(),
+        path.as_str().to_string(),
+    )?;
+    let exited = Arc::new(AtomicBool::new(false));
+    properties.connect_properties_changed({
+        let exited = exited.clone();
+        move |interface, changed, _invalidated| {
+            if interface == "org.freedesktop.systemd1.Service"
+                && changed.contains_key("ExecMainCode")
+            {
+                exited.store(true, Ordering::Relaxed);
+            }
+            Ok(())
+        }
+    })?;
+    let service = proxies::SystemdServiceProxy::new_for_owned(
+        connection,
+        "org.freedesktop.systemd1".to_string(),
+        path.as_str().to_string(),
+    )?;
+    // The process may have exited before we subscribed, so look once before waiting.
+    let mut code = service.exec_main_code()?;
+    while code == 0 {
+        while !exited.swap(false, Ordering::Relaxed) {
+            properties.next_signal()?;
+        }
+        code = service.exec_main_code()?;
+    }
+    let status = service.exec_main_status()?;
+    Ok(wait_status(code, status))
+}
+
+/// Convert the `si_code` and `si_status` systemd reports back into a wait status.
+fn wait_status(code: i32, status: i32) -> ExitStatus {
+    let raw = match code {
+        libc::CLD_KILLED => status & 0x7f,
+        libc::CLD_DUMPED => (status & 0x7f) | 0x80,
+        _ => (status & 0xff) << 8,
+    };
+    ExitStatus::from_raw(raw)
+}
+
+/// A machine registered with `systemd-machined`.
+///
+/// The host itself is included, as `".host"`.
+#[derive(Debug, Clone)]
+pub struct MachineInfo {
+    pub name: String,
+    /// `"host"`, `"container"` or `"vm"`.
+    pub class: String,
+    /// What registered the machine, for example `"systemd-nspawn"`.
+    pub service: String,
+    /// `"opening"`, `"running"` or `"closing"`.
+    pub state: String,
+}
+
+impl Dbus<'_> {
+    /// List the machines known to `systemd-machined`.
+    pub fn list_machines(&self) -> Result<Vec<MachineInfo>, Error> {
+        let mut machines = Vec::new();
+        for (name, class, service, path) in self.proxy.list_machines()? {
+            let machine = proxies::MachineProxy::new_for_owned(
+                self.connection.clone(),
+                "org.freedesktop.machine1".to_string(),
+                path.as_str().to_string(),
+            )?;
+            let state = match machine.state() {
+                Ok(state) => state,
+                // Went away after listing.
+                Err(zbus::fdo::Error::UnknownObject(_)) => continue,
+                Err(error) => return Err(error.into()),
+            };
+            machines.push(MachineInfo {
+                name,
+                class,
+                service,
+                state,
+            });
+        }
+        Ok(machines)
+    }
+}
+
+/// Notifications about machines going away.
+///
+/// Uses its own D-Bus connection, since waiting for signals would block method calls.
+pub struct MachineWatcher<'a> {
+    proxy: proxies::MachineManagerProxy<'a>,
+}
+
+impl MachineWatcher<'_> {
+    pub fn new() -> Result<Self, ConnectError> {
+        let connection = zbus::Connection::new_system().map_err(ConnectError::Connect)?;
+        let proxy = proxies::MachineManagerProxy::new(&connection)
+            // zbus v1.9.1 Proxy::new never fails, but still returns a Result.
+            .map_err(ConnectError::Connect)?;
+        Ok(Self { proxy })
+    }
+
+    /// Call `handler` with the name of every machine removed, until an error occurs.
+    ///
+    /// See <https://github.com/systemd/systemd/blob/ed056c560b47f84a0aa0289151f4ec91f786d24a/src/machine/machinectl.c#L1403-L1408>.
+    pub fn run(&self, mut handler: impl FnMut(&str) + Send + 'static) -> Result<(), Error> {
+        self.proxy.connect_machine_removed(move |name, _path| {
+            handler(name);
+            Ok(())
+        })?;
+        loop {
+            self.proxy.next_signal()?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_command_user, shell_unit, Error};
+
+    #[test]
+    fn shell_units() {
+        assert_eq!(
+            shell_unit("3").as_deref(),
+            Some("container-shell@3.service")
+        );
+        for number in &["", "ptmx", "3/../4", "-1"] {
+            assert_eq!(shell_unit(number), None, "{:?}", number);
+        }
+    }
+
+    #[test]
+    fn command_user() {
+        assert!(check_command_user("nobody").is_ok());
+        for user in &["root", "", "0", "1000", "no\0body"] {
+            let result = check_command_user(user);
+            assert!(
+                matches!(result, Err(Error::ForbiddenUser(_))),
+                "{:?}: {:?}",
+                user,
+                result
+            );
+        }
+        assert!(matches!(
+            check_command_user("tere-test-nonexistent"),
+            Err(Error::NoSuchUser(_))
+        ));
     }
 }
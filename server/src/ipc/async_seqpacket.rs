@@ -0,0 +1,222 @@
+//! Async IPC with FD passing over UNIX domain sockets of type `SOCK_SEQPACKET`, on a [tokio] reactor.
+//!
+//! Messages are encoded and decoded exactly as with [SeqPacket], so either end of a connection can be blocking or async.
+
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Serialize};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use tokio::io::unix::AsyncFd;
+
+use crate::ipc;
+use crate::ipc::seqpacket::{self, SeqPacket};
+
+/// Implement the async IPC abstraction for UNIX domain `SOCK_SEQPACKET` sockets.
+pub struct AsyncSeqPacket {
+    socket: AsyncFd<UnixDatagram>,
+}
+
+impl AsyncSeqPacket {
+    /// Register the connection with the reactor.
+    ///
+    /// Must be called from within a [tokio] runtime with I/O enabled.
+    pub fn new(conn: SeqPacket) -> std::io::Result<Self> {
+        let socket = conn.into_socket();
+        socket.set_nonblocking(true)?;
+        let socket = AsyncFd::new(socket)?;
+        Ok(Self { socket })
+    }
+
+    /// Create a pair of sockets that are connected to each other, and prepare one side for use as `AsyncIPC`.
+    ///
+    /// See `SeqPacket::pair`.
+    pub fn pair() -> std::io::Result<(AsyncSeqPacket, UnixDatagram)> {
+        let (a, b) = SeqPacket::pair()?;
+        Ok((Self::new(a)?, b))
+    }
+}
+
+impl AsRawFd for AsyncSeqPacket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+#[async_trait]
+impl ipc::AsyncIPC for AsyncSeqPacket {
+    async fn send_with_fds<M>(&self, message: &M) -> Result<(), ipc::SendError>
+    where
+        M: 'static + ipc::Message + Serialize + Sync,
+    {
+        loop {
+            let mut guard = self
+                .socket
+                .writable()
+                .await
+                .map_err(ipc::SendError::Socket)?;
+            let result = guard.try_io(|socket| match seqpacket::send(socket.get_ref(), message) {
+                // Let the reactor know we need to wait.
+                Err(ipc::SendError::Socket(error))
+                    if error.kind() == std::io::ErrorKind::WouldBlock =>
+                {
+                    Err(error)
+                }
+                result => Ok(result),
+            });
+            match result {
+                Ok(Ok(result)) => return result,
+                Ok(Err(error)) => return Err(ipc::SendError::Socket(error)),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    async fn receive_with_fds<M>(&self) -> Result<M, ipc::ReceiveError>
+    where
+        M: 'static + ipc::Message + DeserializeOwned + Send,
+    {
+        loop {
+            let mut guard = self
+                .socket
+                .readable()
+                .await
+                .map_err(ipc::ReceiveError::Socket)?;
+            let result = guard.try_io(|socket| match seqpacket::receive(socket.get_ref()) {
+                // Let the reactor know we need to wait.
+                Err(ipc::ReceiveError::Socket(error))
+                    if error.kind() == std::io::ErrorKind::WouldBlock =>
+                {
+                    Err(error)
+                }
+                result => Ok(result),
+            });
+            match result {
+                Ok(Ok(result)) => return result,
+                Ok(Err(error)) => return Err(ipc::ReceiveError::Socket(error)),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    fn shutdown(&self, how: std::net::Shutdown) -> Result<(), ipc::ShutdownError> {
+        self.socket
+            .get_ref()
+            .shutdown(how)
+            .map_err(|source| ipc::ShutdownError::Io { how, source })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use memfd;
+    use serde::{Deserialize, Serialize};
+    use std::convert::TryFrom;
+    use std::fs::File;
+    use std::io::Read;
+    use std::os::unix::fs::FileExt;
+
+    use super::AsyncSeqPacket;
+    use crate::ipc;
+    use crate::ipc::seqpacket::SeqPacket;
+    use crate::ipc::AsyncIPC;
+    use crate::ipc::IPC;
+
+    #[derive(Serialize, Deserialize, Debug)]
+    struct Chunk(Vec<u8>);
+
+    impl ipc::Message for Chunk {}
+
+    #[derive(Serialize, Deserialize, Debug)]
+    struct WithFile {
+        greeting: String,
+        #[serde(with = "ipc::passfd")]
+        file: File,
+    }
+
+    impl ipc::Message for WithFile {
+        const MAX_FDS: usize = 1;
+    }
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .expect("tokio runtime")
+    }
+
+    #[test]
+    fn pass_fd_to_blocking() {
+        let opts = memfd::MemfdOptions::new().close_on_exec(true);
+        let file = opts.create("one").expect("memfd_create").into_file();
+        file.write_all_at(b"one", 0).expect("write to memfd");
+
+        runtime().block_on(async {
+            let (sender, receiver) = AsyncSeqPacket::pair().expect("socketpair");
+            let msg = WithFile {
+                greeting: "hello".to_string(),
+                file,
+            };
+            sender.send_with_fds(&msg).await.expect("send");
+
+            let receiver = SeqPacket::try_from(receiver).expect("SeqPacket::try_from");
+            let mut got: WithFile = receiver.receive_with_fds().expect("receive");
+            assert_eq!(got.greeting, "hello");
+            let mut content = String::new();
+            got.file
+                .read_to_string(&mut content)
+                .expect("read from memfd");
+            assert_eq!(content, "one");
+        });
+    }
+
+    #[test]
+    fn pass_fd_from_blocking() {
+        let opts = memfd::MemfdOptions::new().close_on_exec(true);
+        let file = opts.create("two").expect("memfd_create").into_file();
+        file.write_all_at(b"two", 0).expect("write to memfd");
+
+        runtime().block_on(async {
+            let (receiver, sender) = AsyncSeqPacket::pair().expect("socketpair");
+            let sender = SeqPacket::try_from(sender).expect("SeqPacket::try_from");
+            let msg = WithFile {
+                greeting: "hi".to_string(),
+                file,
+            };
+            sender.send_with_fds(&msg).expect("send");
+
+            let mut got: WithFile = receiver.receive_with_fds().await.expect("receive");
+            assert_eq!(got.greeting, "hi");
+            let mut content = String::new();
+            got.file
+                .read_to_string(&mut content)
+                .expect("read from memfd");
+            assert_eq!(content, "two");
+        });
+    }
+
+    #[test]
+    fn waits_for_room() {
+        runtime().block_on(async {
+            let (sender, receiver) = AsyncSeqPacket::pair().expect("socketpair");
+            let receiver =
+                AsyncSeqPacket::new(SeqPacket::try_from(receiver).expect("SeqPacket::try_from"))
+                    .expect("AsyncSeqPacket::new");
+            // Far more than fits in the socket buffer, so the sender has to wait for the receiver.
+            let sender = tokio::spawn(async move {
+                for i in 0..1000_u32 {
+                    let msg = Chunk(vec![i as u8; 4000]);
+                    sender.send_with_fds(&msg).await.expect("send");
+                }
+            });
+            for i in 0..1000_u32 {
+                let got: Chunk = receiver.receive_with_fds().await.expect("receive");
+                assert_eq!(got.0, vec![i as u8; 4000]);
+            }
+            sender.await.expect("sender task");
+            assert!(matches!(
+                receiver.receive_with_fds::<Chunk>().await,
+                Err(ipc::ReceiveError::End)
+            ));
+        });
+    }
+}
@@ -0,0 +1,275 @@
+//! Worst-case encoded sizes of types, for computing the limits of a [Message](super::Message).
+//!
+//! Instead of picking `MAX_SIZE` and `MAX_FDS` by hand, derive them:
+//!
+//! ```
+//! # use std::fs::File;
+//! # use serde::{Serialize,Deserialize};
+//! # use tere_server::ipc;
+//! #
+//! #[derive(Debug, Serialize, Deserialize, ipc::Message)]
+//! enum MyMessage {
+//!     Hello {
+//!         #[ipc(max_len = 64)]
+//!         name: String,
+//!         #[serde(with = "ipc::passfd")]
+//!         file: File,
+//!     },
+//!     Goodbye(Option<u32>),
+//! }
+//! ```
+//!
+//! Fields marked for FD passing count as one FD each.
+//! Every other field must implement [Bounded], or have a maximum length in `#[ipc(max_len = N)]`, in bytes for a [String] and in items for a [Vec].
+//! A [Vec] of [String]s also needs the maximum length of each item, as in `#[ipc(max_len = 10, max_item_len = 100)]`.
+//! Use `#[derive(ipc::bounded::Bounded)]` on types used in messages that are not messages themselves.
+//!
+//! This assumes the encoding of [bincode::DefaultOptions], and the default representation of [serde] derives.
+
+use std::marker::PhantomData;
+use std::time::{Duration, SystemTime};
+
+pub use tere_server_derive::Bounded;
+
+/// A type with a limit on its encoded size.
+pub trait Bounded {
+    /// Maximum size of the encoded value.
+    const MAX_SIZE: usize;
+    /// Maximum number of FDs passed with the value.
+    const MAX_FDS: usize = 0;
+}
+
+/// A type encoded as a length and that many items, usable with `#[ipc(max_len = N)]`.
+pub trait Sequence {
+    /// Size taken up by anything other than the length and the items.
+    const OVERHEAD: usize = 0;
+    /// Either [Bounded], or a [Sequence] itself, bounded by `#[ipc(max_item_len = M)]`.
+    type Item;
+}
+
+/// Size of an integer encoded as a varint, as bincode does for everything wider than a byte.
+pub const fn varint_size(n: u64) -> usize {
+    if n < 251 {
+        1
+    } else if n <= u16::MAX as u64 {
+        3
+    } else if n <= u32::MAX as u64 {
+        5
+    } else {
+        9
+    }
+}
+
+/// Size of the length prefix of a sequence of up to `max_len` items.
+pub const fn length_size(max_len: usize) -> usize {
+    varint_size(max_len as u64)
+}
+
+/// Like [std::cmp::max], usable in constants.
+#[doc(hidden)]
+pub const fn max(a: usize, b: usize) -> usize {
+    if a > b {
+        a
+    } else {
+        b
+    }
+}
+
+macro_rules! impl_bounded {
+    ($($t:ty => $size:expr),* $(,)?) => {
+        $(
+            impl Bounded for $t {
+                const MAX_SIZE: usize = $size;
+            }
+        )*
+    };
+}
+
+impl_bounded!(
+    () => 0,
+    bool => 1,
+    u8 => 1,
+    i8 => 1,
+    u16 => varint_size(u16::MAX as u64),
+    i16 => varint_size(u16::MAX as u64),
+    u32 => varint_size(u32::MAX as u64),
+    i32 => varint_size(u32::MAX as u64),
+    u64 => varint_size(u64::MAX),
+    i64 => varint_size(u64::MAX),
+    usize => varint_size(u64::MAX),
+    isize => varint_size(u64::MAX),
+    // Up to a `u128`, with one byte saying so.
+    u128 => 17,
+    i128 => 17,
+    f32 => 4,
+    f64 => 8,
+    // UTF-8, without a length.
+    char => 4,
+    // Seconds and nanoseconds, as a `u64` and a `u32`.
+    Duration => varint_size(u64::MAX) + varint_size(u32::MAX as u64),
+    SystemTime => varint_size(u64::MAX) + varint_size(u32::MAX as u64),
+);
+
+impl<T: ?Sized> Bounded for PhantomData<T> {
+    const MAX_SIZE: usize = 0;
+}
+
+impl<T: Bounded> Bounded for Option<T> {
+    const MAX_SIZE: usize = 1 + T::MAX_SIZE;
+    const MAX_FDS: usize = T::MAX_FDS;
+}
+
+impl<T: Bounded> Bounded for Box<T> {
+    const MAX_SIZE: usize = T::MAX_SIZE;
+    const MAX_FDS: usize = T::MAX_FDS;
+}
+
+/// Encoded like a tuple, without a length.
+impl<T: Bounded, const N: usize> Bounded for [T; N] {
+    const MAX_SIZE: usize = N * T::MAX_SIZE;
+    const MAX_FDS: usize = N * T::MAX_FDS;
+}
+
+macro_rules! impl_bounded_tuple {
+    ($($t:ident),*) => {
+        impl<$($t: Bounded),*> Bounded for ($($t,)*) {
+            const MAX_SIZE: usize = 0 $(+ $t::MAX_SIZE)*;
+            const MAX_FDS: usize = 0 $(+ $t::MAX_FDS)*;
+        }
+    };
+}
+
+impl_bounded_tuple!(A);
+impl_bounded_tuple!(A, B);
+impl_bounded_tuple!(A, B, C);
+impl_bounded_tuple!(A, B, C, D);
+
+/// Encoded like a `Vec<u8>`.
+impl Sequence for String {
+    type Item = u8;
+}
+
+impl<T> Sequence for Vec<T> {
+    type Item = T;
+}
+
+impl<T: Sequence> Sequence for Option<T> {
+    const OVERHEAD: usize = 1 + T::OVERHEAD;
+    type Item = T::Item;
+}
+
+#[cfg(test)]
+mod tests {
+    use bincode::Options;
+    use serde::{Deserialize, Serialize};
+    use std::os::unix::net::UnixDatagram;
+    use std::time::Duration;
+
+    use super::Bounded;
+    use crate::ipc;
+    use crate::ipc::Message;
+
+    fn encoded_size<T: Serialize>(value: &T) -> usize {
+        let config = bincode::DefaultOptions::new().with_no_limit();
+        let mut fds = Vec::new();
+        let encoded = ipc::passfd::gather_fds_to_vec(&mut fds, || {
+            config.serialize(value).expect("serialize")
+        });
+        encoded.len()
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, ipc::bounded::Bounded)]
+    enum Inner {
+        Nothing,
+        Number(i64),
+        Wait { duration: Duration },
+    }
+
+    #[derive(Debug, Serialize, Deserialize, ipc::Message)]
+    struct Worst {
+        flag: bool,
+        inner: Inner,
+        pair: (u16, Option<char>),
+        id: [u8; 4],
+        #[ipc(max_len = 300)]
+        name: String,
+        #[ipc(max_len = 3)]
+        list: Option<Vec<Inner>>,
+        #[ipc(max_len = 2, max_item_len = 260)]
+        words: Vec<String>,
+    }
+
+    #[test]
+    fn worst_case_is_exact() {
+        let longest = Inner::Wait {
+            duration: Duration::new(u64::MAX, 999_999_999),
+        };
+        assert_eq!(encoded_size(&longest), Inner::MAX_SIZE);
+
+        let worst = Worst {
+            flag: true,
+            inner: longest.clone(),
+            pair: (u16::MAX, Some('\u{10ffff}')),
+            id: [0xff; 4],
+            name: "x".repeat(300),
+            list: Some(vec![longest; 3]),
+            words: vec!["x".repeat(260); 2],
+        };
+        assert_eq!(encoded_size(&worst), <Worst as Message>::MAX_SIZE);
+        assert_eq!(<Worst as Message>::MAX_FDS, 0);
+    }
+
+    #[derive(Debug, Serialize, Deserialize, ipc::Message)]
+    enum WithFds {
+        One {
+            #[serde(with = "ipc::passfd")]
+            fd: UnixDatagram,
+        },
+        Two(
+            #[serde(with = "ipc::passfd")] UnixDatagram,
+            #[serde(with = "ipc::passfd")] UnixDatagram,
+        ),
+        Neither,
+    }
+
+    #[test]
+    fn counts_fds() {
+        assert_eq!(<WithFds as Message>::MAX_FDS, 2);
+        // Just the variant index, the FDs are passed outside the message.
+        assert_eq!(<WithFds as Message>::MAX_SIZE, 1);
+        let (a, b) = UnixDatagram::pair().expect("socketpair");
+        assert_eq!(
+            encoded_size(&WithFds::Two(a, b)),
+            <WithFds as Message>::MAX_SIZE
+        );
+    }
+
+    #[derive(Debug, Serialize, Deserialize, ipc::Message)]
+    struct Generic<T> {
+        value: T,
+    }
+
+    #[test]
+    fn generic() {
+        assert_eq!(<Generic<u32> as Message>::MAX_SIZE, 5);
+        assert_eq!(<Generic<Option<u8>> as Message>::MAX_SIZE, 2);
+    }
+
+    #[test]
+    fn varint_size() {
+        for n in &[
+            0,
+            250,
+            251,
+            65535,
+            65536,
+            u32::MAX as u64,
+            u32::MAX as u64 + 1,
+            u64::MAX,
+        ] {
+            assert_eq!(encoded_size(n), super::varint_size(*n), "{}", n);
+        }
+        assert_eq!(encoded_size(&i32::MIN), i32::MAX_SIZE);
+        assert_eq!(encoded_size(&u128::MAX), u128::MAX_SIZE);
+    }
+}
@@ -0,0 +1,771 @@
+//! Carry many logical circuits over one IPC connection.
+//!
+//! See `doc/dev/sketch/ipc.md` for the design.
+//!
+//! Every circuit is virtually open at all times, there are no open/close messages.
+//! A circuit is idle when neither side is using it, and busy from the first message sent on it until both sides have ended it.
+//! Ending a circuit is like shutting down writing on a socket: the other side sees [ReceiveError::End](ipc::ReceiveError::End) once it has received everything before that.
+//! Dropping a [Circuit] ends it, and discards anything the other side still sends on it.
+//!
+//! Circuits with IDs below the `reserved` count given to [Mux::new] are for purposes previously agreed on by both sides, such as a control channel, and are claimed with [Mux::reserved].
+//! Other circuits are interchangeable: [Mux::open] takes an idle one, and the other side sees it from [Mux::accept].
+//! To avoid both sides taking the same circuit at the same time, the [Side::Client] only opens even-numbered circuits, and the [Side::Server] odd-numbered ones.
+//!
+//! A [Circuit] is itself an [IPC](ipc::IPC), and passes FDs when the underlying connection does.
+//!
+//! There is no backpressure yet: messages received for a circuit queue up until it is read from.
+
+use bincode::Options;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use thiserror::Error;
+
+use crate::ipc;
+use crate::ipc::ownedfd::OwnedFd;
+
+/// Maximum size of an encoded message sent on a circuit.
+pub const MAX_SIZE: usize = 60 * 1024;
+
+/// Maximum number of FDs in a message sent on a circuit.
+pub const MAX_FDS: usize = 16;
+
+/// An integer type used for circuit IDs.
+/// Its size decides the maximum number of circuits on one connection.
+pub trait CircuitId:
+    Copy + Eq + Hash + Debug + Serialize + DeserializeOwned + Send + Sync + 'static
+{
+    fn from_index(index: usize) -> Option<Self>;
+    fn index(self) -> usize;
+}
+
+macro_rules! impl_circuit_id {
+    ($($t:ty),*) => {
+        $(
+            impl CircuitId for $t {
+                fn from_index(index: usize) -> Option<Self> {
+                    <$t>::try_from(index).ok()
+                }
+
+                fn index(self) -> usize {
+                    self as usize
+                }
+            }
+        )*
+    };
+}
+
+impl_circuit_id!(u8, u16, u32);
+
+/// Which end of the connection we are, deciding which circuits we may open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Client,
+    Server,
+}
+
+impl Side {
+    fn parity(&self) -> usize {
+        match self {
+            Side::Client => 0,
+            Side::Server => 1,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct SendFd(#[serde(with = "ipc::passfd")] RawFd);
+
+#[derive(Debug, Deserialize)]
+struct ReceivedFd(#[serde(with = "ipc::passfd")] OwnedFd);
+
+#[derive(Debug, Serialize)]
+enum SendBody {
+    Message { payload: Vec<u8>, fds: Vec<SendFd> },
+    End,
+}
+
+#[derive(Debug, Serialize)]
+struct SendFrame<C> {
+    circuit: C,
+    body: SendBody,
+}
+
+impl<C: CircuitId> ipc::Message for SendFrame<C> {
+    const MAX_SIZE: usize = <Frame<C> as ipc::Message>::MAX_SIZE;
+    const MAX_FDS: usize = <Frame<C> as ipc::Message>::MAX_FDS;
+}
+
+#[derive(Debug, Deserialize)]
+enum Body {
+    Message {
+        payload: Vec<u8>,
+        fds: Vec<ReceivedFd>,
+    },
+    End,
+}
+
+/// What goes over the underlying connection.
+#[derive(Debug, Deserialize)]
+struct Frame<C> {
+    circuit: C,
+    body: Body,
+}
+
+impl<C: CircuitId> ipc::Message for Frame<C> {
+    // Room for the circuit ID, the enum tag and the length prefixes.
+    const MAX_SIZE: usize = MAX_SIZE + 32;
+    const MAX_FDS: usize = MAX_FDS;
+}
+
+/// Limits of the frames on the underlying connection, for when it is a [Stream](ipc::stream::Stream).
+pub fn limits<C: CircuitId>() -> ipc::stream::Limits {
+    ipc::stream::Limits::of::<Frame<C>>()
+}
+
+#[derive(Error, Debug)]
+pub enum OpenError {
+    #[error("all circuits are busy")]
+    Exhausted,
+
+    #[error("circuit is not reserved: {0}")]
+    NotReserved(usize),
+
+    #[error("reserved circuit is already in use: {0}")]
+    Busy(usize),
+
+    #[error("connection is closed")]
+    Closed,
+}
+
+#[derive(Error, Debug)]
+pub enum RunError {
+    #[error("socket receive error: {0}")]
+    Receive(#[source] ipc::ReceiveError),
+
+    #[error("peer used a circuit it may not open: {0}")]
+    NotPeerCircuit(usize),
+
+    #[error("peer sent on a circuit it had already ended: {0}")]
+    AfterEnd(usize),
+}
+
+struct Slot {
+    /// Tells apart uses of the same circuit ID, so a stale [Circuit] can't affect a later one.
+    generation: u64,
+    /// A [Circuit] has been handed out for this.
+    claimed: bool,
+    /// Our side has sent its end.
+    local_ended: bool,
+    /// The other side has sent its end.
+    remote_ended: bool,
+    /// We're not interested in what the other side sends anymore.
+    discard: bool,
+    incoming: VecDeque<(Vec<u8>, VecDeque<OwnedFd>)>,
+}
+
+struct State<C> {
+    /// Busy circuits.
+    slots: HashMap<C, Slot>,
+    /// Circuits opened by the other side, waiting for [Mux::accept].
+    accept: VecDeque<(C, u64)>,
+    next_generation: u64,
+    /// The underlying connection has ended.
+    closed: bool,
+}
+
+impl<C: CircuitId> State<C> {
+    fn new_slot(&mut self, id: C, claimed: bool) -> u64 {
+        let generation = self.next_generation;
+        self.next_generation += 1;
+        self.slots.insert(
+            id,
+            Slot {
+                generation,
+                claimed,
+                local_ended: false,
+                remote_ended: false,
+                discard: false,
+                incoming: VecDeque::new(),
+            },
+        );
+        generation
+    }
+
+    fn slot(&mut self, id: C, generation: u64) -> Option<&mut Slot> {
+        self.slots
+            .get_mut(&id)
+            .filter(|slot| slot.generation == generation)
+    }
+
+    /// Make the circuit idle, once both sides are done with it.
+    fn release_if_done(&mut self, id: C) {
+        if let Some(slot) = self.slots.get(&id) {
+            if slot.local_ended && slot.remote_ended {
+                self.slots.remove(&id);
+            }
+        }
+    }
+}
+
+struct Shared<I, C> {
+    conn: I,
+    side: Side,
+    reserved: usize,
+    state: Mutex<State<C>>,
+    changed: Condvar,
+    /// Held while sending, so nothing is sent on a circuit after its end.
+    sending: Mutex<()>,
+}
+
+impl<I: ipc::IPC, C: CircuitId> Shared<I, C> {
+    fn lock(&self) -> MutexGuard<State<C>> {
+        self.state.lock().expect("internal: circuits mutex poison")
+    }
+
+    fn send_end(&self, id: C, generation: u64) -> Result<(), ipc::SendError> {
+        let _sending = self
+            .sending
+            .lock()
+            .expect("internal: circuits mutex poison");
+        match self.lock().slot(id, generation) {
+            Some(slot) if !slot.local_ended => (),
+            _ => return Ok(()),
+        }
+        let frame = SendFrame {
+            circuit: id,
+            body: SendBody::End,
+        };
+        let result = self.conn.send_with_fds(&frame);
+        // Even if sending failed, there's no sending on this circuit after trying to end it.
+        let mut state = self.lock();
+        if let Some(slot) = state.slot(id, generation) {
+            slot.local_ended = true;
+        }
+        state.release_if_done(id);
+        result
+    }
+
+    fn stop_receiving(&self, id: C, generation: u64) {
+        let mut state = self.lock();
+        if let Some(slot) = state.slot(id, generation) {
+            slot.discard = true;
+            slot.incoming.clear();
+        }
+        self.changed.notify_all();
+    }
+}
+
+/// Multiplex circuits over the connection `conn`.
+///
+/// Nothing is received until [Mux::run] is called, usually in a thread of its own.
+pub struct Mux<I, C> {
+    shared: Arc<Shared<I, C>>,
+}
+
+impl<I, C> Clone for Mux<I, C> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<I, C> Mux<I, C>
+where
+    I: ipc::IPC,
+    C: CircuitId,
+{
+    /// Circuits `0..reserved` are set aside for [Mux::reserved], and must be agreed on by both sides.
+    pub fn new(conn: I, side: Side, reserved: usize) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                conn,
+                side,
+                reserved,
+                state: Mutex::new(State {
+                    slots: HashMap::new(),
+                    accept: VecDeque::new(),
+                    next_generation: 0,
+                    closed: false,
+                }),
+                changed: Condvar::new(),
+                sending: Mutex::new(()),
+            }),
+        }
+    }
+
+    fn circuit(&self, id: C, generation: u64) -> Circuit<I, C> {
+        Circuit {
+            shared: self.shared.clone(),
+            id,
+            generation,
+        }
+    }
+
+    /// Claim a reserved circuit.
+    pub fn reserved(&self, index: usize) -> Result<Circuit<I, C>, OpenError> {
+        if index >= self.shared.reserved {
+            return Err(OpenError::NotReserved(index));
+        }
+        let id = C::from_index(index).ok_or(OpenError::NotReserved(index))?;
+        let mut state = self.shared.lock();
+        if state.closed {
+            return Err(OpenError::Closed);
+        }
+        let generation = match state.slots.get_mut(&id) {
+            // The other side got there first.
+            Some(slot) if !slot.claimed => {
+                slot.claimed = true;
+                slot.generation
+            }
+            Some(_) => return Err(OpenError::Busy(index)),
+            None => state.new_slot(id, true),
+        };
+        Ok(self.circuit(id, generation))
+    }
+
+    /// Take an idle circuit into use.
+    pub fn open(&self) -> Result<Circuit<I, C>, OpenError> {
+        let parity = self.shared.side.parity();
+        let mut index = self.shared.reserved;
+        if index % 2 != parity {
+            index += 1;
+        }
+        let mut state = self.shared.lock();
+        if state.closed {
+            return Err(OpenError::Closed);
+        }
+        loop {
+            let id = C::from_index(index).ok_or(OpenError::Exhausted)?;
+            if !state.slots.contains_key(&id) {
+                let generation = state.new_slot(id, true);
+                return Ok(self.circuit(id, generation));
+            }
+            index += 2;
+        }
+    }
+
+    /// Wait for the other side to open a circuit.
+    ///
+    /// Returns `None` once the connection has ended.
+    pub fn accept(&self) -> Option<Circuit<I, C>> {
+        let mut state = self.shared.lock();
+        loop {
+            if let Some((id, generation)) = state.accept.pop_front() {
+                return Some(self.circuit(id, generation));
+            }
+            if state.closed {
+                return None;
+            }
+            state = self
+                .shared
+                .changed
+                .wait(state)
+                .expect("internal: circuits mutex poison");
+        }
+    }
+
+    fn dispatch(&self, frame: Frame<C>) -> Result<(), RunError> {
+        let index = frame.circuit.index();
+        let mut state = self.shared.lock();
+        if !state.slots.contains_key(&frame.circuit) {
+            if index >= self.shared.reserved && index % 2 == self.shared.side.parity() {
+                return Err(RunError::NotPeerCircuit(index));
+            }
+            let generation = state.new_slot(frame.circuit, false);
+            if index >= self.shared.reserved {
+                state.accept.push_back((frame.circuit, generation));
+            }
+        }
+        let slot = state
+            .slots
+            .get_mut(&frame.circuit)
+            .expect("internal: circuit slot just created");
+        if slot.remote_ended {
+            return Err(RunError::AfterEnd(index));
+        }
+        match frame.body {
+            Body::Message { payload, fds } => {
+                if !slot.discard {
+                    let fds = fds.into_iter().map(|ReceivedFd(fd)| fd).collect();
+                    slot.incoming.push_back((payload, fds));
+                }
+            }
+            Body::End => {
+                slot.remote_ended = true;
+                state.release_if_done(frame.circuit);
+            }
+        }
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+
+    /// Receive from the connection and pass messages on to their circuits, until the connection ends.
+    pub fn run(&self) -> Result<(), RunError> {
+        let result = loop {
+            let frame: Frame<C> = match self.shared.conn.receive_with_fds() {
+                Ok(frame) => frame,
+                Err(ipc::ReceiveError::End) => break Ok(()),
+                Err(error) => break Err(RunError::Receive(error)),
+            };
+            if let Err(error) = self.dispatch(frame) {
+                break Err(error);
+            }
+        };
+        let mut state = self.shared.lock();
+        state.closed = true;
+        self.shared.changed.notify_all();
+        result
+    }
+}
+
+/// One logical connection, carried by a [Mux].
+pub struct Circuit<I, C>
+where
+    I: ipc::IPC,
+    C: CircuitId,
+{
+    shared: Arc<Shared<I, C>>,
+    id: C,
+    generation: u64,
+}
+
+impl<I, C> Circuit<I, C>
+where
+    I: ipc::IPC,
+    C: CircuitId,
+{
+    pub fn id(&self) -> C {
+        self.id
+    }
+}
+
+impl<I, C> Drop for Circuit<I, C>
+where
+    I: ipc::IPC,
+    C: CircuitId,
+{
+    fn drop(&mut self) {
+        self.shared.stop_receiving(self.id, self.generation);
+        // Errors mean the connection is broken, and the other side won't be waiting for this anymore.
+        let _ = self.shared.send_end(self.id, self.generation);
+    }
+}
+
+impl<I, C> ipc::IPC for Circuit<I, C>
+where
+    I: ipc::IPC,
+    C: CircuitId,
+{
+    fn send_with_fds<M>(&self, message: &M) -> Result<(), ipc::SendError>
+    where
+        M: ipc::Message + Serialize,
+    {
+        let config = bincode::DefaultOptions::new()
+            // MUST use with_no_limit or fds are serialized twice
+            .with_no_limit();
+
+        let mut fds: Vec<RawFd> = Vec::new();
+        let mut payload = Vec::new();
+        ipc::passfd::gather_fds_to_vec(&mut fds, || {
+            config
+                .serialize_into(&mut payload, &message)
+                .map_err(ipc::SendError::Serialize)
+        })?;
+        ipc::check_limits(
+            payload.len(),
+            fds.len(),
+            M::MAX_SIZE.min(MAX_SIZE),
+            M::MAX_FDS.min(MAX_FDS),
+        )?;
+
+        let _sending = self
+            .shared
+            .sending
+            .lock()
+            .expect("internal: circuits mutex poison");
+        {
+            let mut state = self.shared.lock();
+            let usable =
+                matches!(state.slot(self.id, self.generation), Some(slot) if !slot.local_ended);
+            if !usable {
+                return Err(ipc::SendError::Socket(
+                    std::io::ErrorKind::BrokenPipe.into(),
+                ));
+            }
+        }
+        let frame = SendFrame {
+            circuit: self.id,
+            body: SendBody::Message {
+                payload,
+                fds: fds.into_iter().map(SendFd).collect(),
+            },
+        };
+        self.shared.conn.send_with_fds(&frame)
+    }
+
+    fn receive_with_fds<M>(&self) -> Result<M, ipc::ReceiveError>
+    where
+        M: ipc::Message + DeserializeOwned,
+    {
+        let config = bincode::DefaultOptions::new()
+            // MUST use with_no_limit or fds are serialized twice
+            .with_no_limit();
+
+        let (payload, mut fds) = {
+            let mut state = self.shared.lock();
+            loop {
+                let closed = state.closed;
+                let slot = match state.slot(self.id, self.generation) {
+                    None => return Err(ipc::ReceiveError::End),
+                    Some(slot) => slot,
+                };
+                if let Some(received) = slot.incoming.pop_front() {
+                    break received;
+                }
+                if slot.remote_ended || slot.discard || closed {
+                    return Err(ipc::ReceiveError::End);
+                }
+                state = self
+                    .shared
+                    .changed
+                    .wait(state)
+                    .expect("internal: circuits mutex poison");
+            }
+        };
+
+        if payload.len() > M::MAX_SIZE {
+            return Err(ipc::ReceiveError::TooLarge);
+        }
+        let orig_num_fds = fds.len();
+        let msg = ipc::passfd::scatter_fds_from_vec_deque(
+            &mut fds,
+            || -> Result<M, ipc::ReceiveError> {
+                config
+                    .deserialize(&payload)
+                    .map_err(ipc::ReceiveError::Deserialize)
+            },
+        )?;
+        let fds_left = fds.len();
+        if fds_left != 0 {
+            return Err(ipc::ReceiveError::TooManyFds {
+                orig: orig_num_fds,
+                extra: fds_left,
+            });
+        }
+        Ok(msg)
+    }
+
+    fn flush(&self) -> Result<(), ipc::SendError> {
+        self.shared.conn.flush()
+    }
+
+    fn shutdown(&self, how: std::net::Shutdown) -> Result<(), ipc::ShutdownError> {
+        if how != std::net::Shutdown::Write {
+            self.shared.stop_receiving(self.id, self.generation);
+        }
+        if how != std::net::Shutdown::Read {
+            self.shared
+                .send_end(self.id, self.generation)
+                .map_err(|error| ipc::ShutdownError::Io {
+                    how,
+                    source: match error {
+                        ipc::SendError::Socket(error) => error,
+                        error => std::io::Error::new(std::io::ErrorKind::Other, error),
+                    },
+                })?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use memfd;
+    use serde::{Deserialize, Serialize};
+    use std::convert::TryFrom;
+    use std::fs::File;
+    use std::io::Read;
+    use std::os::unix::fs::FileExt;
+
+    use super::{Mux, OpenError, Side};
+    use crate::ipc;
+    use crate::ipc::seqpacket::SeqPacket;
+    use crate::ipc::stream::Stream;
+    use crate::ipc::IPC;
+
+    #[derive(Serialize, Deserialize, Debug)]
+    struct Text(String);
+
+    impl ipc::Message for Text {}
+
+    #[derive(Serialize, Deserialize, Debug)]
+    struct WithFile {
+        #[serde(with = "ipc::passfd")]
+        file: File,
+    }
+
+    impl ipc::Message for WithFile {
+        const MAX_FDS: usize = 1;
+    }
+
+    /// Connected client and server, each with its receiving running in a thread.
+    fn pair() -> (Mux<SeqPacket, u16>, Mux<SeqPacket, u16>) {
+        let (a, b) = SeqPacket::pair().expect("socketpair");
+        let b = SeqPacket::try_from(b).expect("SeqPacket::try_from");
+        let client = Mux::new(a, Side::Client, 1);
+        let server = Mux::new(b, Side::Server, 1);
+        for mux in [client.clone(), server.clone()].iter().cloned() {
+            std::thread::spawn(move || mux.run());
+        }
+        (client, server)
+    }
+
+    fn send(conn: &impl IPC, text: &str) {
+        conn.send_with_fds(&Text(text.to_string())).expect("send");
+    }
+
+    fn receive(conn: &impl IPC) -> String {
+        let Text(text) = conn.receive_with_fds().expect("receive");
+        text
+    }
+
+    #[test]
+    fn reserved() {
+        let (client, server) = pair();
+        let client_control = client.reserved(0).expect("reserved");
+        send(&client_control, "hello");
+        let server_control = server.reserved(0).expect("reserved");
+        assert_eq!(receive(&server_control), "hello");
+        send(&server_control, "hi");
+        assert_eq!(receive(&client_control), "hi");
+
+        assert!(matches!(client.reserved(0), Err(OpenError::Busy(0))));
+        assert!(matches!(client.reserved(1), Err(OpenError::NotReserved(1))));
+    }
+
+    #[test]
+    fn handshake() {
+        let (client, server) = pair();
+        let server_task = std::thread::spawn(move || {
+            let conn = server.accept().expect("accept");
+            ipc::handshake::handshake_as_server(&conn, "xyzzy client", "xyzzy server")
+        });
+        let conn = client.open().expect("open");
+        ipc::handshake::handshake_as_client(&conn, "xyzzy client", "xyzzy server")
+            .expect("handshake as client");
+        server_task.join().unwrap().expect("handshake as server");
+    }
+
+    #[test]
+    fn concurrent() {
+        let (client, server) = pair();
+        let one = client.open().expect("open");
+        let two = client.open().expect("open");
+        assert_ne!(one.id(), two.id());
+        send(&one, "one");
+        send(&two, "two");
+        send(&one, "three");
+
+        let server_one = server.accept().expect("accept");
+        let server_two = server.accept().expect("accept");
+        assert_eq!((server_one.id(), server_two.id()), (one.id(), two.id()));
+        assert_eq!(receive(&server_two), "two");
+        assert_eq!(receive(&server_one), "one");
+        assert_eq!(receive(&server_one), "three");
+
+        // Both sides can open circuits at the same time, without colliding.
+        let three = server.open().expect("open");
+        assert_ne!(three.id() % 2, one.id() % 2);
+        send(&three, "four");
+        assert_eq!(receive(&client.accept().expect("accept")), "four");
+    }
+
+    #[test]
+    fn end_and_reuse() {
+        let (client, server) = pair();
+        let conn = client.open().expect("open");
+        let id = conn.id();
+        send(&conn, "bye");
+        conn.shutdown(std::net::Shutdown::Write).expect("shutdown");
+        assert!(conn.send_with_fds(&Text("more".to_string())).is_err());
+
+        let server_conn = server.accept().expect("accept");
+        assert_eq!(receive(&server_conn), "bye");
+        assert!(matches!(
+            server_conn.receive_with_fds::<Text>(),
+            Err(ipc::ReceiveError::End)
+        ));
+        drop(server_conn);
+        assert!(matches!(
+            conn.receive_with_fds::<Text>(),
+            Err(ipc::ReceiveError::End)
+        ));
+
+        // Once both sides are done, the circuit is idle and can be used again.
+        drop(conn);
+        let again = client.open().expect("open");
+        assert_eq!(again.id(), id);
+        send(&again, "hello again");
+        let server_again = server.accept().expect("accept");
+        assert_eq!(receive(&server_again), "hello again");
+    }
+
+    #[test]
+    fn pass_fd() {
+        let opts = memfd::MemfdOptions::new().close_on_exec(true);
+        let file = opts.create("one").expect("memfd_create").into_file();
+        file.write_all_at(b"one", 0).expect("write to memfd");
+
+        let (client, server) = pair();
+        let conn = client.open().expect("open");
+        conn.send_with_fds(&WithFile { file })
+            .expect("send with FD");
+        let server_conn = server.accept().expect("accept");
+        let mut got: WithFile = server_conn.receive_with_fds().expect("receive with FD");
+        let mut content = String::new();
+        got.file
+            .read_to_string(&mut content)
+            .expect("read from memfd");
+        assert_eq!(content, "one");
+    }
+
+    #[test]
+    fn over_stream() {
+        let limits = super::limits::<u8>();
+        let (a, b) = Stream::pair(limits).expect("socketpair");
+        let client: Mux<Stream, u8> = Mux::new(a, Side::Client, 0);
+        let server: Mux<Stream, u8> = Mux::new(Stream::new(b, limits), Side::Server, 0);
+        for mux in [client.clone(), server.clone()].iter().cloned() {
+            std::thread::spawn(move || mux.run());
+        }
+        let conn = client.open().expect("open");
+        send(&conn, "buffered");
+        conn.flush().expect("flush");
+        assert_eq!(receive(&server.accept().expect("accept")), "buffered");
+    }
+
+    #[test]
+    fn connection_ends() {
+        let (a, b) = SeqPacket::pair().expect("socketpair");
+        let client: Mux<SeqPacket, u8> = Mux::new(a, Side::Client, 0);
+        let client_task = std::thread::spawn({
+            let client = client.clone();
+            move || client.run()
+        });
+        let conn = client.open().expect("open");
+        drop(b);
+        client_task.join().unwrap().expect("run");
+        assert!(matches!(
+            conn.receive_with_fds::<Text>(),
+            Err(ipc::ReceiveError::End)
+        ));
+        assert!(client.accept().is_none());
+        assert!(matches!(client.open(), Err(OpenError::Closed)));
+    }
+}
@@ -11,8 +11,12 @@ fn identify(context: &'static str) -> blake3::Hash {
     blake3::Hash::from(out)
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Longest intent a peer may send.
+const MAX_INTENT_LEN: usize = 128;
+
+#[derive(Debug, Serialize, Deserialize, ipc::Message)]
 struct Handshake {
+    #[ipc(max_len = MAX_INTENT_LEN)]
     intent: String,
     // TODO make my own type that combines blake3::Hash and Serialize/Deserialize with nicer output
     build_id: [u8; 32],
@@ -25,9 +29,23 @@ impl Handshake {
             build_id: *identify(intent).as_bytes(),
         }
     }
+
+    /// Is the peer who we expect it to be.
+    fn check(&self, intent: &'static str) -> Result<(), Error> {
+        if identify(intent) != self.build_id {
+            return Err(Error::WrongVersion);
+        }
+        if self.intent != intent {
+            return Err(Error::WrongService);
+        }
+        Ok(())
+    }
 }
 
-impl ipc::Message for Handshake {}
+/// Limits of the handshake, for [Stream](ipc::stream::Stream) connections that start with one.
+pub fn limits() -> ipc::stream::Limits {
+    ipc::stream::Limits::of::<Handshake>()
+}
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -51,15 +69,9 @@ pub fn handshake_as_client(
 ) -> Result<(), Error> {
     conn.send_with_fds(&Handshake::new(client_intent))
         .map_err(Error::Send)?;
+    conn.flush().map_err(Error::Send)?;
     let msg: Handshake = conn.receive_with_fds().map_err(Error::Receive)?;
-    let server_build_id = identify(server_intent);
-    if server_build_id != msg.build_id {
-        return Err(Error::WrongVersion);
-    }
-    if msg.intent != server_intent {
-        return Err(Error::WrongService);
-    }
-    Ok(())
+    msg.check(server_intent)
 }
 
 pub fn handshake_as_server(
@@ -68,14 +80,36 @@ pub fn handshake_as_server(
     server_intent: &'static str,
 ) -> Result<(), Error> {
     let msg: Handshake = conn.receive_with_fds().map_err(Error::Receive)?;
-    let client_build_id = identify(client_intent);
-    if client_build_id != msg.build_id {
-        return Err(Error::WrongVersion);
-    }
-    if msg.intent != client_intent {
-        return Err(Error::WrongService);
-    }
+    msg.check(client_intent)?;
+    conn.send_with_fds(&Handshake::new(server_intent))
+        .map_err(Error::Send)?;
+    conn.flush().map_err(Error::Send)?;
+    Ok(())
+}
+
+/// Like [handshake_as_client], for [AsyncIPC](ipc::AsyncIPC).
+pub async fn handshake_as_client_async(
+    conn: &impl ipc::AsyncIPC,
+    client_intent: &'static str,
+    server_intent: &'static str,
+) -> Result<(), Error> {
+    conn.send_with_fds(&Handshake::new(client_intent))
+        .await
+        .map_err(Error::Send)?;
+    let msg: Handshake = conn.receive_with_fds().await.map_err(Error::Receive)?;
+    msg.check(server_intent)
+}
+
+/// Like [handshake_as_server], for [AsyncIPC](ipc::AsyncIPC).
+pub async fn handshake_as_server_async(
+    conn: &impl ipc::AsyncIPC,
+    client_intent: &'static str,
+    server_intent: &'static str,
+) -> Result<(), Error> {
+    let msg: Handshake = conn.receive_with_fds().await.map_err(Error::Receive)?;
+    msg.check(client_intent)?;
     conn.send_with_fds(&Handshake::new(server_intent))
+        .await
         .map_err(Error::Send)?;
     Ok(())
 }
@@ -135,6 +169,34 @@ mod tests {
         }
     }
 
+    #[test]
+    fn async_client_blocking_server() {
+        use crate::ipc::async_seqpacket::AsyncSeqPacket;
+        use crate::ipc::seqpacket::SeqPacket;
+        use std::convert::TryFrom;
+
+        const CLIENT: &str = "tere 2021-06-10T13:38:10 testing client";
+        const SERVER: &str = "tere 2021-06-10T13:38:43 testing server";
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .expect("tokio runtime");
+        runtime.block_on(async {
+            let (conn, server_socket) = AsyncSeqPacket::pair().expect("socketpair");
+            let server = std::thread::spawn(move || {
+                let conn = SeqPacket::try_from(server_socket).expect("SeqPacket::try_from");
+                handshake_as_server(&conn, CLIENT, SERVER)
+            });
+            handshake_as_client_async(&conn, CLIENT, SERVER)
+                .await
+                .expect("handshake_as_client_async");
+            server
+                .join()
+                .expect("server thread")
+                .expect("handshake_as_server");
+        });
+    }
+
     #[test]
     fn server_simple() {
         let conn = FakeIpc::new();
@@ -27,23 +27,56 @@
 //! We also assume serializing units (as in `()`) takes no space in the encoded message.
 //! [bincode] obeys this.
 //! Anything transported via FD passing is encoded as a unit.
+//! A message may thus encode to nothing at all; transports that can't pass FDs without data pad such messages themselves.
 //! The relevant APIs do not offer a "skip" mechanism at that level.
 
+use async_trait::async_trait;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use std::fmt::Debug;
+use std::os::unix::io::AsRawFd;
 use std::os::unix::net::AncillaryError;
 use thiserror::Error;
 
+pub mod async_seqpacket;
+pub mod bounded;
+pub mod circuits;
 pub mod handshake;
 pub mod ownedfd;
 pub mod passfd;
 pub mod seqpacket;
+pub mod stream;
 
 #[cfg(test)]
 mod fakeipc;
 
+/// Implement [Message] with limits computed from the type, see [bounded].
+pub use tere_server_derive::Message;
+
+/// The type of a socket, such as `libc::SOCK_SEQPACKET`.
+pub fn socket_type(socket: &impl AsRawFd) -> std::io::Result<libc::c_int> {
+    let fd = socket.as_raw_fd();
+    let mut socket_type: libc::c_int = 0;
+    let mut len = std::mem::size_of_val(&socket_type) as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            &mut socket_type as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(socket_type)
+}
+
 /// Expose information about messages to the transport.
+///
+/// Prefer deriving the limits with `#[derive(ipc::Message)]`, see [bounded].
+/// Messages over the limits are refused on send, as the peer would refuse to receive them.
 pub trait Message: Debug {
     /// Maximum size of the encoded message.
     /// Must not depend on message contents, this is the worst case.
@@ -58,10 +91,31 @@ pub enum SendError {
     #[error("serialize failed: {0}")]
     Serialize(#[source] bincode::Error),
 
+    #[error("message is over the limits of its type: {size} bytes and {fds} FDs, limits are {max_size} bytes and {max_fds} FDs")]
+    TooLarge {
+        size: usize,
+        fds: usize,
+        max_size: usize,
+        max_fds: usize,
+    },
+
     #[error("socket sendmsg failed: {0}")]
     Socket(#[source] std::io::Error),
 }
 
+/// Refuse to send an encoded message that is over the given limits, typically those of its [Message] type.
+fn check_limits(size: usize, fds: usize, max_size: usize, max_fds: usize) -> Result<(), SendError> {
+    if size > max_size || fds > max_fds {
+        return Err(SendError::TooLarge {
+            size,
+            fds,
+            max_size,
+            max_fds,
+        });
+    }
+    Ok(())
+}
+
 #[derive(Error, Debug)]
 pub enum ReceiveError {
     #[error("end of stream")]
@@ -107,6 +161,30 @@ pub trait IPC {
     where
         M: 'static + Message + DeserializeOwned;
 
+    /// Send any messages the transport is holding on to.
+    ///
+    /// Call before waiting for the peer to respond to what was sent.
+    fn flush(&self) -> Result<(), SendError> {
+        Ok(())
+    }
+
+    /// Shuts down the read, write, or both halves of this connection.
+    fn shutdown(&self, how: std::net::Shutdown) -> Result<(), ShutdownError>;
+}
+
+/// Like [IPC], but for use on an async runtime, without tying up a thread per connection.
+#[async_trait]
+pub trait AsyncIPC {
+    /// Send a [Message] with the included file descriptors.
+    async fn send_with_fds<M>(&self, message: &M) -> Result<(), SendError>
+    where
+        M: 'static + Message + Serialize + Sync;
+
+    /// Receive a [Message] and included file descriptors.
+    async fn receive_with_fds<M>(&self) -> Result<M, ReceiveError>
+    where
+        M: 'static + Message + DeserializeOwned + Send;
+
     /// Shuts down the read, write, or both halves of this connection.
     fn shutdown(&self, how: std::net::Shutdown) -> Result<(), ShutdownError>;
 }
@@ -4,10 +4,8 @@
 //! Due to similarities with `SOCK_DATAGRAM` -- mostly that messages must be received whole -- we're using [`UnixDatagram`] as the underlying type.
 //! However, the sockets are of type `SOCK_SEQPACKET` and only work via connections.
 
-// TODO SOCK_SEQPACKET means messages map 1:1 to syscalls.
-// That's fine for now, and unavoidable for protocols that pass FDs in practically every message, but at some point we're probably going to want to decrease syscall overhead with a SOCK_STREAM alternative that buffers messages.
-// With that, received FDs belong to the last message in the buffer, and recvmsg will give a short read on the trailing boundary of any message with FDs so there will never be two in the buffer at the same time.
-// At that time, Message::MAX_SIZE and Message:MAX_FDS handling need to switch from current message to max of all possible messages.
+// SOCK_SEQPACKET means messages map 1:1 to syscalls.
+// That's fine for protocols that pass FDs in practically every message, but see the `stream` module for an alternative that buffers messages.
 // Or, we just avoid the whole syscall overhead issue with io_uring.
 
 use bincode::Options;
@@ -30,6 +28,9 @@ use std::os::unix::net::SocketAncillary;
 use crate::ipc;
 use crate::ipc::ownedfd::OwnedFd;
 
+/// Sent in place of an empty message, as `sendmsg` with no data can't pass FDs.
+const PADDING: u8 = 0;
+
 /// Create a pair of packet-oriented (`SOCK_SEQPACKET`) sockets that are connected to each others, using `socketpair(2)`.
 ///
 /// Like [std::os::unix::net::UnixStream::pair], except using `SOCK_SEQPACKET`.
@@ -75,6 +76,18 @@ impl SeqPacket {
         let a = Self { socket: a };
         Ok((a, b))
     }
+
+    /// Give up the IPC wrapper, for example to use the socket with [AsyncSeqPacket](super::async_seqpacket::AsyncSeqPacket).
+    pub fn into_socket(self) -> UnixDatagram {
+        self.socket
+    }
+
+    /// Shut down reading, writing, or both halves of the connection.
+    ///
+    /// Shutting down reading makes a concurrent `receive_with_fds` see the end of the stream.
+    pub fn shutdown(&self, how: std::net::Shutdown) -> std::io::Result<()> {
+        self.socket.shutdown(how)
+    }
 }
 
 /// Implement the IPC abstraction for UNIX domain `SOCK_SEQPACKET` sockets.
@@ -82,30 +95,21 @@ pub struct SeqPacket {
     socket: UnixDatagram,
 }
 
+impl AsRawFd for SeqPacket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum SocketConversionError {
     #[error("not a SOCK_SEQPACKET")]
     NotSeqPacket,
 }
 
-fn is_seq_packet(socket: &impl AsRawFd) -> bool {
-    let fd = socket.as_raw_fd();
-    let mut socket_type: libc::c_int = 0;
-    let mut len = std::mem::size_of_val(&socket_type) as libc::socklen_t;
-    let ret = unsafe {
-        libc::getsockopt(
-            fd,
-            libc::SOL_SOCKET,
-            libc::SO_TYPE,
-            &mut socket_type as *mut _ as *mut libc::c_void,
-            &mut len,
-        )
-    };
-    if ret < 0 {
-        // we don't really care why it failed
-        return false;
-    }
-    socket_type == libc::SOCK_SEQPACKET
+pub(super) fn is_seq_packet(socket: &impl AsRawFd) -> bool {
+    // we don't really care why it failed
+    matches!(ipc::socket_type(socket), Ok(libc::SOCK_SEQPACKET))
 }
 
 /// The socket must be of `SOCK_SEQPACKET`, and connected.
@@ -120,123 +124,159 @@ impl TryFrom<UnixDatagram> for SeqPacket {
     }
 }
 
+/// Send one message on `socket`.
+///
+/// Shared with [AsyncSeqPacket](super::async_seqpacket::AsyncSeqPacket), which calls this on a non-blocking socket.
+pub(super) fn send<M>(socket: &UnixDatagram, message: &M) -> Result<(), ipc::SendError>
+where
+    M: ipc::Message + Serialize,
+{
+    // It would be nicer if we could just return a SocketAncillary and let caller deal with it.
+    // Unfortunately, SocketAncillary borrows the buffer, so that doesn't work.
+
+    let config = bincode::DefaultOptions::new()
+        // MUST use with_no_limit or fds are serialized twice
+        .with_no_limit();
+
+    let mut fds: Vec<RawFd> = Vec::new();
+    let mut encoded = Vec::with_capacity(M::MAX_SIZE);
+    ipc::passfd::gather_fds_to_vec(&mut fds, || {
+        config
+            .serialize_into(&mut encoded, &message)
+            .map_err(ipc::SendError::Serialize)
+    })?;
+    ipc::check_limits(encoded.len(), fds.len(), M::MAX_SIZE, M::MAX_FDS)?;
+    if encoded.is_empty() {
+        // Messages consisting of only FDs still need data to carry them.
+        encoded.push(PADDING);
+    }
+
+    // TODO rust doesn't (yet?) expose CMSG_SPACE, forcing us to either guess or probe the size.
+    // TODO just call libc::CMSG_SPACE?
+    // TODO this doesn't align right? https://github.com/rust-lang/rust/issues/76915#issuecomment-855368476
+    let mut ancillary_buffer = Vec::with_capacity(128);
+    let mut ancillary = {
+        loop {
+            ancillary_buffer.resize_with(ancillary_buffer.len() + 128, Default::default);
+            let mut ancillary = SocketAncillary::new(&mut ancillary_buffer[..]);
+            if ancillary.add_fds(&fds) {
+                break ancillary;
+            }
+        }
+    };
+
+    let iovec = &mut [IoSlice::new(&encoded[..])][..];
+    socket
+        .send_vectored_with_ancillary(iovec, &mut ancillary)
+        .map_err(ipc::SendError::Socket)?;
+
+    Ok(())
+}
+
+/// Receive one message from `socket`.
+///
+/// Shared with [AsyncSeqPacket](super::async_seqpacket::AsyncSeqPacket), which calls this on a non-blocking socket.
+pub(super) fn receive<M>(socket: &UnixDatagram) -> Result<M, ipc::ReceiveError>
+where
+    M: ipc::Message + DeserializeOwned,
+{
+    let config = bincode::DefaultOptions::new()
+        // MUST use with_no_limit or fds are serialized twice
+        .with_no_limit();
+
+    // Room for the padding byte of an empty message.
+    let mut encoded = vec![0_u8; M::MAX_SIZE.max(1)];
+    let iovec = &mut [IoSliceMut::new(&mut encoded)][..];
+
+    let inner_size = (std::mem::size_of::<libc::c_int>() * M::MAX_FDS) as libc::c_uint;
+    let ancillary_size = unsafe { libc::CMSG_SPACE(inner_size) } as usize;
+    // TODO alignment is wrong, https://github.com/rust-lang/rust/issues/76915
+    let mut ancillary_buffer = vec![0_u8; ancillary_size];
+    let mut ancillary = SocketAncillary::new(&mut ancillary_buffer[..]);
+
+    let (size, truncated) = match socket.recv_vectored_with_ancillary(iovec, &mut ancillary) {
+        // The peer closed the connection without reading everything we sent.
+        // That's still just the end of the conversation.
+        Err(error) if error.raw_os_error() == Some(libc::ECONNRESET) => {
+            return Err(ipc::ReceiveError::End)
+        }
+        Err(error) => return Err(ipc::ReceiveError::Socket(error)),
+        Ok(result) => result,
+    };
+    if truncated {
+        // If we had flags|=MSG_TRUNC, we could report the sent size.
+        return Err(ipc::ReceiveError::TooLarge);
+    }
+    if size == 0 {
+        return Err(ipc::ReceiveError::End);
+    }
+    let encoded = &encoded[..size];
+
+    let mut fds: VecDeque<OwnedFd> = ancillary
+        .messages()
+        .filter_map(|r| match r {
+            // TODO should we fail on ancillary data errors?
+            // If choosing to fail, make sure to collect all the fds so we close them.
+
+            // Definitely ignore AncillaryError::Unknown.
+            Err(AncillaryError::Unknown { .. }) => None,
+            Err(_) => None,
+            Ok(AncillaryData::ScmRights(rights)) => Some(rights),
+            Ok(_) => None,
+        })
+        .flatten()
+        // We've been handed new, open, FDs by the kernel.
+        // Ensure they get closed on error paths by moving them into something that takes ownership.
+        .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) })
+        .collect();
+
+    if ancillary.truncated() {
+        // Do this after we collect the FDs so we don't leak them.
+        return Err(ipc::ReceiveError::AncillaryTruncated {
+            max_fds: M::MAX_FDS,
+            bytes_cap: ancillary.capacity(),
+        });
+    }
+
+    let orig_num_fds = fds.len();
+    let msg =
+        ipc::passfd::scatter_fds_from_vec_deque(&mut fds, || -> Result<M, ipc::ReceiveError> {
+            let mut rest = encoded;
+            let msg = config
+                .deserialize_from(&mut rest)
+                .map_err(ipc::ReceiveError::Deserialize)?;
+            // An empty message comes with the padding byte added on send.
+            let padded = rest.len() == encoded.len() && rest == [PADDING];
+            if !rest.is_empty() && !padded {
+                return Err(ipc::ReceiveError::Deserialize(Box::new(
+                    bincode::ErrorKind::Custom("trailing bytes after message".to_string()),
+                )));
+            }
+            Ok(msg)
+        })?;
+    let fds_left = fds.len();
+    if fds_left != 0 {
+        return Err(ipc::ReceiveError::TooManyFds {
+            orig: orig_num_fds,
+            extra: fds_left,
+        });
+    }
+    Ok(msg)
+}
+
 impl ipc::IPC for SeqPacket {
     fn send_with_fds<M>(&self, message: &M) -> Result<(), ipc::SendError>
     where
         M: ipc::Message + Serialize,
     {
-        // It would be nicer if we could just return a SocketAncillary and let caller deal with it.
-        // Unfortunately, SocketAncillary borrows the buffer, so that doesn't work.
-
-        let config = bincode::DefaultOptions::new()
-            // MUST use with_no_limit or fds are serialized twice
-            .with_no_limit();
-
-        let mut fds: Vec<RawFd> = Vec::new();
-        let mut encoded = Vec::with_capacity(M::MAX_SIZE);
-        ipc::passfd::gather_fds_to_vec(&mut fds, || {
-            config
-                .serialize_into(&mut encoded, &message)
-                .map_err(ipc::SendError::Serialize)
-        })?;
-
-        // TODO rust doesn't (yet?) expose CMSG_SPACE, forcing us to either guess or probe the size.
-        // TODO just call libc::CMSG_SPACE?
-        // TODO this doesn't align right? https://github.com/rust-lang/rust/issues/76915#issuecomment-855368476
-        let mut ancillary_buffer = Vec::with_capacity(128);
-        let mut ancillary = {
-            loop {
-                ancillary_buffer.resize_with(ancillary_buffer.len() + 128, Default::default);
-                let mut ancillary = SocketAncillary::new(&mut ancillary_buffer[..]);
-                if ancillary.add_fds(&fds) {
-                    break ancillary;
-                }
-            }
-        };
-
-        let iovec = &mut [IoSlice::new(&encoded[..])][..];
-        self.socket
-            .send_vectored_with_ancillary(iovec, &mut ancillary)
-            .map_err(ipc::SendError::Socket)?;
-
-        Ok(())
+        send(&self.socket, message)
     }
 
     fn receive_with_fds<M>(&self) -> Result<M, ipc::ReceiveError>
     where
         M: ipc::Message + DeserializeOwned,
     {
-        // TODO in debug mode, validate against max_message_size on send
-
-        let config = bincode::DefaultOptions::new()
-            // MUST use with_no_limit or fds are serialized twice
-            .with_no_limit();
-
-        let mut encoded = vec![0_u8; M::MAX_SIZE];
-        let iovec = &mut [IoSliceMut::new(&mut encoded)][..];
-
-        let inner_size = (std::mem::size_of::<libc::c_int>() * M::MAX_FDS) as libc::c_uint;
-        let ancillary_size = unsafe { libc::CMSG_SPACE(inner_size) } as usize;
-        // TODO alignment is wrong, https://github.com/rust-lang/rust/issues/76915
-        let mut ancillary_buffer = vec![0_u8; ancillary_size];
-        let mut ancillary = SocketAncillary::new(&mut ancillary_buffer[..]);
-
-        let (size, truncated) = self
-            .socket
-            .recv_vectored_with_ancillary(iovec, &mut ancillary)
-            .map_err(ipc::ReceiveError::Socket)?;
-        if truncated {
-            // If we had flags|=MSG_TRUNC, we could report the sent size.
-            return Err(ipc::ReceiveError::TooLarge);
-        }
-        if size == 0 {
-            return Err(ipc::ReceiveError::End);
-        }
-        let encoded = &encoded[..size];
-
-        let mut fds: VecDeque<OwnedFd> = ancillary
-            .messages()
-            .filter_map(|r| match r {
-                // TODO should we fail on ancillary data errors?
-                // If choosing to fail, make sure to collect all the fds so we close them.
-
-                // Definitely ignore AncillaryError::Unknown.
-                Err(AncillaryError::Unknown { .. }) => None,
-                Err(_) => None,
-                Ok(AncillaryData::ScmRights(rights)) => Some(rights),
-                Ok(_) => None,
-            })
-            .flatten()
-            // We've been handed new, open, FDs by the kernel.
-            // Ensure they get closed on error paths by moving them into something that takes ownership.
-            .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) })
-            .collect();
-
-        if ancillary.truncated() {
-            // Do this after we collect the FDs so we don't leak them.
-            return Err(ipc::ReceiveError::AncillaryTruncated {
-                max_fds: M::MAX_FDS,
-                bytes_cap: ancillary.capacity(),
-            });
-        }
-
-        let orig_num_fds = fds.len();
-        let msg = ipc::passfd::scatter_fds_from_vec_deque(
-            &mut fds,
-            || -> Result<M, ipc::ReceiveError> {
-                config
-                    .deserialize(encoded)
-                    .map_err(ipc::ReceiveError::Deserialize)
-            },
-        )?;
-        let fds_left = fds.len();
-        if fds_left != 0 {
-            return Err(ipc::ReceiveError::TooManyFds {
-                orig: orig_num_fds,
-                extra: fds_left,
-            });
-        }
-        Ok(msg)
+        receive(&self.socket)
     }
 
     fn shutdown(&self, how: std::net::Shutdown) -> Result<(), ipc::ShutdownError> {
@@ -326,4 +366,95 @@ mod tests {
             .expect("read from memfd");
         assert_eq!(got_two_str, "two");
     }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    struct OnlyFd {
+        #[serde(with = "ipc::passfd")]
+        file: File,
+    }
+
+    impl ipc::Message for OnlyFd {
+        const MAX_SIZE: usize = 0;
+        const MAX_FDS: usize = 1;
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    struct Empty;
+
+    impl ipc::Message for Empty {}
+
+    #[derive(Serialize, Deserialize, Debug)]
+    struct Byte(u8);
+
+    impl ipc::Message for Byte {}
+
+    #[test]
+    fn only_fd() {
+        let opts = memfd::MemfdOptions::new().close_on_exec(true);
+        let file = opts.create("only").expect("memfd_create").into_file();
+        file.write_all_at(b"only", 0).expect("write to memfd");
+
+        let (sender, receiver) = SeqPacket::pair().expect("socketpair");
+        sender.send_with_fds(&OnlyFd { file }).expect("sendmsg");
+        let receiver = SeqPacket::try_from(receiver).expect("SeqPacket::try_from");
+        let mut got: OnlyFd = receiver.receive_with_fds().expect("recvmsg");
+        let mut content = String::new();
+        got.file
+            .read_to_string(&mut content)
+            .expect("read from memfd");
+        assert_eq!(content, "only");
+    }
+
+    #[test]
+    fn empty_is_not_end() {
+        let (sender, receiver) = SeqPacket::pair().expect("socketpair");
+        sender.send_with_fds(&Empty).expect("sendmsg");
+        // Same bytes on the wire as the padding, but not empty.
+        sender.send_with_fds(&Byte(0)).expect("sendmsg");
+        drop(sender);
+        let receiver = SeqPacket::try_from(receiver).expect("SeqPacket::try_from");
+        let _: Empty = receiver.receive_with_fds().expect("recvmsg");
+        let got: Byte = receiver.receive_with_fds().expect("recvmsg");
+        assert_eq!(got.0, 0);
+        assert!(matches!(
+            receiver.receive_with_fds::<Empty>(),
+            Err(ipc::ReceiveError::End)
+        ));
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    struct Short(String);
+
+    impl ipc::Message for Short {
+        const MAX_SIZE: usize = 4;
+    }
+
+    #[test]
+    fn send_too_large() {
+        let (sender, receiver) = SeqPacket::pair().expect("socketpair");
+        let error = sender
+            .send_with_fds(&Short("xyzzy".to_string()))
+            .expect_err("sent a message over its limits");
+        assert!(matches!(
+            error,
+            ipc::SendError::TooLarge {
+                size: 6,
+                fds: 0,
+                max_size: 4,
+                max_fds: 0,
+            }
+        ));
+        sender
+            .send_with_fds(&Short("abc".to_string()))
+            .expect("sendmsg");
+        drop(sender);
+        // The message that was refused never made it to the peer.
+        let receiver = SeqPacket::try_from(receiver).expect("SeqPacket::try_from");
+        let got: Short = receiver.receive_with_fds().expect("recvmsg");
+        assert_eq!(got.0, "abc");
+        assert!(matches!(
+            receiver.receive_with_fds::<Short>(),
+            Err(ipc::ReceiveError::End)
+        ));
+    }
 }
@@ -0,0 +1,573 @@
+//! IPC with FD passing over UNIX domain sockets of type `SOCK_STREAM`, buffering messages to save on syscalls.
+//!
+//! Every message is framed by its length, as a little-endian `u32`.
+//! Sent messages are buffered until [IPC::flush](ipc::IPC::flush), or until the buffer fills up.
+//! Received messages are read as many at a time as the peer has sent.
+//!
+//! A message with FDs flushes everything before it, and is sent on its own, with the FDs attached.
+//! The kernel attaches the FDs to the first read that touches that message, and ends that read at the end of the message.
+//! Thus received FDs always belong to the last message in the receive buffer, and there are never FDs for two messages in it at the same time.
+//! For that to hold, a read must always have room for the largest message in the protocol, so unlike with [SeqPacket](super::seqpacket::SeqPacket), the limits of [Message](ipc::Message) are needed for all messages in the protocol up front, as [Limits].
+
+use bincode::Options;
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::io::{IoSlice, IoSliceMut, Write};
+use std::os::unix::io::FromRawFd;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixStream;
+use std::os::unix::net::{AncillaryData, AncillaryError};
+use std::path::Path;
+use std::sync::Mutex;
+
+// Using unstable feature `unix_socket_ancillary_data`.
+// https://github.com/rust-lang/rust/issues/76915
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::SocketAncillary;
+
+use crate::ipc;
+use crate::ipc::ownedfd::OwnedFd;
+
+/// Size of the length prefix of every message.
+const HEADER_SIZE: usize = std::mem::size_of::<u32>();
+
+/// Flush once this much is waiting to be sent.
+const SEND_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Read up to this much at a time, or more if the protocol has larger messages.
+const RECEIVE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Worst case sizes over all messages of a protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Largest [Message::MAX_SIZE](ipc::Message::MAX_SIZE).
+    pub max_size: usize,
+    /// Largest [Message::MAX_FDS](ipc::Message::MAX_FDS).
+    pub max_fds: usize,
+}
+
+impl Limits {
+    /// Limits of a protocol consisting of just `M`.
+    pub fn of<M: ipc::Message>() -> Self {
+        Self {
+            max_size: M::MAX_SIZE,
+            max_fds: M::MAX_FDS,
+        }
+    }
+
+    /// Extend the limits to also cover `M`.
+    pub fn and<M: ipc::Message>(self) -> Self {
+        Self {
+            max_size: self.max_size.max(M::MAX_SIZE),
+            max_fds: self.max_fds.max(M::MAX_FDS),
+        }
+    }
+}
+
+struct Sending {
+    buffer: Vec<u8>,
+}
+
+struct Receiving {
+    buffer: Vec<u8>,
+    /// Received but not yet consumed data is `buffer[start..end]`.
+    start: usize,
+    end: usize,
+    /// FDs received for the message ending at `fds_end`.
+    fds: VecDeque<OwnedFd>,
+    fds_end: usize,
+}
+
+/// Implement the IPC abstraction for UNIX domain `SOCK_STREAM` sockets.
+///
+/// Sending and receiving can happen concurrently, from different threads.
+pub struct Stream {
+    socket: UnixStream,
+    limits: Limits,
+    sending: Mutex<Sending>,
+    receiving: Mutex<Receiving>,
+}
+
+impl Stream {
+    /// Prepare a connected socket for use as `IPC`, for a protocol whose messages fit in `limits`.
+    pub fn new(socket: UnixStream, limits: Limits) -> Self {
+        // After moving a partial message to the start of the buffer, there must be room for a whole message after it.
+        let receive_buffer_size = RECEIVE_BUFFER_SIZE.max(2 * (HEADER_SIZE + limits.max_size));
+        Self {
+            socket,
+            limits,
+            sending: Mutex::new(Sending {
+                buffer: Vec::with_capacity(SEND_BUFFER_SIZE),
+            }),
+            receiving: Mutex::new(Receiving {
+                buffer: vec![0_u8; receive_buffer_size],
+                start: 0,
+                end: 0,
+                fds: VecDeque::new(),
+                fds_end: 0,
+            }),
+        }
+    }
+
+    pub fn connect<P: AsRef<Path>>(path: P, limits: Limits) -> Result<Self, std::io::Error> {
+        let socket = UnixStream::connect(path)?;
+        Ok(Self::new(socket, limits))
+    }
+
+    /// Create a pair of sockets that are connected to each other, and prepare one side for use as `IPC`.
+    ///
+    /// See `SeqPacket::pair`.
+    pub fn pair(limits: Limits) -> std::io::Result<(Stream, UnixStream)> {
+        let (a, b) = UnixStream::pair()?;
+        Ok((Self::new(a, limits), b))
+    }
+
+    fn write_all(&self, mut data: &[u8]) -> Result<(), ipc::SendError> {
+        while !data.is_empty() {
+            match (&self.socket).write(data) {
+                Ok(0) => return Err(ipc::SendError::Socket(std::io::ErrorKind::WriteZero.into())),
+                Ok(n) => data = &data[n..],
+                Err(error) if error.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(error) => return Err(ipc::SendError::Socket(error)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Send `data` with `fds` attached to it.
+    fn write_all_with_fds(&self, data: &[u8], fds: &[RawFd]) -> Result<(), ipc::SendError> {
+        let inner_size = (std::mem::size_of::<libc::c_int>() * fds.len()) as libc::c_uint;
+        let ancillary_size = unsafe { libc::CMSG_SPACE(inner_size) } as usize;
+        // TODO alignment is wrong, https://github.com/rust-lang/rust/issues/76915
+        let mut ancillary_buffer = vec![0_u8; ancillary_size];
+        let mut ancillary = SocketAncillary::new(&mut ancillary_buffer[..]);
+        if !ancillary.add_fds(fds) {
+            return Err(ipc::SendError::Socket(std::io::Error::from_raw_os_error(
+                libc::EMSGSIZE,
+            )));
+        }
+        let sent = loop {
+            let iovec = &mut [IoSlice::new(data)][..];
+            match self
+                .socket
+                .send_vectored_with_ancillary(iovec, &mut ancillary)
+            {
+                Ok(sent) => break sent,
+                // Nothing was sent, FDs included.
+                Err(error) if error.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(error) => return Err(ipc::SendError::Socket(error)),
+            }
+        };
+        // The FDs went with the first byte, the rest is just data.
+        self.write_all(&data[sent..])
+    }
+
+    fn flush_buffer(&self, sending: &mut Sending) -> Result<(), ipc::SendError> {
+        let result = self.write_all(&sending.buffer);
+        // After a write error, the stream is broken anyway.
+        sending.buffer.clear();
+        result
+    }
+
+    /// Read more from the socket, returning whether the peer had anything left to say.
+    fn fill(&self, receiving: &mut Receiving) -> Result<bool, ipc::ReceiveError> {
+        // Move a partial message to the start of the buffer, to make room for the rest of it.
+        if receiving.start > 0 {
+            let Receiving {
+                buffer,
+                start,
+                end,
+                fds_end,
+                ..
+            } = receiving;
+            buffer.copy_within(*start..*end, 0);
+            *end -= *start;
+            *fds_end = fds_end.saturating_sub(*start);
+            *start = 0;
+        }
+
+        let end = receiving.end;
+        let iovec = &mut [IoSliceMut::new(&mut receiving.buffer[end..])][..];
+
+        let inner_size = (std::mem::size_of::<libc::c_int>() * self.limits.max_fds) as libc::c_uint;
+        let ancillary_size = unsafe { libc::CMSG_SPACE(inner_size) } as usize;
+        // TODO alignment is wrong, https://github.com/rust-lang/rust/issues/76915
+        let mut ancillary_buffer = vec![0_u8; ancillary_size];
+        let mut ancillary = SocketAncillary::new(&mut ancillary_buffer[..]);
+
+        let size = loop {
+            match self
+                .socket
+                .recv_vectored_with_ancillary(iovec, &mut ancillary)
+            {
+                // The peer closed the connection without reading everything we sent.
+                // That's still just the end of the conversation.
+                Err(error) if error.raw_os_error() == Some(libc::ECONNRESET) => return Ok(false),
+                Err(error) if error.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(error) => return Err(ipc::ReceiveError::Socket(error)),
+                Ok(size) => break size,
+            }
+        };
+
+        let fds = ancillary
+            .messages()
+            .filter_map(|r| match r {
+                // TODO should we fail on ancillary data errors?
+                // If choosing to fail, make sure to collect all the fds so we close them.
+
+                // Definitely ignore AncillaryError::Unknown.
+                Err(AncillaryError::Unknown { .. }) => None,
+                Err(_) => None,
+                Ok(AncillaryData::ScmRights(rights)) => Some(rights),
+                Ok(_) => None,
+            })
+            .flatten()
+            // We've been handed new, open, FDs by the kernel.
+            // Ensure they get closed on error paths by moving them into something that takes ownership.
+            .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) });
+        receiving.fds.extend(fds);
+        receiving.end += size;
+        if !receiving.fds.is_empty() {
+            receiving.fds_end = receiving.end;
+        }
+
+        if ancillary.truncated() {
+            // Do this after we collect the FDs so we don't leak them.
+            return Err(ipc::ReceiveError::AncillaryTruncated {
+                max_fds: self.limits.max_fds,
+                bytes_cap: ancillary.capacity(),
+            });
+        }
+        Ok(size > 0)
+    }
+}
+
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        // Like a BufWriter, don't lose messages that were already sent as far as the caller is concerned.
+        // Errors have nowhere to go.
+        if let Ok(mut sending) = self.sending.lock() {
+            let _ = self.flush_buffer(&mut sending);
+        }
+    }
+}
+
+impl ipc::IPC for Stream {
+    fn send_with_fds<M>(&self, message: &M) -> Result<(), ipc::SendError>
+    where
+        M: ipc::Message + Serialize,
+    {
+        let config = bincode::DefaultOptions::new()
+            // MUST use with_no_limit or fds are serialized twice
+            .with_no_limit();
+
+        let mut sending = self.sending.lock().expect("internal: send mutex poison");
+        let message_start = sending.buffer.len();
+        sending.buffer.extend_from_slice(&[0; HEADER_SIZE]);
+        let mut fds: Vec<RawFd> = Vec::new();
+        let result = ipc::passfd::gather_fds_to_vec(&mut fds, || {
+            config
+                .serialize_into(&mut sending.buffer, &message)
+                .map_err(ipc::SendError::Serialize)
+        })
+        .and_then(|()| {
+            let size = sending.buffer.len() - message_start - HEADER_SIZE;
+            ipc::check_limits(size, fds.len(), M::MAX_SIZE, M::MAX_FDS)?;
+            u32::try_from(size)
+                .map_err(|_| ipc::SendError::Serialize(Box::new(bincode::ErrorKind::SizeLimit)))
+        });
+        let size = match result {
+            Ok(size) => size,
+            Err(error) => {
+                sending.buffer.truncate(message_start);
+                return Err(error);
+            }
+        };
+        sending.buffer[message_start..message_start + HEADER_SIZE]
+            .copy_from_slice(&size.to_le_bytes());
+
+        if !fds.is_empty() {
+            // Earlier messages go first, so the FDs are attached to just this one.
+            let result = self
+                .write_all(&sending.buffer[..message_start])
+                .and_then(|()| self.write_all_with_fds(&sending.buffer[message_start..], &fds));
+            sending.buffer.clear();
+            return result;
+        }
+        if sending.buffer.len() >= SEND_BUFFER_SIZE {
+            self.flush_buffer(&mut sending)?;
+        }
+        Ok(())
+    }
+
+    fn receive_with_fds<M>(&self) -> Result<M, ipc::ReceiveError>
+    where
+        M: ipc::Message + DeserializeOwned,
+    {
+        let config = bincode::DefaultOptions::new()
+            // MUST use with_no_limit or fds are serialized twice
+            .with_no_limit();
+
+        let mut receiving = self
+            .receiving
+            .lock()
+            .expect("internal: receive mutex poison");
+        let (message_start, message_end) = loop {
+            let available = &receiving.buffer[receiving.start..receiving.end];
+            if available.len() >= HEADER_SIZE {
+                let mut header = [0_u8; HEADER_SIZE];
+                header.copy_from_slice(&available[..HEADER_SIZE]);
+                let size = u32::from_le_bytes(header) as usize;
+                if size > M::MAX_SIZE || size > self.limits.max_size {
+                    return Err(ipc::ReceiveError::TooLarge);
+                }
+                if available.len() >= HEADER_SIZE + size {
+                    let message_start = receiving.start + HEADER_SIZE;
+                    break (message_start, message_start + size);
+                }
+            }
+            if !self.fill(&mut receiving)? {
+                if receiving.start == receiving.end {
+                    return Err(ipc::ReceiveError::End);
+                }
+                return Err(ipc::ReceiveError::Socket(
+                    std::io::ErrorKind::UnexpectedEof.into(),
+                ));
+            }
+        };
+        receiving.start = message_end;
+
+        let Receiving {
+            buffer,
+            fds,
+            fds_end,
+            ..
+        } = &mut *receiving;
+        let mut message_fds = if *fds_end == message_end {
+            std::mem::take(fds)
+        } else {
+            VecDeque::new()
+        };
+        let encoded = &buffer[message_start..message_end];
+        let orig_num_fds = message_fds.len();
+        let msg = ipc::passfd::scatter_fds_from_vec_deque(
+            &mut message_fds,
+            || -> Result<M, ipc::ReceiveError> {
+                config
+                    .deserialize(encoded)
+                    .map_err(ipc::ReceiveError::Deserialize)
+            },
+        )?;
+        let fds_left = message_fds.len();
+        if fds_left != 0 {
+            return Err(ipc::ReceiveError::TooManyFds {
+                orig: orig_num_fds,
+                extra: fds_left,
+            });
+        }
+        Ok(msg)
+    }
+
+    fn flush(&self) -> Result<(), ipc::SendError> {
+        let mut sending = self.sending.lock().expect("internal: send mutex poison");
+        self.flush_buffer(&mut sending)
+    }
+
+    fn shutdown(&self, how: std::net::Shutdown) -> Result<(), ipc::ShutdownError> {
+        if how != std::net::Shutdown::Read {
+            // Buffered messages can't be sent after this, so this is the last chance.
+            // Errors will show up again as errors from shutdown, or were already seen by the peer.
+            let _ = self.flush();
+        }
+        self.socket
+            .shutdown(how)
+            .map_err(|source| ipc::ShutdownError::Io { how, source })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use memfd;
+    use serde::{Deserialize, Serialize};
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::os::unix::fs::FileExt;
+    use std::os::unix::net::UnixStream;
+
+    use super::{Limits, Stream};
+    use crate::ipc;
+    use crate::ipc::IPC;
+
+    #[derive(Serialize, Deserialize, Debug)]
+    struct Chunk(Vec<u8>);
+
+    impl ipc::Message for Chunk {
+        const MAX_SIZE: usize = 2000;
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    struct WithFile {
+        greeting: String,
+        #[serde(with = "ipc::passfd")]
+        file: File,
+    }
+
+    impl ipc::Message for WithFile {
+        const MAX_SIZE: usize = 100;
+        const MAX_FDS: usize = 1;
+    }
+
+    fn limits() -> Limits {
+        Limits::of::<Chunk>().and::<WithFile>()
+    }
+
+    fn pair() -> (Stream, Stream) {
+        let (sender, receiver) = Stream::pair(limits()).expect("socketpair");
+        (sender, Stream::new(receiver, limits()))
+    }
+
+    #[test]
+    fn limits_cover_protocol() {
+        assert_eq!(
+            limits(),
+            Limits {
+                max_size: 2000,
+                max_fds: 1
+            }
+        );
+    }
+
+    #[test]
+    fn buffered_until_flush() {
+        let (sender, receiver) = pair();
+        for i in 0..10_u8 {
+            sender.send_with_fds(&Chunk(vec![i; 1000])).expect("send");
+        }
+        // Nothing has been written to the socket yet.
+        receiver
+            .socket
+            .set_nonblocking(true)
+            .expect("set_nonblocking");
+        let mut buf = [0_u8; 1];
+        let error = (&receiver.socket).read(&mut buf).expect_err("read");
+        assert_eq!(error.kind(), std::io::ErrorKind::WouldBlock);
+        receiver
+            .socket
+            .set_nonblocking(false)
+            .expect("set_nonblocking");
+
+        sender.flush().expect("flush");
+        for i in 0..10_u8 {
+            let got: Chunk = receiver.receive_with_fds().expect("receive");
+            assert_eq!(got.0, vec![i; 1000]);
+        }
+    }
+
+    #[test]
+    fn fds_belong_to_their_message() {
+        let opts = memfd::MemfdOptions::new().close_on_exec(true);
+        let file = opts.create("one").expect("memfd_create").into_file();
+        file.write_all_at(b"one", 0).expect("write to memfd");
+
+        let (sender, receiver) = pair();
+        sender
+            .send_with_fds(&Chunk(b"before".to_vec()))
+            .expect("send");
+        sender
+            .send_with_fds(&WithFile {
+                greeting: "hello".to_string(),
+                file,
+            })
+            .expect("send with FD");
+        sender
+            .send_with_fds(&Chunk(b"after".to_vec()))
+            .expect("send");
+        drop(sender);
+
+        let got: Chunk = receiver.receive_with_fds().expect("receive");
+        assert_eq!(got.0, b"before");
+        let mut got: WithFile = receiver.receive_with_fds().expect("receive with FD");
+        assert_eq!(got.greeting, "hello");
+        let mut content = String::new();
+        got.file
+            .read_to_string(&mut content)
+            .expect("read from memfd");
+        assert_eq!(content, "one");
+        let got: Chunk = receiver.receive_with_fds().expect("receive");
+        assert_eq!(got.0, b"after");
+        assert!(matches!(
+            receiver.receive_with_fds::<Chunk>(),
+            Err(ipc::ReceiveError::End)
+        ));
+    }
+
+    #[test]
+    fn larger_than_buffer() {
+        let (sender, receiver) = pair();
+        let sender = std::thread::spawn(move || {
+            for i in 0..200_u8 {
+                sender.send_with_fds(&Chunk(vec![i; 1500])).expect("send");
+            }
+            sender.flush().expect("flush");
+        });
+        for i in 0..200_u8 {
+            let got: Chunk = receiver.receive_with_fds().expect("receive");
+            assert_eq!(got.0, vec![i; 1500]);
+        }
+        sender.join().expect("sender thread");
+    }
+
+    #[test]
+    fn too_large() {
+        let (sender, receiver) = pair();
+        sender.send_with_fds(&Chunk(vec![0; 1000])).expect("send");
+        sender.flush().expect("flush");
+        assert!(matches!(
+            receiver.receive_with_fds::<WithFile>(),
+            Err(ipc::ReceiveError::TooLarge)
+        ));
+    }
+
+    #[test]
+    fn send_too_large() {
+        let (sender, receiver) = pair();
+        sender.send_with_fds(&Chunk(vec![1; 10])).expect("send");
+        assert!(matches!(
+            sender.send_with_fds(&Chunk(vec![2; 3000])),
+            Err(ipc::SendError::TooLarge { .. })
+        ));
+        sender.send_with_fds(&Chunk(vec![3; 10])).expect("send");
+        drop(sender);
+        // Buffered messages around the refused one are unharmed.
+        let got: Chunk = receiver.receive_with_fds().expect("receive");
+        assert_eq!(got.0, vec![1; 10]);
+        let got: Chunk = receiver.receive_with_fds().expect("receive");
+        assert_eq!(got.0, vec![3; 10]);
+        assert!(matches!(
+            receiver.receive_with_fds::<Chunk>(),
+            Err(ipc::ReceiveError::End)
+        ));
+    }
+
+    #[test]
+    fn truncated() {
+        let (mut raw, receiver) = UnixStream::pair().expect("socketpair");
+        let receiver = Stream::new(receiver, limits());
+        // Claims 10 bytes, but the peer goes away after 3.
+        raw.write_all(&[10, 0, 0, 0, 1, 2, 3]).expect("write");
+        drop(raw);
+        match receiver.receive_with_fds::<Chunk>() {
+            Err(ipc::ReceiveError::Socket(error)) => {
+                assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof)
+            }
+            result => panic!("expected unexpected EOF, got {:?}", result),
+        }
+    }
+}
@@ -7,6 +7,9 @@
 // https://github.com/rust-lang/rust/issues/74465
 #![feature(once_cell)]
 
+// Lets the `ipc` derive macros refer to this crate by name, from inside it too.
+extern crate self as tere_server;
+
 // RUST-WART All of our modules need to be public to get doctests to work right.
 // They are *not* implied to be usable by others, and if rustdoc improves they will be made private again.
 // Anything ready for external users will be split into different crates (or even repos).
@@ -15,7 +18,9 @@
 
 pub mod dbus_shell;
 pub mod ipc;
+pub mod local_shell;
 pub mod proto;
 pub mod pty_master;
+pub mod sd_notify;
 pub mod services;
 pub mod socket_activation;
@@ -0,0 +1,522 @@
+//! Open new shell sessions as child processes, for when Tere runs as the user whose shells it serves.
+//!
+//! This is the alternative to [`dbus_shell`](crate::dbus_shell) for single-user deployments, without [`systemd-machined`](https://www.freedesktop.org/software/systemd/man/systemd-machined.service.html) or polkit.
+//! Only sessions on the host, for the current user, are supported.
+
+use std::ffi::{CStr, OsStr, OsString};
+use std::fs::File;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use thiserror::Error;
+
+use crate::dbus_shell::{CommandSpec, ErrorKind, MachineInfo, ShellSpec};
+use crate::pty_master::PtyMaster;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("cannot look up current user: {0}")]
+    UserLookup(#[source] std::io::Error),
+
+    #[error("current user not found in user database")]
+    NoCurrentUser,
+
+    #[error("only the host is supported, not machine: {0}")]
+    NoSuchMachine(String),
+
+    #[error("cannot start sessions for other users: {0}")]
+    WrongUser(String),
+
+    #[error("cannot open PTY: {0}")]
+    OpenPty(#[source] std::io::Error),
+
+    #[error("cannot start shell: {0}")]
+    Spawn(#[source] std::io::Error),
+
+    #[error("cannot pass standard input/output to command: {0}")]
+    Stdio(#[source] std::io::Error),
+
+    #[error("cannot wait for command: {0}")]
+    Wait(#[source] std::io::Error),
+
+    #[error("no such session")]
+    NoSuchSession,
+
+    #[error("cannot signal session: {0}")]
+    Signal(#[source] std::io::Error),
+
+    #[error("cannot list processes: {0}")]
+    ListProcesses(#[source] std::io::Error),
+}
+
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::NoSuchMachine(_) => ErrorKind::NoSuchMachine,
+            Error::WrongUser(_) => ErrorKind::Denied,
+            Error::NoSuchSession => ErrorKind::NoSuchSession,
+            Error::Signal(error) if error.raw_os_error() == Some(libc::EPERM) => ErrorKind::Denied,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+/// The parts of the user database entry we need.
+#[derive(Debug)]
+struct User {
+    name: String,
+    home: PathBuf,
+    shell: PathBuf,
+}
+
+fn current_user() -> Result<User, Error> {
+    let uid = unsafe { libc::getuid() };
+    let mut buf: Vec<libc::c_char> = vec![0; 1024];
+    loop {
+        let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut result: *mut libc::passwd = std::ptr::null_mut();
+        let ret =
+            unsafe { libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+        if ret == libc::ERANGE {
+            let len = buf.len() * 2;
+            buf.resize(len, 0);
+            continue;
+        }
+        if ret != 0 {
+            return Err(Error::UserLookup(std::io::Error::from_raw_os_error(ret)));
+        }
+        if result.is_null() {
+            return Err(Error::NoCurrentUser);
+        }
+        let field = |ptr: *const libc::c_char| {
+            let bytes = unsafe { CStr::from_ptr(ptr) }.to_bytes().to_vec();
+            OsString::from_vec(bytes)
+        };
+        let user = User {
+            name: field(pwd.pw_name).to_string_lossy().into_owned(),
+            home: PathBuf::from(field(pwd.pw_dir)),
+            shell: PathBuf::from(field(pwd.pw_shell)),
+        };
+        return Ok(user);
+    }
+}
+
+/// Open a new PTY, returning the master and the child side.
+fn open_pty() -> Result<(PtyMaster, File), std::io::Error> {
+    let ret = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC) };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let pty_master = unsafe { PtyMaster::from_raw_fd(ret) };
+    if unsafe { libc::grantpt(pty_master.as_raw_fd()) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    if unsafe { libc::unlockpt(pty_master.as_raw_fd()) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let mut buf = [0u8; 64];
+    let ret = unsafe {
+        libc::ptsname_r(
+            pty_master.as_raw_fd(),
+            buf.as_mut_ptr() as *mut libc::c_char,
+            buf.len(),
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::from_raw_os_error(ret));
+    }
+    let end = buf
+        .iter()
+        .position(|c| *c == 0)
+        .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::InvalidData))?;
+    let path = Path::new(OsStr::from_bytes(&buf[..end]));
+    let pty_child = std::fs::OpenOptions::new()
+        .custom_flags(libc::O_NOCTTY | libc::O_CLOEXEC)
+        .read(true)
+        .write(true)
+        .open(path)?;
+    Ok((pty_master, pty_child))
+}
+
+const DEFAULT_PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+const DEFAULT_TERM: &str = "xterm-256color";
+
+/// Start shells as children of this process, running as the current user.
+pub struct LocalShell {
+    user: User,
+}
+
+impl LocalShell {
+    pub fn new() -> Result<Self, Error> {
+        let user = current_user()?;
+        Ok(Self { user })
+    }
+
+    fn check_user(&self, user: &str) -> Result<(), Error> {
+        if !user.is_empty() && user != self.user.name {
+            return Err(Error::WrongUser(user.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Set up the environment and working directory like a login would, plus `TERM` when given, and `environment`.
+    fn prepare(&self, command: &mut Command, term: Option<&str>, environment: &[&str]) {
+        command
+            .env_clear()
+            .env("HOME", &self.user.home)
+            .env("USER", &self.user.name)
+            .env("LOGNAME", &self.user.name)
+            .env("SHELL", &self.user.shell)
+            .env(
+                "PATH",
+                std::env::var_os("PATH").unwrap_or_else(|| DEFAULT_PATH.into()),
+            )
+            .envs(term.map(|term| ("TERM", term)))
+            .envs(environment.iter().filter_map(|var| {
+                let pos = var.find('=')?;
+                Some((&var[..pos], &var[pos + 1..]))
+            }))
+            .current_dir(&self.user.home);
+    }
+
+    /// Create a new shell session.
+    ///
+    /// Returns the PTY master, and the shell to wait for.
+    pub fn create_shell(&self, spec: &ShellSpec) -> Result<(PtyMaster, RunningCommand), Error> {
+        if spec.machine != ".host" {
+            return Err(Error::NoSuchMachine(spec.machine.to_string()));
+        }
+        self.check_user(spec.user)?;
+
+        let mut command = if spec.program.is_empty() {
+            let mut command = Command::new(&self.user.shell);
+            // Make it a login shell, like machined does.
+            let mut arg0 = OsString::from("-");
+            arg0.push(self.user.shell.file_name().unwrap_or_default());
+            command.arg0(arg0);
+            command
+        } else {
+            let mut command = Command::new(spec.program);
+            if let Some((arg0, args)) = spec.args.split_first() {
+                command.arg0(arg0).args(args);
+            }
+            command
+        };
+
+        self.prepare(&mut command, Some(DEFAULT_TERM), spec.environment);
+
+        let (pty_master, pty_child) = open_pty().map_err(Error::OpenPty)?;
+        let stdio = || {
+            pty_child
+                .try_clone()
+                .map(Stdio::from)
+                .map_err(Error::OpenPty)
+        };
+        command.stdin(stdio()?).stdout(stdio()?).stderr(stdio()?);
+        let child_fd = pty_child.as_raw_fd();
+        unsafe {
+            command.pre_exec(move || {
+                // New session, with the PTY as its controlling terminal.
+                if libc::setsid() < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                if libc::ioctl(child_fd, libc::TIOCSCTTY, 0) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let child = command.spawn().map_err(Error::Spawn)?;
+        Ok((pty_master, RunningCommand { child }))
+    }
+
+    /// Start a non-interactive command, on the host.
+    pub fn run_command(&self, spec: &CommandSpec) -> Result<RunningCommand, Error> {
+        self.check_user(spec.user)?;
+        let mut command = Command::new(spec.program);
+        if let Some((arg0, args)) = spec.args.split_first() {
+            command.arg0(arg0).args(args);
+        }
+        self.prepare(&mut command, None, spec.environment);
+        let stdio = |file: &File| file.try_clone().map(Stdio::from).map_err(Error::Stdio);
+        command
+            .stdin(stdio(spec.stdin)?)
+            .stdout(stdio(spec.stdout)?)
+            .stderr(stdio(spec.stderr)?);
+        let child = command.spawn().map_err(Error::Spawn)?;
+        Ok(RunningCommand { child })
+    }
+
+    /// The only machine we can start sessions on, the host.
+    pub fn list_machines(&self) -> Vec<MachineInfo> {
+        vec![MachineInfo {
+            name: ".host".to_string(),
+            class: "host".to_string(),
+            service: String::new(),
+            state: "running".to_string(),
+        }]
+    }
+
+    /// End the session that process `pid` is the leader of.
+    ///
+    /// This kills all processes of the session, not just `pid`, including background jobs in process groups of their own.
+    pub fn terminate_session(&self, pid: u32) -> Result<(), Error> {
+        let mut killed = signal_all(pid, libc::SIGKILL)?;
+        if killed.is_empty() {
+            return Err(Error::NoSuchSession);
+        }
+        // Processes forked after we looked, before their parent was killed, need another look.
+        // A killed process can't fork anymore, so this ends once a look finds nothing new.
+        loop {
+            let found = signal_all(pid, libc::SIGKILL)?;
+            let before = killed.len();
+            killed.extend(found);
+            if killed.len() == before {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Send a signal to all processes in the session that process `pid` is the leader of.
+    pub fn signal_session(&self, pid: u32, signal: i32) -> Result<(), Error> {
+        if signal_all(pid, signal)?.is_empty() {
+            return Err(Error::NoSuchSession);
+        }
+        Ok(())
+    }
+}
+
+/// Processes in the session `sid`, not counting ones that have already exited.
+///
+/// Listed from `/proc`, as the session ID is the only thing all processes in a session have in common; a process can leave its process group, but not its session without starting a new one.
+fn session_processes(sid: u32) -> Result<Vec<libc::pid_t>, std::io::Error> {
+    let mut pids = Vec::new();
+    for entry in std::fs::read_dir("/proc")? {
+        let entry = entry?;
+        let pid: libc::pid_t = match entry.file_name().to_str().and_then(|s| s.parse().ok()) {
+            Some(pid) => pid,
+            None => continue,
+        };
+        // The process may have exited since listing the directory.
+        let stat = match std::fs::read_to_string(entry.path().join("stat")) {
+            Ok(stat) => stat,
+            Err(_) => continue,
+        };
+        // The command name in parentheses may contain anything, so look for fields after the last parenthesis: state, ppid, pgrp and session.
+        let fields: Vec<&str> = match stat.rfind(')') {
+            Some(pos) => stat[pos + 1..].split_whitespace().take(4).collect(),
+            None => continue,
+        };
+        if let [state, _ppid, _pgrp, session] = fields[..] {
+            if state != "Z" && session.parse() == Ok(sid) {
+                pids.push(pid);
+            }
+        }
+    }
+    Ok(pids)
+}
+
+/// Send a signal to all processes in the session `sid`, returning the processes signaled.
+fn signal_all(sid: u32, signal: i32) -> Result<std::collections::HashSet<libc::pid_t>, Error> {
+    let mut signaled = std::collections::HashSet::new();
+    for pid in session_processes(sid).map_err(Error::ListProcesses)? {
+        let ret = unsafe { libc::kill(pid, signal) };
+        if ret < 0 {
+            let error = std::io::Error::last_os_error();
+            // Exited since we looked.
+            if error.raw_os_error() == Some(libc::ESRCH) {
+                continue;
+            }
+            return Err(Error::Signal(error));
+        }
+        signaled.insert(pid);
+    }
+    Ok(signaled)
+}
+
+/// A shell or command started by [LocalShell], to be waited for.
+pub struct RunningCommand {
+    child: Child,
+}
+
+impl RunningCommand {
+    pub fn wait(mut self) -> Result<ExitStatus, Error> {
+        self.child.wait().map_err(Error::Wait)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::os::unix::process::ExitStatusExt;
+
+    use super::*;
+
+    fn spec<'a>(
+        machine: &'a str,
+        user: &'a str,
+        program: &'a str,
+        args: &'a [&'a str],
+    ) -> ShellSpec<'a> {
+        ShellSpec {
+            machine,
+            user,
+            program,
+            args,
+            environment: &["XYZZY=thud"],
+        }
+    }
+
+    #[test]
+    fn run_program() {
+        let shell = LocalShell::new().expect("LocalShell::new");
+        let (pty_master, shell) = shell
+            .create_shell(&spec(
+                ".host",
+                "",
+                "/bin/sh",
+                &["sh", "-c", "echo \"$XYZZY\""],
+            ))
+            .expect("create_shell");
+        let mut output = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !output.windows(4).any(|w| w == b"thud") {
+            match (&pty_master).read(&mut buf) {
+                // Once the child is gone, reading the PTY master fails with EIO.
+                Ok(0) | Err(_) => break,
+                Ok(n) => output.extend_from_slice(&buf[..n]),
+            }
+        }
+        assert!(
+            output.windows(4).any(|w| w == b"thud"),
+            "output: {:?}",
+            String::from_utf8_lossy(&output)
+        );
+        assert_eq!(shell.wait().expect("wait").code(), Some(0));
+    }
+
+    #[test]
+    fn session_leader() {
+        let shell = LocalShell::new().expect("LocalShell::new");
+        let (pty_master, sleep) = shell
+            .create_shell(&spec(".host", "", "/bin/sleep", &["sleep", "60"]))
+            .expect("create_shell");
+        // The child may not have called setsid yet.
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        let sid = loop {
+            if let Ok(sid) = pty_master.session_id() {
+                break sid;
+            }
+            assert!(std::time::Instant::now() < deadline, "no session on PTY");
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        };
+        shell
+            .terminate_session(sid as u32)
+            .expect("terminate_session");
+        let status = sleep.wait().expect("wait");
+        assert_eq!(status.signal(), Some(libc::SIGKILL));
+        assert!(matches!(
+            shell.terminate_session(sid as u32),
+            Err(Error::NoSuchSession)
+        ));
+    }
+
+    #[test]
+    fn terminate_background_jobs() {
+        let shell = LocalShell::new().expect("LocalShell::new");
+        // With job control, the background job gets a process group of its own.
+        let (pty_master, sh) = shell
+            .create_shell(&spec(
+                ".host",
+                "",
+                "/bin/sh",
+                &["sh", "-c", "set -m; sleep 60 & echo \"job=$!.\"; wait"],
+            ))
+            .expect("create_shell");
+        let mut output = String::new();
+        let mut buf = [0u8; 1024];
+        let job: libc::pid_t = loop {
+            if let Some(start) = output.find("job=") {
+                if let Some(len) = output[start..].find('.') {
+                    break output[start + 4..start + len].parse().expect("job pid");
+                }
+            }
+            let n = (&pty_master).read(&mut buf).expect("read PTY");
+            output.push_str(&String::from_utf8_lossy(&buf[..n]));
+        };
+        let sid = pty_master.session_id().expect("session_id");
+        assert_ne!(unsafe { libc::getpgid(job) }, sid);
+
+        shell
+            .terminate_session(sid as u32)
+            .expect("terminate_session");
+        sh.wait().expect("wait");
+        // The job is not our child, so it may linger as a zombie until someone else reaps it.
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        while session_processes(sid as u32)
+            .expect("session_processes")
+            .contains(&job)
+        {
+            assert!(std::time::Instant::now() < deadline, "job still running");
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn run_command() {
+        let shell = LocalShell::new().expect("LocalShell::new");
+        let dir = std::env::temp_dir();
+        let output_path = |name: &str| {
+            dir.join(format!(
+                "tere-test-local-shell-{}-{}",
+                std::process::id(),
+                name
+            ))
+        };
+        let (stdout_path, stderr_path) = (output_path("stdout"), output_path("stderr"));
+        let stdin = File::open("/dev/null").expect("open /dev/null");
+        let stdout = File::create(&stdout_path).expect("create stdout file");
+        let stderr = File::create(&stderr_path).expect("create stderr file");
+        let command = shell
+            .run_command(&CommandSpec {
+                user: "",
+                program: "/bin/sh",
+                args: &["sh", "-c", "echo \"$XYZZY\"; echo foo >&2; exit 3"],
+                environment: &["XYZZY=thud"],
+                stdin: &stdin,
+                stdout: &stdout,
+                stderr: &stderr,
+            })
+            .expect("run_command");
+        let status = command.wait().expect("wait");
+        assert_eq!(status.code(), Some(3));
+        let read = |path: &Path| {
+            let got = std::fs::read_to_string(path).expect("read output");
+            std::fs::remove_file(path).expect("remove output file");
+            got
+        };
+        assert_eq!(read(&stdout_path), "thud\n");
+        assert_eq!(read(&stderr_path), "foo\n");
+    }
+
+    #[test]
+    fn other_machine() {
+        let shell = LocalShell::new().expect("LocalShell::new");
+        let result = shell.create_shell(&spec("xyzzy", "", "", &[]));
+        assert!(matches!(result, Err(Error::NoSuchMachine(name)) if name == "xyzzy"));
+    }
+
+    #[test]
+    fn other_user() {
+        let shell = LocalShell::new().expect("LocalShell::new");
+        let other = format!("{}-not", shell.user.name);
+        let result = shell.create_shell(&spec(".host", &other, "", &[]));
+        match result {
+            Err(error) => assert_eq!(error.kind(), ErrorKind::Denied),
+            Ok(_) => panic!("started a session for another user"),
+        }
+    }
+}
@@ -0,0 +1,28 @@
+use serde::{Deserialize, Serialize};
+
+use crate::ipc;
+use crate::proto::ExitStatus;
+
+pub const CLIENT_INTENT: &str = "tere 2021-07-14T10:02:47 command client";
+pub const SERVER_INTENT: &str = "tere 2021-07-14T10:03:05 command server";
+
+/// Most data carried in a single [Input::Stdin], [Output::Stdout] or [Output::Stderr].
+pub const MAX_DATA: usize = 4096;
+
+#[derive(Debug, Serialize, Deserialize, ipc::Message)]
+pub enum Output {
+    Stdout(#[ipc(max_len = MAX_DATA)] Vec<u8>),
+    Stderr(#[ipc(max_len = MAX_DATA)] Vec<u8>),
+    /// The command has ended, and all of its output has been sent.
+    /// This is the last message.
+    ///
+    /// `None` if the exit status could not be determined.
+    Finished(Option<ExitStatus>),
+}
+
+#[derive(Debug, Serialize, Deserialize, ipc::Message)]
+pub enum Input {
+    Stdin(#[ipc(max_len = MAX_DATA)] Vec<u8>),
+    /// There is no more input, the command sees end of file.
+    StdinEnd,
+}
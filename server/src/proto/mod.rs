@@ -1,2 +1,16 @@
+use serde::{Deserialize, Serialize};
+
+use crate::ipc;
+
+pub mod command;
 pub mod pty;
 pub mod sessions;
+
+/// How a process ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ipc::bounded::Bounded)]
+pub enum ExitStatus {
+    /// The process exited with this exit code.
+    Exited(i32),
+    /// The process was killed by this signal, as on Linux.
+    Killed(i32),
+}
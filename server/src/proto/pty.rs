@@ -2,6 +2,7 @@ use serde::{Deserialize, Serialize};
 use std::os::unix::net::UnixDatagram;
 
 use crate::ipc;
+use crate::proto::ExitStatus;
 use crate::pty_master::PtyMaster;
 
 pub mod user;
@@ -9,34 +10,79 @@ pub mod user;
 pub const CLIENT_INTENT: &str = "tere 2021-06-11T21:34:03 pty client";
 pub const SERVER_INTENT: &str = "tere 2021-06-11T21:35:37 pty server";
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Init {
-    // Always need to transport some data, to make FD passing work.
-    //
-    // TODO make this the ipc module's concern.
-    pub _dummy: u8,
+/// What to do when a client is not consuming output fast enough.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ipc::bounded::Bounded)]
+pub enum SlowConsumerPolicy {
+    /// Disconnect the client.
+    Disconnect,
+    /// Drop output for the client until it catches up, then tell the client it has missed output, and send a snapshot of the screen to redraw from.
+    Resync,
+    /// Stop reading from the PTY until the client catches up.
+    /// This slows down the session for every other client, too.
+    Backpressure,
+}
+
+/// Largest allowed [Options::max_queued_bytes].
+pub const MAX_QUEUED_BYTES_LIMIT: u32 = 16 * 1024 * 1024;
+/// Largest allowed [Options::scrollback_bytes].
+pub const SCROLLBACK_BYTES_LIMIT: u32 = 4 * 1024 * 1024;
+
+/// Configuration for serving a PTY.
+#[derive(Debug, Clone, Serialize, Deserialize, ipc::bounded::Bounded)]
+pub struct Options {
+    /// How much output may be waiting to be sent to a single client, before `slow_consumer` is applied.
+    /// The scrollback and screen replayed to a new client don't count towards this.
+    pub max_queued_bytes: u32,
+    pub slow_consumer: SlowConsumerPolicy,
+    /// How much recent output to keep for replaying to new clients, before the snapshot of the screen.
+    pub scrollback_bytes: u32,
+}
+
+impl Options {
+    /// Are the sizes within the limits.
+    pub fn is_valid(&self) -> bool {
+        self.max_queued_bytes <= MAX_QUEUED_BYTES_LIMIT
+            && self.scrollback_bytes <= SCROLLBACK_BYTES_LIMIT
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            max_queued_bytes: 256 * 1024,
+            slow_consumer: SlowConsumerPolicy::Resync,
+            scrollback_bytes: 64 * 1024,
+        }
+    }
+}
 
+#[derive(Debug, Serialize, Deserialize, ipc::Message)]
+pub struct Init {
     #[serde(with = "ipc::passfd")]
     pub pty_master: PtyMaster,
-}
 
-impl ipc::Message for Init {
-    const MAX_SIZE: usize = 1;
-    const MAX_FDS: usize = 1;
+    pub options: Options,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, ipc::Message)]
 pub enum Request {
     NewClient {
-        // TODO make this the ipc module's concern.
-        _dummy: u8,
-
         /// A `SOCK_SEQPACKET` socket (not `SOCK_DATAGRAM`), regardless of our best option of how to represent it in Rust.
+        /// May also be a `SOCK_STREAM` socket, for a client that wants output buffered, see [ipc::stream].
         #[serde(with = "ipc::passfd")]
         fd: UnixDatagram,
     },
+    /// How the shell ended, in answer to [Event::Closed].
+    SessionEnded { exit_status: Option<ExitStatus> },
 }
 
-impl ipc::Message for Request {
-    const MAX_FDS: usize = 1;
+/// Sent by the PTY service to whoever started it, to report changes in the session.
+#[derive(Debug, Serialize, Deserialize, ipc::Message)]
+pub enum Event {
+    /// Number of attached clients changed.
+    Clients { count: u32 },
+    /// The PTY was closed, the session is over.
+    ///
+    /// Clients are told the exit status once it arrives in [Request::SessionEnded], or after a short wait without it.
+    Closed,
 }
@@ -1,26 +1,51 @@
 use serde::{Deserialize, Serialize};
 
 use crate::ipc;
+use crate::proto::ExitStatus;
+
+use super::SlowConsumerPolicy;
 
 pub const CLIENT_INTENT: &str = "tere 2021-06-22T12:12:30 pty_user client";
 pub const SERVER_INTENT: &str = "tere 2021-06-22T12:12:51 pty_user server";
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Most output sent in one [Output::SessionOutput].
+pub const MAX_OUTPUT_LEN: usize = 4096;
+/// Most input sent in one [Input::KeyboardInput].
+pub const MAX_INPUT_LEN: usize = 4096;
+/// Most text sent in one [Input::PasteInput].
+/// A longer paste must be split, and is seen by the application as several pastes.
+pub const MAX_PASTE_LEN: usize = 64 * 1024;
+
+#[derive(Debug, Serialize, Deserialize, ipc::Message)]
 pub enum Output {
-    SessionOutput(Vec<u8>),
+    SessionOutput(#[ipc(max_len = MAX_OUTPUT_LEN)] Vec<u8>),
+    /// This client fell behind on output, and the session applied the given policy.
+    ///
+    /// With [SlowConsumerPolicy::Resync], output has been lost just before this message, and the following output starts with a snapshot of the screen.
+    /// With [SlowConsumerPolicy::Disconnect], this is the last message.
+    SlowConsumer(SlowConsumerPolicy),
+    /// The session is over, and this is the last message.
+    ///
+    /// `exit_status` is that of the shell, when known.
+    SessionEnded {
+        exit_status: Option<ExitStatus>,
+    },
 }
 
-impl ipc::Message for Output {}
-
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, ipc::Message)]
 pub enum Input {
-    KeyboardInput(Vec<u8>),
-    // TODO
-    // PasteInput(Vec<u8>),
-    // Resize{
-    //     Rows: u16,
-    //     Columns: u16,
-    // },
+    KeyboardInput(#[ipc(max_len = MAX_INPUT_LEN)] Vec<u8>),
+    /// Text pasted by the user.
+    /// Delivered to the application as a bracketed paste, if it has asked for that.
+    PasteInput(#[ipc(max_len = MAX_PASTE_LEN)] Vec<u8>),
+    /// The client terminal changed size.
+    Resize {
+        rows: u16,
+        columns: u16,
+    },
 }
 
-impl ipc::Message for Input {}
+/// Limits of this protocol, for clients connecting with a [Stream](ipc::stream::Stream).
+pub fn stream_limits() -> ipc::stream::Limits {
+    ipc::handshake::limits().and::<Input>().and::<Output>()
+}
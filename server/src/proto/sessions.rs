@@ -1,42 +1,435 @@
 use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
 use std::os::unix::net::UnixDatagram;
+use std::str::FromStr;
+use std::time::SystemTime;
+use thiserror::Error;
 
 use crate::ipc;
+use crate::proto;
+use crate::proto::ExitStatus;
 
 pub const CLIENT_INTENT: &str = "tere 2021-07-01T19:41:51 sessions client";
 pub const SERVER_INTENT: &str = "tere 2021-07-01T19:42:20 sessions server";
 
-#[derive(Debug, Serialize, Deserialize)]
+pub const SESSION_ID_BYTES: usize = 24;
+
+/// Longest allowed username, in bytes.
+pub const MAX_USER_LEN: usize = 256;
+/// Longest allowed program path, in bytes.
+pub const MAX_PROGRAM_LEN: usize = 4096;
+/// Most arguments, and most environment variables, for one program.
+pub const MAX_ARGS: usize = 64;
+/// Longest allowed argument or environment variable, in bytes.
+pub const MAX_ARG_LEN: usize = 1024;
+/// Most machines in one [Response::Machines].
+pub const MAX_MACHINES: usize = 64;
+/// Longest description of a machine in [MachineInfo], in bytes.
+pub const MAX_MACHINE_INFO_LEN: usize = 64;
+/// Longest explanation in [InvalidRequest::Malformed], in bytes.
+pub const MAX_MALFORMED_LEN: usize = 1024;
+/// Most sessions in one [Response::Sessions].
+pub const SESSIONS_PER_PAGE: usize = 10;
+
+/// Identifies a running session.
+///
+/// Session IDs are random, and knowing one is enough to attach to the session.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, ipc::bounded::Bounded,
+)]
+pub struct SessionId(pub [u8; SESSION_ID_BYTES]);
+
+impl fmt::Display for SessionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for b in &self.0 {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("invalid session ID")]
+pub struct ParseSessionIdError;
+
+/// Parse the hex format produced by `Display`.
+impl FromStr for SessionId {
+    type Err = ParseSessionIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != SESSION_ID_BYTES * 2 || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(ParseSessionIdError);
+        }
+        let mut id = [0u8; SESSION_ID_BYTES];
+        for (i, b) in id.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).map_err(|_| ParseSessionIdError)?;
+        }
+        Ok(SessionId(id))
+    }
+}
+
+/// Longest accepted container name.
+/// `systemd-machined` requires machine names to be valid hostnames.
+pub const CONTAINER_NAME_MAX_LEN: usize = 64;
+
+#[derive(Error, Debug)]
+pub enum ContainerNameError {
+    #[error("container name is empty")]
+    Empty,
+
+    #[error("container name is longer than {} bytes", CONTAINER_NAME_MAX_LEN)]
+    TooLong,
+
+    /// Names starting with a dot are reserved, for example `.host`.
+    #[error("container name must not start with a dot")]
+    LeadingDot,
+
+    #[error("container name must not contain {0:?}")]
+    BadChar(char),
+}
+
+/// Name of a container, as known to `systemd-machined`.
+///
+/// Validated on creation and deserialization, so a container name can never refer to the host.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize, ipc::bounded::Bounded)]
+#[serde(try_from = "String")]
+pub struct ContainerName(#[ipc(max_len = CONTAINER_NAME_MAX_LEN)] String);
+
+impl ContainerName {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl TryFrom<String> for ContainerName {
+    type Error = ContainerNameError;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        if name.is_empty() {
+            return Err(ContainerNameError::Empty);
+        }
+        if name.len() > CONTAINER_NAME_MAX_LEN {
+            return Err(ContainerNameError::TooLong);
+        }
+        if name.starts_with('.') {
+            return Err(ContainerNameError::LeadingDot);
+        }
+        if let Some(c) = name.chars().find(|c| *c == '/' || c.is_control()) {
+            return Err(ContainerNameError::BadChar(c));
+        }
+        Ok(ContainerName(name))
+    }
+}
+
+impl FromStr for ContainerName {
+    type Err = ContainerNameError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::try_from(s.to_string())
+    }
+}
+
+impl fmt::Display for ContainerName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ipc::bounded::Bounded)]
 pub enum Machine {
     Host,
     /// Name of container to connect to.
-    //
-    // TODO Ensure this does not start with ".", to force use of Machine::Host.
-    Container(String),
+    Container(ContainerName),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, ipc::bounded::Bounded)]
 pub struct CreateShellSession {
     /// Client for this session.
+    /// `SOCK_SEQPACKET`, or `SOCK_STREAM` to have output buffered, see [ipc::stream].
     #[serde(with = "ipc::passfd")]
     pub fd: UnixDatagram,
     pub machine: Machine,
     /// Username to start the session as.
+    #[ipc(max_len = MAX_USER_LEN)]
     pub user: String,
     /// Absolute path to shell to run.
+    /// Defaults to the login shell of the user.
+    #[ipc(max_len = MAX_PROGRAM_LEN)]
     pub program: Option<String>,
     /// Arguments to the shell.
     /// First argument should be the name of the program.
+    /// Required with `program`, and not allowed without it.
+    #[ipc(max_len = MAX_ARGS, max_item_len = MAX_ARG_LEN)]
     pub args: Option<Vec<String>>,
-    /// Environment variables to pass.
+    /// Environment variables to pass, as `KEY=VALUE`.
+    #[ipc(max_len = MAX_ARGS, max_item_len = MAX_ARG_LEN)]
     pub env: Option<Vec<String>>,
+    /// How to serve the terminal, such as the slow consumer policy and the size of the scrollback.
+    /// Defaults to [proto::pty::Options::default].
+    pub pty_options: Option<proto::pty::Options>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Run a program without a terminal.
+///
+/// The client talks to the program with [command](super::command) messages, with standard output and error kept apart.
+/// Currently only supported on the host.
+#[derive(Debug, Serialize, Deserialize, ipc::bounded::Bounded)]
+pub struct RunCommand {
+    /// Client for this command.
+    #[serde(with = "ipc::passfd")]
+    pub fd: UnixDatagram,
+    pub machine: Machine,
+    /// Username to run the command as.
+    /// On the host, this must be an existing user other than root.
+    #[ipc(max_len = MAX_USER_LEN)]
+    pub user: String,
+    /// Absolute path to the program to run.
+    #[ipc(max_len = MAX_PROGRAM_LEN)]
+    pub program: String,
+    /// Arguments to the program.
+    /// First argument should be the name of the program.
+    #[ipc(max_len = MAX_ARGS, max_item_len = MAX_ARG_LEN)]
+    pub args: Vec<String>,
+    /// Environment variables to pass, as `KEY=VALUE`.
+    #[ipc(max_len = MAX_ARGS, max_item_len = MAX_ARG_LEN)]
+    pub env: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ipc::bounded::Bounded)]
+pub struct AttachSession {
+    pub id: SessionId,
+    /// Client to attach to the session.
+    /// `SOCK_SEQPACKET`, or `SOCK_STREAM` to have output buffered, see [ipc::stream].
+    #[serde(with = "ipc::passfd")]
+    pub fd: UnixDatagram,
+}
+
+/// End a session, and all processes in it.
+#[derive(Debug, Serialize, Deserialize, ipc::bounded::Bounded)]
+pub struct TerminateSession {
+    pub id: SessionId,
+}
+
+/// List known sessions, a page at a time.
+#[derive(Debug, Serialize, Deserialize, ipc::bounded::Bounded)]
+pub struct ListSessions {
+    /// Number of sessions to skip, `0` or `next` from the previous page.
+    ///
+    /// Sessions are listed oldest first, so new sessions don't disturb the paging, but sessions forgotten in between pages may cause some to be skipped.
+    pub start: u32,
+}
+
+/// Send a signal to all processes in a session.
+#[derive(Debug, Serialize, Deserialize, ipc::bounded::Bounded)]
+pub struct SignalSession {
+    pub id: SessionId,
+    /// Signal number, as on Linux.
+    pub signal: i32,
+}
+
+/// Requests to the sessions service.
+///
+/// The service trusts whoever can connect to it: every session is listed to every client, and knowing a session ID is enough to attach to it.
+/// Deciding who may see and use which session is up to `tere-policy@`, and the socket must only be reachable by it.
+#[derive(Debug, Serialize, Deserialize, ipc::Message)]
 pub enum Request {
     CreateShellSession(CreateShellSession),
+    AttachSession(AttachSession),
+    ListSessions(ListSessions),
+    TerminateSession(TerminateSession),
+    SignalSession(SignalSession),
+    ListMachines,
+    RunCommand(RunCommand),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ipc::bounded::Bounded)]
+pub enum SessionState {
+    Running,
+    Exited {
+        /// Exit status of the session, if known.
+        status: Option<ExitStatus>,
+        at: SystemTime,
+    },
+}
+
+/// What we know about a session.
+#[derive(Debug, Clone, Serialize, Deserialize, ipc::bounded::Bounded)]
+pub struct SessionInfo {
+    pub id: SessionId,
+    pub machine: Machine,
+    #[ipc(max_len = MAX_USER_LEN)]
+    pub user: String,
+    /// Program requested when creating the session, if not the default shell.
+    #[ipc(max_len = MAX_PROGRAM_LEN)]
+    pub program: Option<String>,
+    pub created: SystemTime,
+    /// Number of clients currently attached.
+    pub clients: u32,
+    pub state: SessionState,
+}
+
+/// A machine sessions can be created on.
+///
+/// Machines with a description longer than [MAX_MACHINE_INFO_LEN] are left out.
+#[derive(Debug, Clone, Serialize, Deserialize, ipc::bounded::Bounded)]
+pub struct MachineInfo {
+    pub machine: Machine,
+    /// `"host"`, `"container"` or `"vm"`.
+    #[ipc(max_len = MAX_MACHINE_INFO_LEN)]
+    pub class: String,
+    /// What registered the machine, for example `"systemd-nspawn"`.
+    #[ipc(max_len = MAX_MACHINE_INFO_LEN)]
+    pub service: String,
+    /// `"opening"`, `"running"` or `"closing"`.
+    #[ipc(max_len = MAX_MACHINE_INFO_LEN)]
+    pub state: String,
+}
+
+/// Why a request was refused without acting on it.
+#[derive(Debug, Serialize, Deserialize, ipc::bounded::Bounded)]
+pub enum InvalidRequest {
+    /// `program` is not an absolute path.
+    ProgramNotAbsolute,
+    /// `program` was given without `args`, or with empty `args`.
+    MissingArgs,
+    /// `args` were given without `program`.
+    ArgsWithoutProgram,
+    /// An environment variable is not of the form `KEY=VALUE`.
+    BadEnvironment,
+    /// A string contains a NUL byte.
+    ContainsNul,
+    /// `user`, `program`, `args` or `env` is over its limit, such as [MAX_USER_LEN] or [MAX_ARGS].
+    TooLong,
+    /// `pty_options` are out of bounds, see [proto::pty::Options::is_valid].
+    BadPtyOptions,
+    /// The request could not be decoded, for example because of an invalid container name.
+    /// The explanation is cut short at [MAX_MALFORMED_LEN].
+    Malformed(#[ipc(max_len = MAX_MALFORMED_LEN)] String),
+}
+
+#[derive(Debug, Serialize, Deserialize, ipc::Message)]
+pub enum Response {
+    /// A new session was created, and the client given in the request is attached to it.
+    Created {
+        id: SessionId,
+    },
+    /// The client given in the request is attached to the session.
+    Attached,
+    NoSuchSession,
+    /// Up to [SESSIONS_PER_PAGE] known sessions, oldest first.
+    /// Exited sessions are remembered for a while.
+    Sessions {
+        #[ipc(max_len = SESSIONS_PER_PAGE)]
+        sessions: Vec<SessionInfo>,
+        /// Where to continue with [ListSessions], if there are more sessions.
+        next: Option<u32>,
+    },
+    Terminated,
+    Signaled,
+    /// All known machines, sorted by name, starting with the host.
+    /// Only the first [MAX_MACHINES] are listed.
+    Machines(#[ipc(max_len = MAX_MACHINES)] Vec<MachineInfo>),
+    /// The client given in the request is talking to the command.
+    CommandStarted,
+    /// The request is not supported for this session or machine.
+    /// Currently, sessions in containers cannot be terminated or signaled, and commands cannot be run in containers.
+    Unsupported,
+    InvalidRequest(InvalidRequest),
+    /// Not allowed, typically by polkit.
+    Denied,
+    NoSuchMachine,
+    NoSuchUser,
+    /// The PTY service could not be started or reached.
+    PtyServiceUnavailable,
+    /// Something went wrong on the server side; details are in the server logs.
+    Internal,
 }
 
-impl ipc::Message for Request {
-    const MAX_FDS: usize = 1;
+#[cfg(test)]
+mod tests {
+    use bincode::Options;
+
+    use super::*;
+
+    fn decode_container(name: &str) -> Result<Machine, bincode::Error> {
+        let options = bincode::DefaultOptions::new();
+        // Serialize as a plain string, to get past the checks on the sending side.
+        #[derive(Serialize)]
+        enum RawMachine<'a> {
+            #[allow(dead_code)]
+            Host,
+            Container(&'a str),
+        }
+        let encoded = options
+            .serialize(&RawMachine::Container(name))
+            .expect("serialize");
+        options.deserialize(&encoded)
+    }
+
+    #[test]
+    fn container_name_valid() {
+        let machine = decode_container("xyzzy-1.example").expect("valid name");
+        assert!(matches!(machine, Machine::Container(name) if name.as_str() == "xyzzy-1.example"));
+    }
+
+    #[test]
+    fn container_name_invalid() {
+        for name in &[".host", ".xyzzy", "", "xyzzy/thud", "../xyzzy", "xyzzy\0"] {
+            assert!(decode_container(name).is_err(), "accepted {:?}", name);
+        }
+        let long = "x".repeat(CONTAINER_NAME_MAX_LEN + 1);
+        let error = decode_container(&long).expect_err("accepted overlong name");
+        assert!(error.to_string().contains("longer than"), "{}", error);
+        assert!(decode_container(&long[1..]).is_ok());
+    }
+
+    #[test]
+    fn container_name_from_str() {
+        assert!(matches!(
+            ".host".parse::<ContainerName>(),
+            Err(ContainerNameError::LeadingDot)
+        ));
+        assert!(matches!(
+            "a/b".parse::<ContainerName>(),
+            Err(ContainerNameError::BadChar('/'))
+        ));
+    }
+
+    #[test]
+    fn sessions_page_fits() {
+        let time = SystemTime::UNIX_EPOCH + std::time::Duration::new(i64::MAX as u64, 999_999_999);
+        let info = SessionInfo {
+            id: SessionId([0xff; SESSION_ID_BYTES]),
+            machine: Machine::Container(
+                "x".repeat(CONTAINER_NAME_MAX_LEN)
+                    .parse()
+                    .expect("container name"),
+            ),
+            user: "x".repeat(MAX_USER_LEN),
+            program: Some("/".repeat(MAX_PROGRAM_LEN)),
+            created: time,
+            clients: u32::MAX,
+            state: SessionState::Exited {
+                status: Some(ExitStatus::Killed(i32::MIN)),
+                at: time,
+            },
+        };
+        let response = Response::Sessions {
+            sessions: vec![info; SESSIONS_PER_PAGE],
+            next: Some(u32::MAX),
+        };
+        let encoded = bincode::DefaultOptions::new()
+            .serialize(&response)
+            .expect("serialize");
+        assert!(encoded.len() <= <Response as ipc::Message>::MAX_SIZE);
+    }
+
+    #[test]
+    fn session_id_roundtrip() {
+        let id = SessionId(rand::random());
+        assert_eq!(id.to_string().parse::<SessionId>().ok(), Some(id));
+        assert!("xyzzy".parse::<SessionId>().is_err());
+        assert!("+f".repeat(SESSION_ID_BYTES).parse::<SessionId>().is_err());
+    }
 }
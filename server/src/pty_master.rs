@@ -62,4 +62,67 @@ impl std::io::Write for &PtyMaster {
     }
 }
 
-// TODO get/set terminal size
+// Missing from the libc crate for glibc targets.
+#[cfg(any(target_arch = "mips", target_arch = "mips64"))]
+const TIOCGSID: libc::c_ulong = 0x7416;
+#[cfg(target_arch = "sparc64")]
+const TIOCGSID: libc::c_ulong = 0x4004_7485;
+#[cfg(not(any(target_arch = "mips", target_arch = "mips64", target_arch = "sparc64")))]
+const TIOCGSID: libc::c_ulong = 0x5429;
+
+/// Terminal window size, in character cells.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WindowSize {
+    pub rows: u16,
+    pub columns: u16,
+}
+
+impl PtyMaster {
+    /// Get the terminal window size, with `TIOCGWINSZ`.
+    pub fn window_size(&self) -> Result<WindowSize, std::io::Error> {
+        let mut winsize = libc::winsize {
+            ws_row: 0,
+            ws_col: 0,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        let ret = unsafe { libc::ioctl(self.0, libc::TIOCGWINSZ, &mut winsize) };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(WindowSize {
+            rows: winsize.ws_row,
+            columns: winsize.ws_col,
+        })
+    }
+
+    /// Set the terminal window size, with `TIOCSWINSZ`.
+    ///
+    /// The kernel sends `SIGWINCH` to the foreground process group of the terminal, if the size changed.
+    pub fn set_window_size(&self, size: WindowSize) -> Result<(), std::io::Error> {
+        // We don't know pixel sizes; zero means unused.
+        let winsize = libc::winsize {
+            ws_row: size.rows,
+            ws_col: size.columns,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        let ret = unsafe { libc::ioctl(self.0, libc::TIOCSWINSZ, &winsize) };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Get the session ID of the process session the terminal belongs to, with `TIOCGSID`.
+    ///
+    /// That is the PID of the session leader, typically the shell.
+    pub fn session_id(&self) -> Result<libc::pid_t, std::io::Error> {
+        let mut sid: libc::pid_t = 0;
+        let ret = unsafe { libc::ioctl(self.0, TIOCGSID, &mut sid) };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(sid)
+    }
+}
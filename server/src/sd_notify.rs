@@ -0,0 +1,347 @@
+//! Systemd service notifications.
+//!
+//! # Resources
+//!
+//! - <https://www.freedesktop.org/software/systemd/man/sd_notify.html>
+//! - <https://www.freedesktop.org/software/systemd/man/systemd.service.html#FileDescriptorStoreMax=>
+
+use std::ffi::{OsStr, OsString};
+use std::io::IoSlice;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use std::os::unix::prelude::OsStrExt;
+use std::time::Duration;
+
+// Using unstable feature `unix_socket_ancillary_data`.
+// https://github.com/rust-lang/rust/issues/76915
+use std::os::unix::net::SocketAncillary;
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("invalid environment value: $NOTIFY_SOCKET: {0:?}")]
+    InvalidAddress(OsString),
+
+    #[error("cannot connect to notify socket: {0}")]
+    Connect(#[source] std::io::Error),
+
+    #[error("socket send error: {0}")]
+    Send(#[source] std::io::Error),
+
+    #[error("too many FDs to pass: {0}")]
+    TooManyFds(usize),
+}
+
+/// Send notifications to the service manager.
+///
+/// When not started by systemd, or the service does not accept notifications, all notifications are silently discarded.
+#[derive(Debug)]
+pub struct Notifier {
+    socket: Option<UnixDatagram>,
+}
+
+impl Notifier {
+    /// Connect to the socket given in `$NOTIFY_SOCKET`.
+    pub fn new() -> Result<Self, Error> {
+        Self::from_address(std::env::var_os("NOTIFY_SOCKET"))
+    }
+
+    /// A notifier that discards everything, for when there is no service manager.
+    pub fn disabled() -> Self {
+        Self { socket: None }
+    }
+
+    /// Like `new`, but read `$<prefix>NOTIFY_SOCKET` instead, so tests don't interfere with each other.
+    #[cfg(test)]
+    fn test(prefix: &str) -> Result<Self, Error> {
+        Self::from_address(std::env::var_os(format!("{}NOTIFY_SOCKET", prefix)))
+    }
+
+    fn from_address(addr: Option<OsString>) -> Result<Self, Error> {
+        let socket = match addr {
+            None => None,
+            Some(addr) => Some(connect(&addr)?),
+        };
+        Ok(Self { socket })
+    }
+
+    /// Is anyone listening to our notifications.
+    pub fn is_enabled(&self) -> bool {
+        self.socket.is_some()
+    }
+
+    /// Send a raw notification, a newline-separated list of `KEY=value` assignments, along with `fds`.
+    pub fn notify(&self, state: &str, fds: &[RawFd]) -> Result<(), Error> {
+        let socket = match &self.socket {
+            None => return Ok(()),
+            Some(socket) => socket,
+        };
+        let space = std::mem::size_of_val(fds);
+        let space = unsafe { libc::CMSG_SPACE(space as u32) } as usize;
+        let mut ancillary_buffer = vec![0; space];
+        let mut ancillary = SocketAncillary::new(&mut ancillary_buffer[..]);
+        if !fds.is_empty() && !ancillary.add_fds(fds) {
+            return Err(Error::TooManyFds(fds.len()));
+        }
+        let iovec = &mut [IoSlice::new(state.as_bytes())][..];
+        socket
+            .send_vectored_with_ancillary(iovec, &mut ancillary)
+            .map_err(Error::Send)?;
+        Ok(())
+    }
+
+    /// Tell the service manager that startup is finished.
+    ///
+    /// With `Type=notify`, the service is not considered started until this.
+    pub fn ready(&self) -> Result<(), Error> {
+        self.notify("READY=1", &[])
+    }
+
+    /// Describe the state of the service, for humans.
+    ///
+    /// Shown by `systemctl status`.
+    /// `status` must be a single line.
+    pub fn status(&self, status: &str) -> Result<(), Error> {
+        let state = format!("STATUS={}", status);
+        self.notify(&state, &[])
+    }
+
+    /// Tell the service manager we're still alive, when the service has `WatchdogSec=`.
+    ///
+    /// This must be sent more often than [watchdog_interval], or the service is considered failed.
+    pub fn watchdog(&self) -> Result<(), Error> {
+        self.notify("WATCHDOG=1", &[])
+    }
+
+    /// Ask the service manager to hold on to `fds` over restarts of this service.
+    ///
+    /// The FDs are passed back on the next start with socket activation, named `name`.
+    /// `name` must not contain `:` or control characters.
+    pub fn fd_store(&self, name: &str, fds: &[RawFd]) -> Result<(), Error> {
+        let state = format!("FDSTORE=1\nFDNAME={}", name);
+        self.notify(&state, fds)
+    }
+
+    /// Ask the service manager to close all FDs stored as `name`.
+    pub fn fd_store_remove(&self, name: &str) -> Result<(), Error> {
+        let state = format!("FDSTOREREMOVE=1\nFDNAME={}", name);
+        self.notify(&state, &[])
+    }
+}
+
+/// How often the service manager expects [Notifier::watchdog], from `$WATCHDOG_USEC`.
+///
+/// Returns `None` when the service has no `WatchdogSec=`, or the watchdog is meant for another process.
+pub fn watchdog_interval() -> Option<Duration> {
+    parse_watchdog(
+        std::env::var_os("WATCHDOG_USEC"),
+        std::env::var_os("WATCHDOG_PID"),
+    )
+}
+
+fn parse_watchdog(usec: Option<OsString>, pid: Option<OsString>) -> Option<Duration> {
+    if let Some(pid) = pid {
+        let pid: u32 = pid.to_str()?.parse().ok()?;
+        if pid != std::process::id() {
+            return None;
+        }
+    }
+    let usec: u64 = usec?.to_str()?.parse().ok()?;
+    if usec == 0 {
+        return None;
+    }
+    Some(Duration::from_micros(usec))
+}
+
+/// Connect a datagram socket to `addr`, which is either an absolute path or, when starting with `@`, a name in the abstract namespace.
+fn connect(addr: &OsStr) -> Result<UnixDatagram, Error> {
+    let invalid = || Error::InvalidAddress(addr.to_os_string());
+    let bytes = addr.as_bytes();
+    let is_abstract = match bytes.first() {
+        Some(b'/') => false,
+        Some(b'@') => true,
+        _ => return Err(invalid()),
+    };
+
+    let mut sockaddr: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+    sockaddr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    // Leave room for the terminating NUL of a path.
+    if bytes.len() >= sockaddr.sun_path.len() {
+        return Err(invalid());
+    }
+    for (dst, src) in sockaddr.sun_path.iter_mut().zip(bytes) {
+        *dst = *src as libc::c_char;
+    }
+    let mut len = std::mem::size_of::<libc::sa_family_t>() + bytes.len();
+    if is_abstract {
+        sockaddr.sun_path[0] = 0;
+    } else {
+        len += 1;
+    }
+
+    let ret = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if ret < 0 {
+        return Err(Error::Connect(std::io::Error::last_os_error()));
+    }
+    let socket = unsafe { UnixDatagram::from_raw_fd(ret) };
+    let ret = unsafe {
+        libc::connect(
+            ret,
+            &sockaddr as *const _ as *const libc::sockaddr,
+            len as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(Error::Connect(std::io::Error::last_os_error()));
+    }
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+    use std::io::IoSliceMut;
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::os::unix::net::{AncillaryData, SocketAncillary, UnixDatagram};
+    use std::path::PathBuf;
+    use std::sync::atomic;
+
+    use super::{Error, Notifier};
+
+    /// A stand-in for the service manager.
+    struct Manager {
+        prefix: String,
+        path: PathBuf,
+        socket: UnixDatagram,
+    }
+
+    impl Manager {
+        fn new() -> Self {
+            static SEQUENCE: atomic::AtomicU64 = atomic::AtomicU64::new(1);
+            let seq = SEQUENCE.fetch_add(1, atomic::Ordering::SeqCst);
+            let prefix = format!("TEST_SD_NOTIFY_{}_", seq);
+            let path = std::env::temp_dir().join(format!(
+                "tere-test-notify-{}-{}.sock",
+                std::process::id(),
+                seq
+            ));
+            let _ = std::fs::remove_file(&path);
+            let socket = UnixDatagram::bind(&path).expect("bind notify socket");
+            std::env::set_var(format!("{}NOTIFY_SOCKET", prefix), &path);
+            Self {
+                prefix,
+                path,
+                socket,
+            }
+        }
+
+        fn notifier(&self) -> Notifier {
+            Notifier::test(&self.prefix).expect("Notifier::test")
+        }
+
+        fn receive(&self) -> (String, Vec<std::fs::File>) {
+            let mut buf = [0u8; 4096];
+            let mut ancillary_buffer = [0u8; 128];
+            let mut ancillary = SocketAncillary::new(&mut ancillary_buffer[..]);
+            let iovec = &mut [IoSliceMut::new(&mut buf[..])][..];
+            let (size, truncated) = self
+                .socket
+                .recv_vectored_with_ancillary(iovec, &mut ancillary)
+                .expect("receive notification");
+            assert!(!truncated);
+            let mut files = Vec::new();
+            for message in ancillary.messages().flatten() {
+                if let AncillaryData::ScmRights(rights) = message {
+                    for fd in rights {
+                        files.push(unsafe { std::fs::File::from_raw_fd(fd) });
+                    }
+                }
+            }
+            let state = String::from_utf8(buf[..size].to_vec()).expect("notification is UTF-8");
+            (state, files)
+        }
+    }
+
+    impl Drop for Manager {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    #[test]
+    fn ready() {
+        let manager = Manager::new();
+        let notifier = manager.notifier();
+        assert!(notifier.is_enabled());
+        notifier.ready().expect("ready");
+        let (state, files) = manager.receive();
+        assert_eq!(state, "READY=1");
+        assert!(files.is_empty());
+    }
+
+    #[test]
+    fn status_and_watchdog() {
+        let manager = Manager::new();
+        let notifier = manager.notifier();
+        notifier.status("xyzzy thud").expect("status");
+        notifier.watchdog().expect("watchdog");
+        assert_eq!(manager.receive().0, "STATUS=xyzzy thud");
+        assert_eq!(manager.receive().0, "WATCHDOG=1");
+    }
+
+    #[test]
+    fn fd_store() {
+        let manager = Manager::new();
+        let notifier = manager.notifier();
+        let fd_factory = memfd::MemfdOptions::new().close_on_exec(true);
+        let file = fd_factory.create("test").expect("memfd_create").into_file();
+        let want_metadata = file.metadata().expect("memfd metadata");
+        notifier
+            .fd_store("xyzzy", &[file.as_raw_fd()])
+            .expect("fd_store");
+        let (state, files) = manager.receive();
+        assert_eq!(state, "FDSTORE=1\nFDNAME=xyzzy");
+        assert_eq!(files.len(), 1);
+        let got_metadata = files[0].metadata().expect("received fd metadata");
+        assert_eq!(
+            (want_metadata.dev(), want_metadata.ino()),
+            (got_metadata.dev(), got_metadata.ino()),
+        );
+
+        notifier.fd_store_remove("xyzzy").expect("fd_store_remove");
+        let (state, files) = manager.receive();
+        assert_eq!(state, "FDSTOREREMOVE=1\nFDNAME=xyzzy");
+        assert!(files.is_empty());
+    }
+
+    #[test]
+    fn not_set() {
+        let notifier = Notifier::test("TEST_SD_NOTIFY_UNSET_").expect("Notifier::test");
+        assert!(!notifier.is_enabled());
+        // Silently discarded.
+        notifier.ready().expect("ready");
+    }
+
+    #[test]
+    fn watchdog_interval() {
+        let usec = || Some(OsString::from("30000000"));
+        let want = Some(std::time::Duration::from_secs(30));
+        assert_eq!(super::parse_watchdog(usec(), None), want);
+        let pid = std::process::id().to_string();
+        assert_eq!(super::parse_watchdog(usec(), Some(pid.into())), want);
+        // Meant for someone else.
+        let other = (std::process::id() + 1).to_string();
+        assert_eq!(super::parse_watchdog(usec(), Some(other.into())), None);
+        assert_eq!(super::parse_watchdog(None, None), None);
+        assert_eq!(super::parse_watchdog(Some("0".into()), None), None);
+        assert_eq!(super::parse_watchdog(Some("xyzzy".into()), None), None);
+    }
+
+    #[test]
+    fn relative_path() {
+        let result = Notifier::from_address(Some(OsString::from("notify.sock")));
+        assert!(matches!(result, Err(Error::InvalidAddress(_))));
+    }
+}
@@ -0,0 +1,778 @@
+//! Fan out PTY output to multiple clients.
+//!
+//! Every client has a bounded queue of output waiting to be sent.
+//! What happens when a queue is full is decided by [SlowConsumerPolicy].
+//!
+//! All output also goes through a terminal emulator, so new clients, and clients resyncing after falling behind, can be shown the current [Screen].
+//! Before that, they get recent output from a [Scrollback] buffer, for context beyond the visible screen.
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::{Condvar, Mutex};
+
+use crate::proto::pty::user::MAX_OUTPUT_LEN;
+use crate::proto::pty::{Options, SlowConsumerPolicy};
+use crate::proto::ExitStatus;
+use crate::pty_master::WindowSize;
+
+use super::screen::Screen;
+use super::scrollback::Scrollback;
+
+pub(super) type ClientId = u64;
+
+/// A chunk of PTY output, shared between all clients.
+pub(super) type Chunk = Arc<[u8]>;
+
+/// Something to send to a client.
+#[derive(Debug)]
+pub(super) enum Item {
+    Output(Chunk),
+    /// The slow consumer policy was applied to this client.
+    SlowConsumer(SlowConsumerPolicy),
+    /// The session is over; always the last item.
+    SessionEnded(Option<ExitStatus>),
+}
+
+struct QueueState {
+    items: VecDeque<Item>,
+    /// Size of the output in `items`, not counting the first `primed` items.
+    bytes: usize,
+    /// Number of items at the front of the queue added by [Queue::prime].
+    primed: usize,
+    /// No more items will be added.
+    closed: bool,
+    /// Nobody is consuming items anymore.
+    detached: bool,
+    /// Output was dropped, and the client hasn't been resynced yet.
+    lagging: bool,
+    /// Output up to this sequence number is already included in the snapshot sent to resync the client.
+    resynced_at: u64,
+    /// The PTY is being held back for this client, and the client has been told.
+    throttled: bool,
+}
+
+struct Queue {
+    max_bytes: usize,
+    policy: SlowConsumerPolicy,
+    state: Mutex<QueueState>,
+    changed: Condvar,
+}
+
+impl Queue {
+    fn new(max_bytes: usize, policy: SlowConsumerPolicy) -> Self {
+        Self {
+            max_bytes,
+            policy,
+            state: Mutex::new(QueueState {
+                items: VecDeque::new(),
+                bytes: 0,
+                primed: 0,
+                closed: false,
+                detached: false,
+                lagging: false,
+                resynced_at: 0,
+                throttled: false,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    fn fits(&self, state: &QueueState, len: usize) -> bool {
+        // Always accept something into an empty queue, or a big enough chunk would never go through.
+        state.items.is_empty() || state.bytes + len <= self.max_bytes
+    }
+
+    /// Add output for a client that has just subscribed, split into chunks that each fit in a message.
+    ///
+    /// The client can't have fallen behind on output it has never been sent, so this is not counted against the limit.
+    /// Otherwise, a replay larger than the limit would have the slow consumer policy applied to the first live output.
+    fn prime(&self, data: &[u8]) {
+        let mut guard = self.state.lock().expect("internal: queue mutex poison");
+        for part in data.chunks(MAX_OUTPUT_LEN) {
+            guard.items.push_back(Item::Output(Chunk::from(part)));
+            guard.primed += 1;
+        }
+        self.changed.notify_all();
+    }
+
+    /// Add output to the queue, applying the slow consumer policy as needed.
+    ///
+    /// `seq` is the sequence number of the chunk, see [State::fed].
+    /// Returns `false` if this client should be forgotten.
+    fn push(&self, chunk: &Chunk, seq: u64) -> bool {
+        let mut guard = self.state.lock().expect("internal: queue mutex poison");
+        loop {
+            if guard.closed || guard.detached {
+                return false;
+            }
+            if guard.lagging || seq <= guard.resynced_at {
+                // The client will get this as part of the snapshot it is resynced with.
+                return true;
+            }
+            if self.fits(&guard, chunk.len()) {
+                break;
+            }
+            match self.policy {
+                SlowConsumerPolicy::Disconnect => {
+                    guard.items.push_back(Item::SlowConsumer(self.policy));
+                    guard.closed = true;
+                    self.changed.notify_all();
+                    return false;
+                }
+                SlowConsumerPolicy::Resync => {
+                    guard.lagging = true;
+                    return true;
+                }
+                SlowConsumerPolicy::Backpressure => {
+                    if !guard.throttled {
+                        // Tell the client, even though that goes over the limit.
+                        guard.items.push_back(Item::SlowConsumer(self.policy));
+                        guard.throttled = true;
+                        self.changed.notify_all();
+                    }
+                    guard = self
+                        .changed
+                        .wait(guard)
+                        .expect("internal: queue mutex poison");
+                }
+            }
+        }
+        guard.throttled = false;
+        guard.items.push_back(Item::Output(chunk.clone()));
+        guard.bytes += chunk.len();
+        self.changed.notify_all();
+        true
+    }
+
+    /// Has the client caught up after missing output.
+    fn needs_resync(&self) -> bool {
+        let guard = self.state.lock().expect("internal: queue mutex poison");
+        guard.lagging && guard.items.is_empty() && !guard.closed && !guard.detached
+    }
+
+    /// Tell the client it has missed output, and send it `snapshot`, which includes output up to sequence number `fed`.
+    fn resync(&self, snapshot: &[u8], fed: u64) {
+        {
+            let mut guard = self.state.lock().expect("internal: queue mutex poison");
+            if !guard.lagging || !guard.items.is_empty() || guard.closed || guard.detached {
+                return;
+            }
+            guard.lagging = false;
+            guard.resynced_at = fed;
+            guard.items.push_back(Item::SlowConsumer(self.policy));
+            guard.primed += 1;
+        }
+        self.prime(snapshot);
+    }
+
+    fn take(&self, state: &mut QueueState) -> Option<Item> {
+        let item = state.items.pop_front()?;
+        if state.primed > 0 {
+            state.primed -= 1;
+        } else if let Item::Output(chunk) = &item {
+            state.bytes -= chunk.len();
+        }
+        // Wake up a PTY reader waiting for room.
+        self.changed.notify_all();
+        Some(item)
+    }
+
+    /// Wait for the next item.
+    /// Returns `None` once the queue is closed and everything in it has been consumed.
+    fn pop(&self) -> Option<Item> {
+        let mut guard = self.state.lock().expect("internal: queue mutex poison");
+        loop {
+            if let Some(item) = self.take(&mut guard) {
+                return Some(item);
+            }
+            if guard.closed {
+                return None;
+            }
+            guard = self
+                .changed
+                .wait(guard)
+                .expect("internal: queue mutex poison");
+        }
+    }
+
+    fn close(&self) {
+        let mut guard = self.state.lock().expect("internal: queue mutex poison");
+        guard.closed = true;
+        self.changed.notify_all();
+    }
+
+    /// Like `close`, but tell the client why, regardless of room in the queue.
+    fn end(&self, exit_status: Option<ExitStatus>) {
+        let mut guard = self.state.lock().expect("internal: queue mutex poison");
+        if !guard.closed && !guard.detached {
+            guard.items.push_back(Item::SessionEnded(exit_status));
+        }
+        guard.closed = true;
+        self.changed.notify_all();
+    }
+
+    fn detach(&self) {
+        let mut guard = self.state.lock().expect("internal: queue mutex poison");
+        guard.detached = true;
+        guard.items.clear();
+        guard.bytes = 0;
+        guard.primed = 0;
+        self.changed.notify_all();
+    }
+}
+
+struct State {
+    next_id: ClientId,
+    /// Number of chunks fed to `screen` so far, used as sequence numbers for output.
+    fed: u64,
+    clients: HashMap<ClientId, Arc<Queue>>,
+    scrollback: Scrollback,
+    screen: Screen,
+    closed: bool,
+}
+
+/// Broadcast hub for PTY output.
+///
+/// Every chunk given to [Hub::broadcast] is delivered to every client subscribed at that time, in order, subject to the slow consumer policy.
+/// Clients subscribing later get the scrollback and a snapshot of the screen first.
+pub(super) struct Hub {
+    max_queued_bytes: usize,
+    slow_consumer: SlowConsumerPolicy,
+    /// Shared with [Subscription]s, for resyncing.
+    state: Arc<Mutex<State>>,
+}
+
+impl Hub {
+    pub(super) fn new(options: &Options, size: WindowSize) -> Self {
+        Self {
+            max_queued_bytes: options.max_queued_bytes as usize,
+            slow_consumer: options.slow_consumer,
+            state: Arc::new(Mutex::new(State {
+                next_id: 0,
+                fed: 0,
+                clients: HashMap::new(),
+                scrollback: Scrollback::new(options.scrollback_bytes as usize),
+                screen: Screen::new(size),
+                closed: false,
+            })),
+        }
+    }
+
+    /// Start receiving output, starting with a replay of the scrollback and a snapshot of the screen.
+    ///
+    /// Returns `None` if the hub has already been closed.
+    pub(super) fn subscribe(&self) -> Option<Subscription> {
+        let mut guard = self.state.lock().expect("internal: hub mutex poison");
+        if guard.closed {
+            return None;
+        }
+        let id = guard.next_id;
+        guard.next_id += 1;
+        let queue = Arc::new(Queue::new(self.max_queued_bytes, self.slow_consumer));
+        // Holding the lock guarantees nothing is broadcast between taking the snapshot and joining the clients, so output is neither lost or duplicated.
+        queue.prime(&guard.scrollback.contents());
+        // The snapshot redraws the screen over whatever the replay left there.
+        queue.prime(&guard.screen.snapshot());
+        guard.clients.insert(id, queue.clone());
+        Some(Subscription {
+            id,
+            queue,
+            hub: self.state.clone(),
+        })
+    }
+
+    /// Stop sending output to a client.
+    /// The client's [Subscription] sees end of output once it has consumed everything already queued.
+    pub(super) fn unsubscribe(&self, id: ClientId) {
+        let mut guard = self.state.lock().expect("internal: hub mutex poison");
+        if let Some(queue) = guard.clients.remove(&id) {
+            queue.close();
+        }
+    }
+
+    /// Number of clients currently subscribed.
+    pub(super) fn len(&self) -> usize {
+        let guard = self.state.lock().expect("internal: hub mutex poison");
+        guard.clients.len()
+    }
+
+    /// Send a chunk of output to all clients.
+    ///
+    /// With [SlowConsumerPolicy::Backpressure], this blocks until every client has room for it.
+    pub(super) fn broadcast(&self, data: &[u8]) {
+        let chunk: Chunk = Arc::from(data);
+        // Don't hold the lock while pushing, that could block for a long time.
+        let (seq, clients): (u64, Vec<(ClientId, Arc<Queue>)>) = {
+            let mut guard = self.state.lock().expect("internal: hub mutex poison");
+            guard.scrollback.push(data);
+            guard.screen.feed(data);
+            guard.fed += 1;
+            let clients = guard
+                .clients
+                .iter()
+                .map(|(id, queue)| (*id, queue.clone()))
+                .collect();
+            (guard.fed, clients)
+        };
+        let gone: Vec<ClientId> = clients
+            .iter()
+            .filter(|(_id, queue)| !queue.push(&chunk, seq))
+            .map(|(id, _queue)| *id)
+            .collect();
+        if !gone.is_empty() {
+            let mut guard = self.state.lock().expect("internal: hub mutex poison");
+            for id in gone {
+                guard.clients.remove(&id);
+            }
+        }
+    }
+
+    /// Has the application asked for pasted text to be bracketed.
+    pub(super) fn bracketed_paste(&self) -> bool {
+        let guard = self.state.lock().expect("internal: hub mutex poison");
+        guard.screen.bracketed_paste()
+    }
+
+    /// The terminal window size changed.
+    pub(super) fn resize(&self, size: WindowSize) {
+        let mut guard = self.state.lock().expect("internal: hub mutex poison");
+        guard.screen.resize(size);
+    }
+
+    /// The session is over, no more output is coming.
+    /// Current clients are told how the session ended once they have consumed everything already queued, and then see end of output.
+    /// New subscriptions are refused.
+    pub(super) fn end(&self, exit_status: Option<ExitStatus>) {
+        let mut guard = self.state.lock().expect("internal: hub mutex poison");
+        guard.closed = true;
+        for (_id, queue) in guard.clients.drain() {
+            queue.end(exit_status);
+        }
+    }
+}
+
+/// Output for one client.
+pub(super) struct Subscription {
+    id: ClientId,
+    queue: Arc<Queue>,
+    hub: Arc<Mutex<State>>,
+}
+
+impl Subscription {
+    pub(super) fn id(&self) -> ClientId {
+        self.id
+    }
+
+    /// With [SlowConsumerPolicy::Resync], once the client has caught up after missing output, queue a snapshot of the screen to redraw from.
+    fn resync_if_caught_up(&self) {
+        if !self.queue.needs_resync() {
+            return;
+        }
+        // Holding the lock keeps more output from being fed to the screen, so the snapshot is of output up to `fed`.
+        let guard = self.hub.lock().expect("internal: hub mutex poison");
+        self.queue.resync(&guard.screen.snapshot(), guard.fed);
+    }
+
+    /// Wait for the next thing to send.
+    /// Returns `None` at end of output.
+    pub(super) fn next(&self) -> Option<Item> {
+        self.resync_if_caught_up();
+        self.queue.pop()
+    }
+
+    /// The next thing to send, if there is one already.
+    pub(super) fn try_next(&self) -> Option<Item> {
+        self.resync_if_caught_up();
+        let mut guard = self
+            .queue
+            .state
+            .lock()
+            .expect("internal: queue mutex poison");
+        self.queue.take(&mut guard)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        // Don't let a PTY reader wait for us.
+        self.queue.detach();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Hub, Item, Subscription};
+    use crate::proto::pty::{Options, SlowConsumerPolicy};
+    use crate::proto::ExitStatus;
+    use crate::pty_master::WindowSize;
+
+    fn hub(max_queued_bytes: u32, slow_consumer: SlowConsumerPolicy) -> Hub {
+        let options = Options {
+            max_queued_bytes,
+            slow_consumer,
+            scrollback_bytes: 0,
+        };
+        Hub::new(
+            &options,
+            WindowSize {
+                rows: 24,
+                columns: 80,
+            },
+        )
+    }
+
+    /// Subscribe, and skip the initial screen snapshot.
+    fn subscribe(hub: &Hub) -> Subscription {
+        let subscription = hub.subscribe().expect("subscribe");
+        match subscription.next() {
+            Some(Item::Output(_)) => (),
+            other => panic!("expected snapshot, got {:?}", other),
+        }
+        subscription
+    }
+
+    fn expect_output(subscription: &Subscription, want: &[u8]) {
+        match subscription.next() {
+            Some(Item::Output(chunk)) => assert_eq!(&*chunk, want),
+            other => panic!("expected output, got {:?}", other),
+        }
+    }
+
+    /// Expect the end of the session, and then nothing more.
+    fn expect_end(subscription: &Subscription, want: Option<ExitStatus>) {
+        match subscription.next() {
+            Some(Item::SessionEnded(status)) => assert_eq!(status, want),
+            other => panic!("expected end of session, got {:?}", other),
+        }
+        assert!(subscription.next().is_none());
+    }
+
+    fn wait_until_throttled(subscription: &Subscription) {
+        loop {
+            {
+                let guard = subscription.queue.state.lock().unwrap();
+                if guard.throttled {
+                    return;
+                }
+            }
+            std::thread::yield_now();
+        }
+    }
+
+    fn expect_slow_consumer(subscription: &Subscription, want: SlowConsumerPolicy) {
+        match subscription.next() {
+            Some(Item::SlowConsumer(policy)) => assert_eq!(policy, want),
+            other => panic!("expected slow consumer notice, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn broadcast() {
+        let hub = hub(1024, SlowConsumerPolicy::Disconnect);
+        let one = subscribe(&hub);
+        let two = subscribe(&hub);
+        assert_ne!(one.id(), two.id());
+        assert_eq!(hub.len(), 2);
+        hub.broadcast(b"hello");
+        hub.broadcast(b"world");
+        expect_output(&one, b"hello");
+        expect_output(&one, b"world");
+        expect_output(&two, b"hello");
+        expect_output(&two, b"world");
+    }
+
+    #[test]
+    fn late_subscriber() {
+        let hub = hub(1024, SlowConsumerPolicy::Disconnect);
+        let one = subscribe(&hub);
+        hub.broadcast(b"early");
+        let two = subscribe(&hub);
+        hub.broadcast(b"late");
+        hub.end(None);
+        expect_output(&one, b"early");
+        expect_output(&one, b"late");
+        expect_end(&one, None);
+        expect_output(&two, b"late");
+        expect_end(&two, None);
+    }
+
+    #[test]
+    fn unsubscribe() {
+        let hub = hub(1024, SlowConsumerPolicy::Disconnect);
+        let one = subscribe(&hub);
+        let two = subscribe(&hub);
+        hub.broadcast(b"both");
+        hub.unsubscribe(one.id());
+        assert_eq!(hub.len(), 1);
+        hub.broadcast(b"just two");
+        expect_output(&one, b"both");
+        assert!(one.next().is_none());
+        expect_output(&two, b"both");
+        expect_output(&two, b"just two");
+    }
+
+    #[test]
+    fn dropped_subscription() {
+        let hub = hub(1024, SlowConsumerPolicy::Disconnect);
+        let one = subscribe(&hub);
+        drop(one);
+        hub.broadcast(b"nobody listening");
+        assert_eq!(hub.len(), 0);
+    }
+
+    #[test]
+    fn end() {
+        let hub = hub(4, SlowConsumerPolicy::Resync);
+        let one = subscribe(&hub);
+        hub.broadcast(b"last");
+        hub.end(Some(ExitStatus::Exited(0)));
+        expect_output(&one, b"last");
+        // Even though the queue is full.
+        expect_end(&one, Some(ExitStatus::Exited(0)));
+        assert!(hub.subscribe().is_none());
+    }
+
+    #[test]
+    fn subscribe_after_close() {
+        let hub = hub(1024, SlowConsumerPolicy::Disconnect);
+        hub.end(None);
+        assert!(hub.subscribe().is_none());
+    }
+
+    #[test]
+    fn slow_consumer_disconnect() {
+        let hub = hub(10, SlowConsumerPolicy::Disconnect);
+        let slow = subscribe(&hub);
+        let fast = subscribe(&hub);
+        hub.broadcast(b"12345678");
+        expect_output(&fast, b"12345678");
+        hub.broadcast(b"abcdefgh");
+        expect_output(&fast, b"abcdefgh");
+        assert_eq!(hub.len(), 1);
+
+        expect_output(&slow, b"12345678");
+        expect_slow_consumer(&slow, SlowConsumerPolicy::Disconnect);
+        assert!(slow.next().is_none());
+    }
+
+    #[test]
+    fn slow_consumer_resync() {
+        let hub = hub(10, SlowConsumerPolicy::Resync);
+        let slow = subscribe(&hub);
+        hub.broadcast(b"12345678");
+        hub.broadcast(b"lost");
+        hub.broadcast(b"also lost");
+        expect_output(&slow, b"12345678");
+        // Still catching up.
+        hub.broadcast(b" and more");
+        expect_slow_consumer(&slow, SlowConsumerPolicy::Resync);
+        match slow.next() {
+            Some(Item::Output(chunk)) => {
+                let snapshot = String::from_utf8_lossy(&chunk);
+                assert!(snapshot.contains("12345678lostalso lost and more"));
+            }
+            other => panic!("expected snapshot, got {:?}", other),
+        }
+        hub.broadcast(b"caught up");
+        expect_output(&slow, b"caught up");
+        assert_eq!(hub.len(), 1);
+    }
+
+    #[test]
+    fn slow_consumer_resync_no_duplicates() {
+        let hub = hub(10, SlowConsumerPolicy::Resync);
+        let slow = subscribe(&hub);
+        hub.broadcast(b"12345678");
+        hub.broadcast(b"lost");
+        expect_output(&slow, b"12345678");
+        expect_slow_consumer(&slow, SlowConsumerPolicy::Resync);
+        // As if this was broadcast before the snapshot was taken, but pushed after.
+        let seq = hub.state.lock().unwrap().fed;
+        assert!(slow.queue.push(&super::Chunk::from(&b"lost"[..]), seq));
+        hub.broadcast(b"new");
+        match slow.next() {
+            Some(Item::Output(_)) => (),
+            other => panic!("expected snapshot, got {:?}", other),
+        }
+        expect_output(&slow, b"new");
+    }
+
+    #[test]
+    fn slow_consumer_backpressure() {
+        let hub = hub(10, SlowConsumerPolicy::Backpressure);
+        let slow = subscribe(&hub);
+        hub.broadcast(b"12345678");
+        let writer = std::thread::spawn(move || {
+            // Blocks until there's room.
+            hub.broadcast(b"abcdefgh");
+            hub.end(None);
+        });
+        wait_until_throttled(&slow);
+        expect_output(&slow, b"12345678");
+        expect_slow_consumer(&slow, SlowConsumerPolicy::Backpressure);
+        expect_output(&slow, b"abcdefgh");
+        expect_end(&slow, None);
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn slow_consumer_backpressure_detach() {
+        let hub = std::sync::Arc::new(hub(10, SlowConsumerPolicy::Backpressure));
+        let slow = subscribe(&hub);
+        hub.broadcast(b"12345678");
+        let writer = std::thread::spawn({
+            let hub = hub.clone();
+            move || {
+                hub.broadcast(b"abcdefgh");
+            }
+        });
+        wait_until_throttled(&slow);
+        // A client going away must release the PTY.
+        drop(slow);
+        writer.join().unwrap();
+        assert_eq!(hub.len(), 0);
+    }
+
+    #[test]
+    fn snapshot() {
+        let hub = hub(1024, SlowConsumerPolicy::Disconnect);
+        hub.broadcast(b"before anyone\r\n");
+        hub.broadcast(b"was listening");
+        let one = hub.subscribe().expect("subscribe");
+        hub.broadcast(b"live");
+        match one.next() {
+            Some(Item::Output(chunk)) => {
+                let snapshot = String::from_utf8_lossy(&chunk);
+                assert!(snapshot.contains("\x1b[1Hbefore anyone"));
+                assert!(snapshot.contains("\x1b[2Hwas listening"));
+                // Cursor is left at the end of the output.
+                assert!(snapshot.ends_with("\x1b[2;14H"));
+            }
+            other => panic!("expected snapshot, got {:?}", other),
+        }
+        expect_output(&one, b"live");
+    }
+
+    #[test]
+    fn large_snapshot() {
+        use crate::ipc::Message;
+        use crate::proto::pty::user as p;
+        use crate::proto::pty::user::MAX_OUTPUT_LEN;
+        use bincode::Options as _;
+        use std::io::Write;
+
+        let hub = Hub::new(
+            &Options {
+                max_queued_bytes: 1024,
+                slow_consumer: SlowConsumerPolicy::Disconnect,
+                scrollback_bytes: 0,
+            },
+            WindowSize {
+                rows: 60,
+                columns: 200,
+            },
+        );
+        hub.broadcast(b"\x1b[?1049h");
+        // Different attributes in every cell, so the snapshot has to repeat them all.
+        for row in 0..60 {
+            let mut line = Vec::new();
+            for col in 0..200 {
+                write!(
+                    line,
+                    "\x1b[1;4;38;5;{};48;2;{};{};255mx",
+                    (row + col) % 256,
+                    row,
+                    col
+                )
+                .unwrap();
+            }
+            hub.broadcast(&line);
+        }
+        let want = hub.state.lock().unwrap().screen.snapshot();
+        assert!(want.len() > 8 * 1024);
+
+        let one = hub.subscribe().expect("subscribe");
+        hub.end(None);
+        let mut snapshot = Vec::new();
+        while let Some(item) = one.next() {
+            match item {
+                Item::Output(chunk) => {
+                    assert!(chunk.len() <= MAX_OUTPUT_LEN);
+                    let message = p::Output::SessionOutput(chunk.to_vec());
+                    let encoded = bincode::DefaultOptions::new()
+                        .serialize(&message)
+                        .expect("serialize");
+                    assert!(encoded.len() <= <p::Output as Message>::MAX_SIZE);
+                    snapshot.extend_from_slice(&chunk);
+                }
+                Item::SessionEnded(None) => (),
+                other => panic!("expected snapshot, got {:?}", other),
+            }
+        }
+        assert_eq!(snapshot, want);
+    }
+
+    #[test]
+    fn replay_over_limit() {
+        let options = Options {
+            max_queued_bytes: 10,
+            slow_consumer: SlowConsumerPolicy::Disconnect,
+            scrollback_bytes: 1024,
+        };
+        let hub = Hub::new(
+            &options,
+            WindowSize {
+                rows: 24,
+                columns: 80,
+            },
+        );
+        let full = [b'x'; 1024];
+        hub.broadcast(&full);
+        let one = hub.subscribe().expect("subscribe");
+        // The replay alone is over the limit, but doesn't count against it.
+        hub.broadcast(b"live");
+        assert_eq!(hub.len(), 1);
+        // Live output still is.
+        hub.broadcast(b"more live output");
+        assert_eq!(hub.len(), 0);
+
+        expect_output(&one, &full);
+        match one.next() {
+            Some(Item::Output(_)) => (),
+            other => panic!("expected snapshot, got {:?}", other),
+        }
+        expect_output(&one, b"live");
+        expect_slow_consumer(&one, SlowConsumerPolicy::Disconnect);
+        assert!(one.next().is_none());
+    }
+
+    #[test]
+    fn replay_scrollback() {
+        let options = Options {
+            max_queued_bytes: 1024,
+            slow_consumer: SlowConsumerPolicy::Disconnect,
+            scrollback_bytes: 1024,
+        };
+        let hub = Hub::new(
+            &options,
+            WindowSize {
+                rows: 24,
+                columns: 80,
+            },
+        );
+        hub.broadcast(b"before anyone ");
+        hub.broadcast(b"was listening\r\n");
+        let one = hub.subscribe().expect("subscribe");
+        hub.broadcast(b"live");
+        expect_output(&one, b"before anyone was listening\r\n");
+        match one.next() {
+            Some(Item::Output(chunk)) => {
+                let snapshot = String::from_utf8_lossy(&chunk);
+                assert!(snapshot.contains("\x1b[1Hbefore anyone was listening"));
+            }
+            other => panic!("expected snapshot, got {:?}", other),
+        }
+        expect_output(&one, b"live");
+    }
+}
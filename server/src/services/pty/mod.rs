@@ -1,15 +1,27 @@
 use std::convert::TryFrom;
-use std::os::unix::io::FromRawFd;
-use std::os::unix::net::UnixDatagram;
+use std::io::{Read, Write};
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::os::unix::net::{UnixDatagram, UnixStream};
+use std::sync::mpsc;
 use std::sync::Arc;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
 use thiserror::Error;
 
 use crate::ipc;
 use crate::ipc::handshake;
 use crate::ipc::seqpacket;
 use crate::ipc::seqpacket::SeqPacket;
+use crate::ipc::stream::Stream;
+use crate::proto;
 use crate::proto::pty as p;
+use crate::proto::ExitStatus;
+use crate::pty_master::{PtyMaster, WindowSize};
 
+mod broadcast;
+mod paste;
+mod screen;
+mod scrollback;
 mod user;
 
 #[derive(Error, Debug)]
@@ -40,35 +52,176 @@ pub enum Error {
     NonBlockingPty(#[source] std::io::Error),
 }
 
-pub fn serve(conn: impl ipc::IPC) -> Result<(), Error> {
+/// How long to wait for the session manager to tell us the exit status, once the PTY is closed.
+const EXIT_STATUS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// State shared between everything serving one PTY.
+struct Session {
+    pty: PtyMaster,
+    /// Writes from different clients must not be interleaved with each other.
+    input_lock: Mutex<()>,
+    output: broadcast::Hub,
+    /// Events to send to the session manager.
+    events: Mutex<mpsc::Sender<p::Event>>,
+    /// Set once the session manager has told us how the session ended, or won't.
+    exit_status: Mutex<Option<Option<ExitStatus>>>,
+    exit_status_set: Condvar,
+}
+
+impl Session {
+    fn write_input(&self, input: &[u8]) -> Result<(), std::io::Error> {
+        let _guard = self
+            .input_lock
+            .lock()
+            .expect("internal: input mutex poison");
+        (&self.pty).write_all(input)
+    }
+
+    fn set_window_size(&self, size: WindowSize) -> Result<(), std::io::Error> {
+        self.pty.set_window_size(size)?;
+        self.output.resize(size);
+        Ok(())
+    }
+
+    fn report(&self, event: p::Event) {
+        let events = self.events.lock().expect("internal: events mutex poison");
+        // The receiving end only goes away when the session manager does, and then nobody is listening anyway.
+        let _ = events.send(event);
+    }
+
+    fn set_exit_status(&self, exit_status: Option<ExitStatus>) {
+        let mut guard = self
+            .exit_status
+            .lock()
+            .expect("internal: exit status mutex poison");
+        guard.get_or_insert(exit_status);
+        self.exit_status_set.notify_all();
+    }
+
+    fn wait_exit_status(&self) -> Option<ExitStatus> {
+        let guard = self
+            .exit_status
+            .lock()
+            .expect("internal: exit status mutex poison");
+        let (guard, _timeout) = self
+            .exit_status_set
+            .wait_timeout_while(guard, EXIT_STATUS_TIMEOUT, |exit_status| {
+                exit_status.is_none()
+            })
+            .expect("internal: exit status mutex poison");
+        guard.flatten()
+    }
+
+    /// Tell the session manager how many clients are attached.
+    fn report_clients(&self) {
+        // Count while holding the lock, so reports can't be reordered.
+        let events = self.events.lock().expect("internal: events mutex poison");
+        let count = self.output.len() as u32;
+        let _ = events.send(p::Event::Clients { count });
+    }
+}
+
+/// Read PTY output and broadcast it to all clients, until the PTY is closed.
+fn read_pty(session: &Session) -> Result<(), std::io::Error> {
+    let mut buf = vec![0; 1024];
+    loop {
+        let n = match (&session.pty).read(&mut buf) {
+            // All PTY interactions end at EIO, there's no EOF.
+            Err(error) if error.raw_os_error() == Some(libc::EIO) => return Ok(()),
+            Err(error) => return Err(error),
+            Ok(n) => n,
+        };
+        session.output.broadcast(&buf[..n]);
+    }
+}
+
+pub fn serve(conn: impl ipc::IPC + Send + Sync + 'static) -> Result<(), Error> {
     handshake::handshake_as_server(&conn, p::CLIENT_INTENT, p::SERVER_INTENT)
         .map_err(Error::Handshake)?;
 
-    let pty = {
+    let (pty, options) = {
         let msg: p::Init = conn.receive_with_fds().map_err(Error::Receive)?;
-        msg.pty_master
+        (msg.pty_master, msg.options)
+    };
+    let size = match pty.window_size() {
+        Ok(size) => size,
+        Err(error) => {
+            println!("cannot get PTY window size, assuming default: {}", error);
+            screen::DEFAULT_SIZE
+        }
     };
-    // Kludging this via Arc because we need to pass it to multiple tasks.
-    // Proper broadcast mechanism, coming later, will deal with this better.
-    let pty = Arc::new(pty);
+    let (events, events_receiver) = mpsc::channel();
+    let session = Arc::new(Session {
+        pty,
+        input_lock: Mutex::new(()),
+        output: broadcast::Hub::new(&options, size),
+        events: Mutex::new(events),
+        exit_status: Mutex::new(None),
+        exit_status_set: Condvar::new(),
+    });
+    // Once the session manager is gone, nobody is going to tell us the exit status.
+    let _no_exit_status =
+        scopeguard::guard(session.clone(), |session| session.set_exit_status(None));
+
+    let conn = Arc::new(conn);
+    std::thread::spawn({
+        let conn = conn.clone();
+        move || {
+            for event in events_receiver {
+                if let Err(error) = conn.send_with_fds(&event) {
+                    println!("error sending event: {}", error);
+                    break;
+                }
+            }
+        }
+    });
+
+    // A single reader for the PTY, so every client sees all of the output.
+    std::thread::spawn({
+        let session = session.clone();
+        move || {
+            let result = read_pty(&session);
+            println!("pty closed: {:?}", result);
+            session.report(p::Event::Closed);
+            let exit_status = session.wait_exit_status();
+            // Clients attaching from now on are told the same, even if the real exit status shows up late.
+            session.set_exit_status(exit_status);
+            // Disconnects all clients.
+            session.output.end(exit_status);
+        }
+    });
 
-    // TODO broadcast mechanism between PTY and multiple clients.
-    // For now, we absolutely mishandle multiple clients, smearing data over them unpredictably.
     loop {
         let msg: p::Request = conn.receive_with_fds().map_err(Error::Receive)?;
         match msg {
-            p::Request::NewClient { _dummy: _, fd } => {
-                let conn = SeqPacket::try_from(fd).unwrap();
-                let pty = pty.clone();
-                std::thread::spawn(move || {
-                    let r = self::user::serve_user(pty, conn);
-                    println!("serve_user exited: {:?}", r);
-                    r
-                });
+            p::Request::NewClient { fd } => {
+                // The client picks the transport.
+                // A bad FD only loses that one client, so log it and keep serving the session.
+                match ipc::socket_type(&fd) {
+                    Ok(libc::SOCK_STREAM) => {
+                        let socket = unsafe { UnixStream::from_raw_fd(fd.into_raw_fd()) };
+                        let conn = Stream::new(socket, proto::pty::user::stream_limits());
+                        spawn_user(session.clone(), conn);
+                    }
+                    Ok(_) => match SeqPacket::try_from(fd) {
+                        Ok(conn) => spawn_user(session.clone(), conn),
+                        Err(error) => println!("dropping client FD: {}", error),
+                    },
+                    Err(error) => println!("dropping client FD: {}", error),
+                }
             }
+            p::Request::SessionEnded { exit_status } => session.set_exit_status(exit_status),
         }
     }
 }
 
+fn spawn_user(session: Arc<Session>, conn: impl ipc::IPC + Send + Sync + 'static) {
+    std::thread::spawn(move || {
+        let r = self::user::serve_user(session, conn);
+        println!("serve_user exited: {:?}", r);
+        r
+    });
+}
+
 #[cfg(test)]
-mod tests;
+pub(crate) mod tests;
@@ -0,0 +1,85 @@
+//! Bracketed paste support.
+//!
+//! Applications opt in to bracketed paste with `CSI ? 2004 h`, after which pasted text is surrounded by `CSI 200 ~` and `CSI 201 ~`.
+//! That lets e.g. shells avoid executing every line of a multi-line paste as it arrives.
+//! It only helps if the pasted text itself can't end the paste early, so we strip any markers from the pasted content.
+//! Whether the application has opted in is tracked by the terminal emulator, see [Screen::bracketed_paste](super::screen::Screen::bracketed_paste).
+//!
+//! <https://invisible-island.net/xterm/ctlseqs/ctlseqs.html#h2-Bracketed-Paste-Mode>
+
+const PASTE_START: &[u8] = b"\x1b[200~";
+const PASTE_END: &[u8] = b"\x1b[201~";
+
+/// Remove all paste start and end markers from `input`.
+///
+/// Removing a marker can join its surroundings into a new marker.
+/// Any marker in the output must end at the byte just added, so checking the end of the output after every byte catches those too, in a single pass.
+fn sanitize(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len());
+    for &b in input {
+        out.push(b);
+        for marker in &[PASTE_START, PASTE_END] {
+            if out.ends_with(marker) {
+                out.truncate(out.len() - marker.len());
+            }
+        }
+    }
+    out
+}
+
+/// Prepare pasted text for writing to the PTY.
+///
+/// Embedded paste markers are always removed.
+/// If the application has enabled bracketed paste mode, the result is wrapped in paste markers.
+pub(super) fn prepare(input: &[u8], bracketed: bool) -> Vec<u8> {
+    let sanitized = sanitize(input);
+    if !bracketed {
+        return sanitized;
+    }
+    let mut out = Vec::with_capacity(PASTE_START.len() + sanitized.len() + PASTE_END.len());
+    out.extend_from_slice(PASTE_START);
+    out.extend_from_slice(&sanitized);
+    out.extend_from_slice(PASTE_END);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prepare_not_bracketed() {
+        assert_eq!(prepare(b"echo hi\n", false), b"echo hi\n");
+    }
+
+    #[test]
+    fn prepare_bracketed() {
+        assert_eq!(prepare(b"echo hi\n", true), b"\x1b[200~echo hi\n\x1b[201~");
+    }
+
+    #[test]
+    fn prepare_strips_markers() {
+        assert_eq!(
+            prepare(b"foo\x1b[201~rm -rf ~\n\x1b[200~bar", true),
+            b"\x1b[200~foorm -rf ~\nbar\x1b[201~"
+        );
+        assert_eq!(prepare(b"foo\x1b[201~bar", false), b"foobar");
+    }
+
+    #[test]
+    fn prepare_strips_nested_markers() {
+        assert_eq!(
+            prepare(b"\x1b[20\x1b[201~1~evil\n", true),
+            b"\x1b[200~evil\n\x1b[201~"
+        );
+    }
+
+    #[test]
+    fn sanitize_only_markers() {
+        let input = [PASTE_START, PASTE_END].concat().repeat(64 * 1024 / 12);
+        assert_eq!(sanitize(&input), b"");
+        // Each marker split around another one, which joins them once removed.
+        let input = b"\x1b[2\x1b[200~0\x1b[201~0~x".repeat(1000);
+        assert_eq!(sanitize(&input), b"x".repeat(1000));
+    }
+}
@@ -0,0 +1,1414 @@
+//! Terminal emulation, for showing new clients what the screen currently looks like.
+//!
+//! Replaying raw output to a client that attaches in the middle of a session falls apart with full-screen applications: the client sees whatever fit in a history buffer, interpreted from some arbitrary starting point.
+//! Instead, all PTY output goes through [Screen], and a new client gets a synthesized [Screen::snapshot] that redraws the current state from scratch.
+//! The raw replay is still sent before the snapshot, to give the client's own scrollback some context, see [Scrollback](super::scrollback::Scrollback).
+//!
+//! This understands the commonly used subset of xterm control sequences: cursor movement, erasing, scrolling regions, character attributes, the alternate screen and modes that affect how input is encoded.
+//! Anything else is ignored.
+//! There is no history beyond the visible screen.
+//!
+//! <https://invisible-island.net/xterm/ctlseqs/ctlseqs.html>
+
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+use crate::pty_master::WindowSize;
+
+/// Size used when the PTY doesn't have one yet.
+pub(super) const DEFAULT_SIZE: WindowSize = WindowSize {
+    rows: 24,
+    columns: 80,
+};
+
+const BEL: u8 = 0x07;
+const BS: u8 = 0x08;
+const HT: u8 = 0x09;
+const LF: u8 = 0x0a;
+const VT: u8 = 0x0b;
+const FF: u8 = 0x0c;
+const CR: u8 = 0x0d;
+const CAN: u8 = 0x18;
+const SUB: u8 = 0x1a;
+const ESC: u8 = 0x1b;
+
+/// Longest OSC string we bother to remember.
+const MAX_OSC_LEN: usize = 4096;
+const MAX_CSI_PARAMS: usize = 32;
+
+/// DEC private mode asking for pasted text to be bracketed, see [paste](super::paste).
+const MODE_BRACKETED_PASTE: u16 = 2004;
+
+/// DEC private modes that don't change what's on the screen, but need to be restored in the client's terminal.
+#[rustfmt::skip]
+const PASSTHROUGH_MODES: &[u16] = &[
+    // Application cursor keys.
+    1,
+    // Mouse and focus reporting, in all their variations.
+    9, 1000, 1002, 1003, 1004, 1005, 1006, 1015,
+    // Bracketed paste.
+    MODE_BRACKETED_PASTE,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Color {
+    Default,
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+impl Color {
+    /// Append SGR parameters selecting this color.
+    /// `base` is 30 for foreground, 40 for background.
+    fn sgr(self, base: u8, out: &mut String) {
+        match self {
+            Color::Default => write!(out, ";{}", base + 9),
+            Color::Indexed(n) if n < 8 => write!(out, ";{}", base + n),
+            Color::Indexed(n) if n < 16 => write!(out, ";{}", base + 60 + n - 8),
+            Color::Indexed(n) => write!(out, ";{};5;{}", base + 8, n),
+            Color::Rgb(r, g, b) => write!(out, ";{};2;{};{};{}", base + 8, r, g, b),
+        }
+        .expect("internal: write to String failed");
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Attrs {
+    bold: bool,
+    dim: bool,
+    italic: bool,
+    underline: bool,
+    blink: bool,
+    inverse: bool,
+    hidden: bool,
+    strikethrough: bool,
+    fg: Color,
+    bg: Color,
+}
+
+impl Default for Attrs {
+    fn default() -> Self {
+        Self {
+            bold: false,
+            dim: false,
+            italic: false,
+            underline: false,
+            blink: false,
+            inverse: false,
+            hidden: false,
+            strikethrough: false,
+            fg: Color::Default,
+            bg: Color::Default,
+        }
+    }
+}
+
+impl Attrs {
+    /// SGR sequence that sets exactly these attributes, starting from a reset.
+    fn sgr(&self) -> String {
+        let mut out = String::from("\x1b[0");
+        let flags = [
+            (self.bold, 1),
+            (self.dim, 2),
+            (self.italic, 3),
+            (self.underline, 4),
+            (self.blink, 5),
+            (self.inverse, 7),
+            (self.hidden, 8),
+            (self.strikethrough, 9),
+        ];
+        for (set, param) in flags.iter() {
+            if *set {
+                write!(out, ";{}", param).expect("internal: write to String failed");
+            }
+        }
+        if self.fg != Color::Default {
+            self.fg.sgr(30, &mut out);
+        }
+        if self.bg != Color::Default {
+            self.bg.sgr(40, &mut out);
+        }
+        out.push('m');
+        out
+    }
+
+    fn apply_sgr(&mut self, params: &[u32]) {
+        let mut iter = params.iter().copied();
+        while let Some(param) = iter.next() {
+            match param {
+                0 => *self = Attrs::default(),
+                1 => self.bold = true,
+                2 => self.dim = true,
+                3 => self.italic = true,
+                4 | 21 => self.underline = true,
+                5 | 6 => self.blink = true,
+                7 => self.inverse = true,
+                8 => self.hidden = true,
+                9 => self.strikethrough = true,
+                22 => {
+                    self.bold = false;
+                    self.dim = false;
+                }
+                23 => self.italic = false,
+                24 => self.underline = false,
+                25 => self.blink = false,
+                27 => self.inverse = false,
+                28 => self.hidden = false,
+                29 => self.strikethrough = false,
+                30..=37 => self.fg = Color::Indexed((param - 30) as u8),
+                38 => {
+                    if let Some(color) = extended_color(&mut iter) {
+                        self.fg = color;
+                    }
+                }
+                39 => self.fg = Color::Default,
+                40..=47 => self.bg = Color::Indexed((param - 40) as u8),
+                48 => {
+                    if let Some(color) = extended_color(&mut iter) {
+                        self.bg = color;
+                    }
+                }
+                49 => self.bg = Color::Default,
+                90..=97 => self.fg = Color::Indexed((param - 90 + 8) as u8),
+                100..=107 => self.bg = Color::Indexed((param - 100 + 8) as u8),
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Parse the rest of an `38;5;n` or `38;2;r;g;b` color.
+fn extended_color(params: &mut impl Iterator<Item = u32>) -> Option<Color> {
+    fn byte(param: Option<u32>) -> Option<u8> {
+        param.map(|p| p.min(255) as u8)
+    }
+    match params.next()? {
+        5 => Some(Color::Indexed(byte(params.next())?)),
+        2 => Some(Color::Rgb(
+            byte(params.next())?,
+            byte(params.next())?,
+            byte(params.next())?,
+        )),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cell {
+    /// `'\0'` marks the second half of a wide character.
+    ch: char,
+    attrs: Attrs,
+}
+
+const WIDE_CONTINUATION: char = '\0';
+
+impl Cell {
+    fn blank(attrs: Attrs) -> Self {
+        Self { ch: ' ', attrs }
+    }
+
+    fn is_default_blank(&self) -> bool {
+        self.ch == ' ' && self.attrs == Attrs::default()
+    }
+}
+
+type Row = Vec<Cell>;
+
+/// Blank out halves of wide characters left behind by shifting or truncating cells.
+fn repair_wide(line: &mut Row) {
+    for col in 0..line.len() {
+        let continued = line
+            .get(col + 1)
+            .map_or(false, |cell| cell.ch == WIDE_CONTINUATION);
+        if char_width(line[col].ch) == 2 && !continued {
+            line[col] = Cell::blank(line[col].attrs);
+        }
+        let lead = col > 0 && char_width(line[col - 1].ch) == 2;
+        if line[col].ch == WIDE_CONTINUATION && !lead {
+            line[col] = Cell::blank(line[col].attrs);
+        }
+    }
+}
+
+/// Approximation of `wcwidth(3)`, good enough for the usual combining marks, CJK and emoji.
+fn char_width(c: char) -> usize {
+    match c as u32 {
+        0x0300..=0x036f
+        | 0x1ab0..=0x1aff
+        | 0x1dc0..=0x1dff
+        | 0x200b..=0x200f
+        | 0x20d0..=0x20ff
+        | 0xfe00..=0xfe0f
+        | 0xfe20..=0xfe2f => 0,
+        0x1100..=0x115f
+        | 0x2e80..=0x303e
+        | 0x3041..=0x33ff
+        | 0x3400..=0x4dbf
+        | 0x4e00..=0x9fff
+        | 0xa000..=0xa4cf
+        | 0xac00..=0xd7a3
+        | 0xf900..=0xfaff
+        | 0xfe30..=0xfe4f
+        | 0xff00..=0xff60
+        | 0xffe0..=0xffe6
+        | 0x1f300..=0x1f64f
+        | 0x1f900..=0x1f9ff
+        | 0x20000..=0x2fffd
+        | 0x30000..=0x3fffd => 2,
+        _ => 1,
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Cursor {
+    row: usize,
+    col: usize,
+    attrs: Attrs,
+    /// A character was written to the last column, the next one goes on a new line.
+    pending_wrap: bool,
+}
+
+#[derive(Default)]
+struct Csi {
+    /// Private marker, like the `?` in `CSI ? 25 h`.
+    prefix: Option<u8>,
+    params: Vec<u32>,
+    current: u32,
+    // Sequences with intermediate bytes or subparameters are something else entirely, just skip them.
+    ignore: bool,
+}
+
+impl Csi {
+    /// Parameter `idx`, with missing and zero values replaced by `default`.
+    fn param(&self, idx: usize, default: u32) -> u32 {
+        match self.params.get(idx) {
+            None | Some(0) => default,
+            Some(value) => *value,
+        }
+    }
+}
+
+enum State {
+    Ground,
+    Escape,
+    /// `ESC` followed by intermediate bytes, e.g. character set designation.
+    EscapeIntermediate,
+    Csi(Csi),
+    Osc(Vec<u8>),
+    OscEscape(Vec<u8>),
+    /// DCS, SOS, PM and APC strings, which are ignored.
+    String,
+    StringEscape,
+}
+
+/// Decode UTF-8 split across arbitrary chunks.
+#[derive(Default)]
+struct Utf8 {
+    buf: [u8; 4],
+    len: usize,
+    needed: usize,
+}
+
+impl Utf8 {
+    fn start(&mut self, b: u8) -> Option<char> {
+        let needed = match b {
+            0xc2..=0xdf => 1,
+            0xe0..=0xef => 2,
+            0xf0..=0xf4 => 3,
+            _ => return Some(char::REPLACEMENT_CHARACTER),
+        };
+        self.buf[0] = b;
+        self.len = 1;
+        self.needed = needed;
+        None
+    }
+
+    fn continue_with(&mut self, b: u8) -> Option<char> {
+        self.buf[self.len] = b;
+        self.len += 1;
+        self.needed -= 1;
+        if self.needed > 0 {
+            return None;
+        }
+        let c = std::str::from_utf8(&self.buf[..self.len])
+            .ok()
+            .and_then(|s| s.chars().next())
+            .unwrap_or(char::REPLACEMENT_CHARACTER);
+        self.len = 0;
+        Some(c)
+    }
+
+    fn in_progress(&self) -> bool {
+        self.needed > 0
+    }
+
+    fn abort(&mut self) {
+        self.len = 0;
+        self.needed = 0;
+    }
+}
+
+/// In-memory model of a terminal screen, fed with PTY output.
+pub(super) struct Screen {
+    rows: usize,
+    columns: usize,
+    primary: Vec<Row>,
+    alternate: Vec<Row>,
+    alternate_active: bool,
+    cursor: Cursor,
+    saved_cursor: Cursor,
+    /// First and last row of the scrolling region, inclusive.
+    scroll_top: usize,
+    scroll_bottom: usize,
+    autowrap: bool,
+    cursor_visible: bool,
+    keypad_application: bool,
+    /// Enabled modes from [PASSTHROUGH_MODES].
+    modes: BTreeSet<u16>,
+    title: Option<String>,
+    last_char: Option<char>,
+    state: State,
+    utf8: Utf8,
+}
+
+impl Screen {
+    pub(super) fn new(size: WindowSize) -> Self {
+        let (rows, columns) = Self::dimensions(size);
+        Self {
+            rows,
+            columns,
+            primary: vec![vec![Cell::blank(Attrs::default()); columns]; rows],
+            alternate: vec![vec![Cell::blank(Attrs::default()); columns]; rows],
+            alternate_active: false,
+            cursor: Cursor::default(),
+            saved_cursor: Cursor::default(),
+            scroll_top: 0,
+            scroll_bottom: rows - 1,
+            autowrap: true,
+            cursor_visible: true,
+            keypad_application: false,
+            modes: BTreeSet::new(),
+            title: None,
+            last_char: None,
+            state: State::Ground,
+            utf8: Utf8::default(),
+        }
+    }
+
+    fn dimensions(size: WindowSize) -> (usize, usize) {
+        let size = if size.rows == 0 || size.columns == 0 {
+            DEFAULT_SIZE
+        } else {
+            size
+        };
+        (usize::from(size.rows), usize::from(size.columns))
+    }
+
+    /// Has the application enabled bracketed paste mode.
+    pub(super) fn bracketed_paste(&self) -> bool {
+        self.modes.contains(&MODE_BRACKETED_PASTE)
+    }
+
+    /// Process a chunk of PTY output.
+    /// Control sequences and characters may be split across chunks.
+    pub(super) fn feed(&mut self, data: &[u8]) {
+        for &b in data {
+            self.feed_byte(b);
+        }
+    }
+
+    fn feed_byte(&mut self, b: u8) {
+        let state = std::mem::replace(&mut self.state, State::Ground);
+        self.state = match state {
+            State::Ground => {
+                self.ground(b);
+                // `ground` may have started an escape sequence.
+                return;
+            }
+            State::Escape => self.escape(b),
+            State::EscapeIntermediate => match b {
+                CAN | SUB => State::Ground,
+                ESC => State::Escape,
+                0x20..=0x2f => State::EscapeIntermediate,
+                0x30..=0x7e => State::Ground,
+                _ => {
+                    self.control(b);
+                    State::EscapeIntermediate
+                }
+            },
+            State::Csi(mut csi) => match b {
+                CAN | SUB => State::Ground,
+                ESC => State::Escape,
+                b'0'..=b'9' => {
+                    csi.current = csi
+                        .current
+                        .saturating_mul(10)
+                        .saturating_add(u32::from(b - b'0'));
+                    State::Csi(csi)
+                }
+                b';' => {
+                    if csi.params.len() < MAX_CSI_PARAMS {
+                        csi.params.push(csi.current);
+                    }
+                    csi.current = 0;
+                    State::Csi(csi)
+                }
+                b'<' | b'=' | b'>' | b'?'
+                    if csi.prefix.is_none() && csi.params.is_empty() && csi.current == 0 =>
+                {
+                    csi.prefix = Some(b);
+                    State::Csi(csi)
+                }
+                0x20..=0x2f | 0x3a..=0x3f => {
+                    csi.ignore = true;
+                    State::Csi(csi)
+                }
+                0x40..=0x7e => {
+                    if csi.params.len() < MAX_CSI_PARAMS {
+                        csi.params.push(csi.current);
+                    }
+                    if !csi.ignore {
+                        self.csi_dispatch(&csi, b);
+                    }
+                    State::Ground
+                }
+                // Other control characters are executed in the middle of a sequence, they don't affect it.
+                _ => {
+                    self.control(b);
+                    State::Csi(csi)
+                }
+            },
+            State::Osc(mut buf) => match b {
+                BEL => {
+                    self.osc_dispatch(&buf);
+                    State::Ground
+                }
+                ESC => State::OscEscape(buf),
+                CAN | SUB => State::Ground,
+                _ => {
+                    if buf.len() < MAX_OSC_LEN {
+                        buf.push(b);
+                    }
+                    State::Osc(buf)
+                }
+            },
+            State::OscEscape(buf) => {
+                if b == b'\\' {
+                    self.osc_dispatch(&buf);
+                    State::Ground
+                } else {
+                    // Anything but ST aborts the string, and starts a new sequence.
+                    self.escape(b)
+                }
+            }
+            State::String => match b {
+                ESC => State::StringEscape,
+                CAN | SUB => State::Ground,
+                _ => State::String,
+            },
+            State::StringEscape => match b {
+                b'\\' => State::Ground,
+                _ => self.escape(b),
+            },
+        };
+    }
+
+    fn ground(&mut self, b: u8) {
+        if self.utf8.in_progress() {
+            if b & 0xc0 == 0x80 {
+                if let Some(c) = self.utf8.continue_with(b) {
+                    self.print(c);
+                }
+                return;
+            }
+            self.utf8.abort();
+            self.print(char::REPLACEMENT_CHARACTER);
+        }
+        match b {
+            ESC => self.state = State::Escape,
+            0x00..=0x1f => self.control(b),
+            0x20..=0x7e => self.print(char::from(b)),
+            0x7f => (),
+            _ => {
+                if let Some(c) = self.utf8.start(b) {
+                    self.print(c);
+                }
+            }
+        }
+    }
+
+    fn escape(&mut self, b: u8) -> State {
+        match b {
+            b'[' => return State::Csi(Csi::default()),
+            b']' => return State::Osc(Vec::new()),
+            b'P' | b'X' | b'^' | b'_' => return State::String,
+            0x20..=0x2f => return State::EscapeIntermediate,
+            ESC => return State::Escape,
+            CAN | SUB => (),
+            0x00..=0x1f => {
+                self.control(b);
+                return State::Escape;
+            }
+            // RIS, full reset.
+            b'c' => self.reset(),
+            // DECSC and DECRC.
+            b'7' => self.save_cursor(),
+            b'8' => self.restore_cursor(),
+            // IND
+            b'D' => self.linefeed(),
+            // NEL
+            b'E' => {
+                self.cursor.col = 0;
+                self.linefeed();
+            }
+            // RI
+            b'M' => self.reverse_index(),
+            // DECKPAM and DECKPNM.
+            b'=' => self.keypad_application = true,
+            b'>' => self.keypad_application = false,
+            _ => (),
+        }
+        State::Ground
+    }
+
+    fn control(&mut self, b: u8) {
+        match b {
+            BS => {
+                self.cursor.pending_wrap = false;
+                self.cursor.col = self.cursor.col.saturating_sub(1);
+            }
+            HT => {
+                self.cursor.pending_wrap = false;
+                self.cursor.col = ((self.cursor.col / 8 + 1) * 8).min(self.columns - 1);
+            }
+            LF | VT | FF => self.linefeed(),
+            CR => {
+                self.cursor.pending_wrap = false;
+                self.cursor.col = 0;
+            }
+            _ => (),
+        }
+    }
+
+    fn reset(&mut self) {
+        let size = WindowSize {
+            rows: self.rows as u16,
+            columns: self.columns as u16,
+        };
+        *self = Self::new(size);
+    }
+
+    fn grid_mut(&mut self) -> &mut Vec<Row> {
+        if self.alternate_active {
+            &mut self.alternate
+        } else {
+            &mut self.primary
+        }
+    }
+
+    /// Blank cell, as left behind by erasing.
+    /// Like xterm, erased cells get the current background color.
+    fn erased(&self) -> Cell {
+        Cell::blank(Attrs {
+            bg: self.cursor.attrs.bg,
+            ..Attrs::default()
+        })
+    }
+
+    fn blank_row(&self) -> Row {
+        vec![self.erased(); self.columns]
+    }
+
+    /// Blank out the other half of any wide character at `col`, before it is overwritten.
+    fn split_wide(&mut self, row: usize, col: usize) {
+        let columns = self.columns;
+        let blank = self.erased();
+        let line = &mut self.grid_mut()[row];
+        if line[col].ch == WIDE_CONTINUATION && col > 0 {
+            line[col - 1] = blank;
+        }
+        if col + 1 < columns && line[col + 1].ch == WIDE_CONTINUATION {
+            line[col + 1] = blank;
+        }
+    }
+
+    fn print(&mut self, c: char) {
+        let width = char_width(c);
+        if width == 0 {
+            // Combining characters are not worth modeling.
+            return;
+        }
+        if self.cursor.pending_wrap {
+            self.cursor.col = 0;
+            self.linefeed();
+        }
+        if width == 2 && self.cursor.col + 1 >= self.columns {
+            if self.columns < 2 {
+                return;
+            }
+            if self.autowrap {
+                let (row, col) = (self.cursor.row, self.cursor.col);
+                self.split_wide(row, col);
+                let blank = self.erased();
+                self.grid_mut()[row][col] = blank;
+                self.cursor.col = 0;
+                self.linefeed();
+            } else {
+                self.cursor.col = self.columns - 2;
+            }
+        }
+
+        let (row, col) = (self.cursor.row, self.cursor.col);
+        let attrs = self.cursor.attrs;
+        self.split_wide(row, col);
+        if width == 2 {
+            self.split_wide(row, col + 1);
+        }
+        let line = &mut self.grid_mut()[row];
+        line[col] = Cell { ch: c, attrs };
+        if width == 2 {
+            line[col + 1] = Cell {
+                ch: WIDE_CONTINUATION,
+                attrs,
+            };
+        }
+        self.last_char = Some(c);
+
+        let next = col + width;
+        if next >= self.columns {
+            self.cursor.col = self.columns - 1;
+            self.cursor.pending_wrap = self.autowrap;
+        } else {
+            self.cursor.col = next;
+        }
+    }
+
+    fn linefeed(&mut self) {
+        self.cursor.pending_wrap = false;
+        if self.cursor.row == self.scroll_bottom {
+            self.scroll_up(1);
+        } else if self.cursor.row + 1 < self.rows {
+            self.cursor.row += 1;
+        }
+    }
+
+    fn reverse_index(&mut self) {
+        self.cursor.pending_wrap = false;
+        if self.cursor.row == self.scroll_top {
+            self.scroll_down(1);
+        } else if self.cursor.row > 0 {
+            self.cursor.row -= 1;
+        }
+    }
+
+    /// Scroll the lines in `top..=bottom` up by `n`, adding blank lines at the bottom.
+    fn scroll_region_up(&mut self, top: usize, bottom: usize, n: usize) {
+        let n = n.min(bottom + 1 - top);
+        let blank = self.blank_row();
+        let grid = self.grid_mut();
+        grid.drain(top..top + n);
+        for _ in 0..n {
+            grid.insert(bottom + 1 - n, blank.clone());
+        }
+    }
+
+    /// Scroll the lines in `top..=bottom` down by `n`, adding blank lines at the top.
+    fn scroll_region_down(&mut self, top: usize, bottom: usize, n: usize) {
+        let n = n.min(bottom + 1 - top);
+        let blank = self.blank_row();
+        let grid = self.grid_mut();
+        grid.drain(bottom + 1 - n..=bottom);
+        for _ in 0..n {
+            grid.insert(top, blank.clone());
+        }
+    }
+
+    fn scroll_up(&mut self, n: usize) {
+        self.scroll_region_up(self.scroll_top, self.scroll_bottom, n);
+    }
+
+    fn scroll_down(&mut self, n: usize) {
+        self.scroll_region_down(self.scroll_top, self.scroll_bottom, n);
+    }
+
+    fn save_cursor(&mut self) {
+        self.saved_cursor = self.cursor;
+    }
+
+    fn restore_cursor(&mut self) {
+        self.cursor = self.saved_cursor;
+        self.cursor.row = self.cursor.row.min(self.rows - 1);
+        self.cursor.col = self.cursor.col.min(self.columns - 1);
+    }
+
+    fn move_to(&mut self, row: usize, col: usize) {
+        self.cursor.pending_wrap = false;
+        self.cursor.row = row.min(self.rows - 1);
+        self.cursor.col = col.min(self.columns - 1);
+    }
+
+    /// Erase `cols` of a row.
+    fn erase_in_row(&mut self, row: usize, cols: std::ops::Range<usize>) {
+        let blank = self.erased();
+        let line = &mut self.grid_mut()[row];
+        for cell in &mut line[cols] {
+            *cell = blank;
+        }
+        repair_wide(line);
+    }
+
+    fn erase_rows(&mut self, rows: std::ops::Range<usize>) {
+        let blank = self.blank_row();
+        for line in &mut self.grid_mut()[rows] {
+            line.clone_from(&blank);
+        }
+    }
+
+    fn set_alternate_screen(&mut self, enable: bool, mode: u32) {
+        if enable == self.alternate_active {
+            return;
+        }
+        if enable {
+            if mode == 1049 {
+                self.save_cursor();
+            }
+            self.alternate_active = true;
+            if mode != 47 {
+                let rows = self.rows;
+                self.erase_rows(0..rows);
+            }
+        } else {
+            self.alternate_active = false;
+            if mode == 1049 {
+                self.restore_cursor();
+            }
+        }
+    }
+
+    fn set_private_mode(&mut self, mode: u32, enable: bool) {
+        match mode {
+            7 => {
+                self.autowrap = enable;
+                if !enable {
+                    self.cursor.pending_wrap = false;
+                }
+            }
+            25 => self.cursor_visible = enable,
+            47 | 1047 | 1049 => self.set_alternate_screen(enable, mode),
+            1048 => {
+                if enable {
+                    self.save_cursor();
+                } else {
+                    self.restore_cursor();
+                }
+            }
+            _ => {
+                if let Some(mode) = PASSTHROUGH_MODES.iter().find(|m| u32::from(**m) == mode) {
+                    if enable {
+                        self.modes.insert(*mode);
+                    } else {
+                        self.modes.remove(mode);
+                    }
+                }
+            }
+        }
+    }
+
+    fn csi_dispatch(&mut self, csi: &Csi, action: u8) {
+        if csi.prefix == Some(b'?') {
+            match action {
+                b'h' | b'l' => {
+                    for mode in &csi.params {
+                        self.set_private_mode(*mode, action == b'h');
+                    }
+                }
+                _ => (),
+            }
+            return;
+        }
+        if csi.prefix.is_some() {
+            return;
+        }
+
+        let n = csi.param(0, 1) as usize;
+        let (row, col) = (self.cursor.row, self.cursor.col);
+        match action {
+            // ICH
+            b'@' => {
+                let n = n.min(self.columns - col);
+                let blank = self.erased();
+                let columns = self.columns;
+                let line = &mut self.grid_mut()[row];
+                line.truncate(columns - n);
+                for _ in 0..n {
+                    line.insert(col, blank);
+                }
+                repair_wide(line);
+                self.cursor.pending_wrap = false;
+            }
+            // CUU
+            b'A' => {
+                let top = if row >= self.scroll_top {
+                    self.scroll_top
+                } else {
+                    0
+                };
+                self.move_to(row.saturating_sub(n).max(top), col);
+            }
+            // CUD, VPR
+            b'B' | b'e' => {
+                let bottom = if row <= self.scroll_bottom {
+                    self.scroll_bottom
+                } else {
+                    self.rows - 1
+                };
+                self.move_to((row + n).min(bottom), col);
+            }
+            // CUF, HPR
+            b'C' | b'a' => self.move_to(row, col + n),
+            // CUB
+            b'D' => self.move_to(row, col.saturating_sub(n)),
+            // CNL
+            b'E' => self.move_to(row + n, 0),
+            // CPL
+            b'F' => self.move_to(row.saturating_sub(n), 0),
+            // CHA, HPA
+            b'G' | b'`' => self.move_to(row, n - 1),
+            // CUP, HVP
+            b'H' | b'f' => {
+                let col = csi.param(1, 1) as usize;
+                self.move_to(n - 1, col - 1);
+            }
+            // ED
+            b'J' => {
+                let rows = self.rows;
+                let columns = self.columns;
+                match csi.param(0, 0) {
+                    0 => {
+                        self.erase_in_row(row, col..columns);
+                        self.erase_rows(row + 1..rows);
+                    }
+                    1 => {
+                        self.erase_rows(0..row);
+                        self.erase_in_row(row, 0..col + 1);
+                    }
+                    2 => self.erase_rows(0..rows),
+                    _ => (),
+                }
+                self.cursor.pending_wrap = false;
+            }
+            // EL
+            b'K' => {
+                let columns = self.columns;
+                match csi.param(0, 0) {
+                    0 => self.erase_in_row(row, col..columns),
+                    1 => self.erase_in_row(row, 0..col + 1),
+                    2 => self.erase_in_row(row, 0..columns),
+                    _ => (),
+                }
+                self.cursor.pending_wrap = false;
+            }
+            // IL
+            b'L' => {
+                if row >= self.scroll_top && row <= self.scroll_bottom {
+                    self.scroll_region_down(row, self.scroll_bottom, n);
+                    self.move_to(row, 0);
+                }
+            }
+            // DL
+            b'M' => {
+                if row >= self.scroll_top && row <= self.scroll_bottom {
+                    self.scroll_region_up(row, self.scroll_bottom, n);
+                    self.move_to(row, 0);
+                }
+            }
+            // DCH
+            b'P' => {
+                let n = n.min(self.columns - col);
+                let blank = self.erased();
+                let line = &mut self.grid_mut()[row];
+                line.drain(col..col + n);
+                line.extend(std::iter::repeat(blank).take(n));
+                repair_wide(line);
+                self.cursor.pending_wrap = false;
+            }
+            // SU
+            b'S' => self.scroll_up(n),
+            // SD
+            b'T' => self.scroll_down(n),
+            // ECH
+            b'X' => {
+                let end = (col + n).min(self.columns);
+                self.erase_in_row(row, col..end);
+                self.cursor.pending_wrap = false;
+            }
+            // REP
+            b'b' => {
+                if let Some(c) = self.last_char {
+                    for _ in 0..n.min(self.rows * self.columns) {
+                        self.print(c);
+                    }
+                }
+            }
+            // VPA
+            b'd' => self.move_to(n - 1, col),
+            // SGR
+            b'm' => self.cursor.attrs.apply_sgr(&csi.params),
+            // DECSTBM
+            b'r' => {
+                let top = csi.param(0, 1) as usize;
+                let bottom = (csi.param(1, self.rows as u32) as usize).min(self.rows);
+                if top < bottom {
+                    self.scroll_top = top - 1;
+                    self.scroll_bottom = bottom - 1;
+                    self.move_to(0, 0);
+                }
+            }
+            // SCOSC and SCORC.
+            b's' => self.save_cursor(),
+            b'u' => self.restore_cursor(),
+            _ => (),
+        }
+    }
+
+    fn osc_dispatch(&mut self, data: &[u8]) {
+        let text = String::from_utf8_lossy(data);
+        let mut parts = text.splitn(2, ';');
+        match (parts.next(), parts.next()) {
+            (Some("0"), Some(title)) | (Some("2"), Some(title)) => {
+                self.title = Some(title.to_string());
+            }
+            _ => (),
+        }
+    }
+
+    /// Change the screen size.
+    ///
+    /// Shrinking keeps the cursor on screen by dropping lines from the top, the way terminals usually do.
+    pub(super) fn resize(&mut self, size: WindowSize) {
+        let (rows, columns) = Self::dimensions(size);
+        if (rows, columns) == (self.rows, self.columns) {
+            return;
+        }
+        let shift = (self.cursor.row + 1).saturating_sub(rows);
+        self.grid_mut().drain(..shift);
+        for grid in [&mut self.primary, &mut self.alternate].iter_mut() {
+            grid.resize(rows, vec![Cell::blank(Attrs::default()); columns]);
+            for line in grid.iter_mut() {
+                line.resize(columns, Cell::blank(Attrs::default()));
+                repair_wide(line);
+            }
+        }
+        self.rows = rows;
+        self.columns = columns;
+        self.scroll_top = 0;
+        self.scroll_bottom = rows - 1;
+        self.saved_cursor.row = self.saved_cursor.row.min(rows - 1);
+        self.saved_cursor.col = self.saved_cursor.col.min(columns - 1);
+        let (row, col) = (self.cursor.row - shift, self.cursor.col);
+        self.move_to(row, col);
+    }
+
+    fn draw(grid: &[Row], out: &mut String) {
+        let mut attrs = Attrs::default();
+        for (idx, line) in grid.iter().enumerate() {
+            let end = match line.iter().rposition(|cell| !cell.is_default_blank()) {
+                Some(end) => end + 1,
+                None => continue,
+            };
+            write!(out, "\x1b[{}H", idx + 1).expect("internal: write to String failed");
+            for cell in &line[..end] {
+                if cell.ch == WIDE_CONTINUATION {
+                    continue;
+                }
+                if cell.attrs != attrs {
+                    attrs = cell.attrs;
+                    out.push_str(&attrs.sgr());
+                }
+                out.push(cell.ch);
+            }
+        }
+        if attrs != Attrs::default() {
+            out.push_str("\x1b[0m");
+        }
+    }
+
+    /// Control sequences that recreate the current state of the screen on a terminal of the same size.
+    ///
+    /// Anything earlier output may have changed is reset first, so the snapshot can follow a replay of raw output.
+    pub(super) fn snapshot(&self) -> Vec<u8> {
+        // Leave the alternate screen, and undo everything set below.
+        let mut out = String::from("\x1b[?1049l\x1b[?7h\x1b[?25h\x1b>");
+        for mode in PASSTHROUGH_MODES {
+            write!(out, "\x1b[?{}l", mode).expect("internal: write to String failed");
+        }
+        out.push_str("\x1b[0m\x1b[r\x1b[H\x1b[2J");
+        Self::draw(&self.primary, &mut out);
+        if self.alternate_active {
+            // Leaving the alternate screen restores the cursor saved when entering it.
+            write!(
+                out,
+                "\x1b[{};{}H\x1b[?1049h",
+                self.saved_cursor.row + 1,
+                self.saved_cursor.col + 1
+            )
+            .expect("internal: write to String failed");
+            Self::draw(&self.alternate, &mut out);
+        }
+        if (self.scroll_top, self.scroll_bottom) != (0, self.rows - 1) {
+            write!(
+                out,
+                "\x1b[{};{}r",
+                self.scroll_top + 1,
+                self.scroll_bottom + 1
+            )
+            .expect("internal: write to String failed");
+        }
+        for mode in &self.modes {
+            write!(out, "\x1b[?{}h", mode).expect("internal: write to String failed");
+        }
+        if !self.autowrap {
+            out.push_str("\x1b[?7l");
+        }
+        if self.keypad_application {
+            out.push_str("\x1b=");
+        }
+        if let Some(title) = &self.title {
+            write!(out, "\x1b]2;{}\x07", title).expect("internal: write to String failed");
+        }
+        write!(out, "\x1b[{};{}H", self.cursor.row + 1, self.cursor.col + 1)
+            .expect("internal: write to String failed");
+        if self.cursor.attrs != Attrs::default() {
+            out.push_str(&self.cursor.attrs.sgr());
+        }
+        if !self.cursor_visible {
+            out.push_str("\x1b[?25l");
+        }
+        out.into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn screen(rows: u16, columns: u16) -> Screen {
+        Screen::new(WindowSize { rows, columns })
+    }
+
+    /// Visible text of the active screen, with trailing blanks removed.
+    fn text(screen: &Screen) -> String {
+        let grid = if screen.alternate_active {
+            &screen.alternate
+        } else {
+            &screen.primary
+        };
+        let lines: Vec<String> = grid
+            .iter()
+            .map(|line| {
+                let s: String = line
+                    .iter()
+                    .filter(|cell| cell.ch != WIDE_CONTINUATION)
+                    .map(|cell| cell.ch)
+                    .collect();
+                s.trim_end().to_string()
+            })
+            .collect();
+        lines.join("\n").trim_end().to_string()
+    }
+
+    fn cursor(screen: &Screen) -> (usize, usize) {
+        (screen.cursor.row, screen.cursor.col)
+    }
+
+    /// Replaying the snapshot on a fresh terminal must give the same state.
+    fn assert_snapshot_roundtrip(screen: &Screen) {
+        assert_snapshot_after(screen, b"");
+    }
+
+    /// Replaying the snapshot after `before` must give the same state.
+    fn assert_snapshot_after(screen: &Screen, before: &[u8]) {
+        let mut copy = Screen::new(WindowSize {
+            rows: screen.rows as u16,
+            columns: screen.columns as u16,
+        });
+        copy.feed(before);
+        copy.feed(&screen.snapshot());
+        assert!(copy.primary == screen.primary, "primary screen differs");
+        assert_eq!(copy.alternate_active, screen.alternate_active);
+        if screen.alternate_active {
+            assert!(
+                copy.alternate == screen.alternate,
+                "alternate screen differs"
+            );
+        }
+        assert_eq!(cursor(&copy), cursor(screen));
+        assert_eq!(copy.cursor.attrs, screen.cursor.attrs);
+        assert_eq!(
+            (copy.scroll_top, copy.scroll_bottom),
+            (screen.scroll_top, screen.scroll_bottom)
+        );
+        assert_eq!(copy.autowrap, screen.autowrap);
+        assert_eq!(copy.cursor_visible, screen.cursor_visible);
+        assert_eq!(copy.keypad_application, screen.keypad_application);
+        assert_eq!(copy.modes, screen.modes);
+        assert_eq!(copy.title, screen.title);
+    }
+
+    #[test]
+    fn simple() {
+        let mut screen = screen(24, 80);
+        screen.feed(b"hello\r\nworld");
+        assert_eq!(text(&screen), "hello\nworld");
+        assert_eq!(cursor(&screen), (1, 5));
+        assert_snapshot_roundtrip(&screen);
+    }
+
+    #[test]
+    fn zero_size() {
+        let screen = screen(0, 0);
+        assert_eq!((screen.rows, screen.columns), (24, 80));
+    }
+
+    #[test]
+    fn autowrap() {
+        let mut screen = screen(3, 5);
+        screen.feed(b"abcde");
+        assert_eq!(cursor(&screen), (0, 4));
+        screen.feed(b"fg");
+        assert_eq!(text(&screen), "abcde\nfg");
+        assert_eq!(cursor(&screen), (1, 2));
+
+        screen.feed(b"\x1b[?7l\r\nhijklmn");
+        assert_eq!(text(&screen), "abcde\nfg\nhijkn");
+        assert_snapshot_roundtrip(&screen);
+    }
+
+    #[test]
+    fn scroll() {
+        let mut screen = screen(3, 10);
+        screen.feed(b"1\r\n2\r\n3\r\n4");
+        assert_eq!(text(&screen), "2\n3\n4");
+        assert_snapshot_roundtrip(&screen);
+    }
+
+    #[test]
+    fn scroll_region() {
+        let mut screen = screen(5, 10);
+        screen.feed(b"top\x1b[2;4r\x1b[4Ha\r\nb\r\nc\x1b[5Hbottom");
+        assert_eq!(text(&screen), "top\na\nb\nc\nbottom");
+        assert_snapshot_roundtrip(&screen);
+    }
+
+    #[test]
+    fn erase() {
+        let mut screen = screen(3, 10);
+        screen.feed(b"hello\r\nworld\x1b[1;3H\x1b[K");
+        assert_eq!(text(&screen), "he\nworld");
+        screen.feed(b"\x1b[2;2H\x1b[1K");
+        assert_eq!(text(&screen), "he\n  rld");
+        screen.feed(b"\x1b[2J");
+        assert_eq!(text(&screen), "");
+        assert_eq!(cursor(&screen), (1, 1));
+    }
+
+    #[test]
+    fn insert_delete() {
+        let mut screen = screen(4, 10);
+        screen.feed(b"1\r\n2\r\n3\r\n4\x1b[2H\x1b[L");
+        assert_eq!(text(&screen), "1\n\n2\n3");
+        screen.feed(b"\x1b[2M");
+        assert_eq!(text(&screen), "1\n3");
+        screen.feed(b"\x1b[1;1Habcdef\x1b[1;2H\x1b[2P");
+        assert_eq!(text(&screen), "adef\n3");
+        screen.feed(b"\x1b[2@");
+        assert_eq!(text(&screen), "a  def\n3");
+        screen.feed(b"\x1b[3X");
+        assert_eq!(text(&screen), "a   ef\n3");
+    }
+
+    #[test]
+    fn attributes() {
+        let mut screen = screen(3, 40);
+        screen.feed(b"\x1b[1;31mred\x1b[0m plain \x1b[38;2;1;2;3;48;5;200mrgb\x1b[4;94m");
+        assert_eq!(text(&screen), "red plain rgb");
+        assert_eq!(
+            screen.primary[0][0].attrs,
+            Attrs {
+                bold: true,
+                fg: Color::Indexed(1),
+                ..Attrs::default()
+            }
+        );
+        assert_eq!(screen.primary[0][3].attrs, Attrs::default());
+        assert_eq!(
+            screen.primary[0][10].attrs,
+            Attrs {
+                fg: Color::Rgb(1, 2, 3),
+                bg: Color::Indexed(200),
+                ..Attrs::default()
+            }
+        );
+        assert_snapshot_roundtrip(&screen);
+    }
+
+    #[test]
+    fn erase_uses_background() {
+        let mut screen = screen(3, 10);
+        screen.feed(b"\x1b[44m\x1b[2J");
+        assert_eq!(screen.primary[1][1].attrs.bg, Color::Indexed(4));
+        assert_snapshot_roundtrip(&screen);
+    }
+
+    #[test]
+    fn utf8() {
+        let mut screen = screen(3, 10);
+        let data = "grüße".as_bytes();
+        // Split in the middle of a character.
+        screen.feed(&data[..3]);
+        screen.feed(&data[3..]);
+        assert_eq!(text(&screen), "grüße");
+        assert_eq!(cursor(&screen), (0, 5));
+        screen.feed(b"\r\n\xffx");
+        assert_eq!(text(&screen), "grüße\n\u{fffd}x");
+        assert_snapshot_roundtrip(&screen);
+    }
+
+    #[test]
+    fn wide() {
+        let mut screen = screen(3, 5);
+        screen.feed("漢字x".as_bytes());
+        assert_eq!(cursor(&screen), (0, 4));
+        assert_eq!(text(&screen), "漢字x");
+        // Doesn't fit at the end of the line.
+        screen.feed("漢".as_bytes());
+        assert_eq!(text(&screen), "漢字x\n漢");
+        // Overwriting half of a wide character erases all of it.
+        screen.feed(b"\x1b[1;2Hy");
+        assert_eq!(text(&screen), " y字x\n漢");
+        assert_snapshot_roundtrip(&screen);
+    }
+
+    #[test]
+    fn alternate_screen() {
+        let mut screen = screen(5, 20);
+        screen.feed(b"$ vim\r\n");
+        screen.feed(b"\x1b[?1049h\x1b[Hediting\x1b[5H~ status");
+        assert!(screen.alternate_active);
+        assert_eq!(text(&screen), "editing\n\n\n\n~ status");
+        assert_snapshot_roundtrip(&screen);
+
+        screen.feed(b"\x1b[?1049l");
+        assert!(!screen.alternate_active);
+        assert_eq!(text(&screen), "$ vim");
+        assert_eq!(cursor(&screen), (1, 0));
+    }
+
+    #[test]
+    fn modes() {
+        let mut screen = screen(5, 20);
+        screen.feed(b"\x1b[?1h\x1b=\x1b[?2004h\x1b[?1000;1006h\x1b[?25l\x1b]2;my title\x07");
+        let want: BTreeSet<u16> = [1, 1000, 1006, 2004].iter().copied().collect();
+        assert_eq!(screen.modes, want);
+        assert!(screen.keypad_application);
+        assert!(!screen.cursor_visible);
+        assert_eq!(screen.title.as_deref(), Some("my title"));
+        assert_snapshot_roundtrip(&screen);
+
+        screen.feed(b"\x1b[?1000l\x1b>\x1b]0;other\x1b\\");
+        assert!(!screen.modes.contains(&1000));
+        assert!(!screen.keypad_application);
+        assert_eq!(screen.title.as_deref(), Some("other"));
+    }
+
+    #[test]
+    fn snapshot_after_replay() {
+        let mut screen = screen(5, 20);
+        screen.feed(b"$ ls\r\nfile\r\n$ ");
+        // What a replay of older output may have left behind.
+        let replay =
+            b"\x1b[?1049h\x1b[2;3r\x1b[?2004h\x1b[?1000h\x1b[?7l\x1b[?25l\x1b=\x1b[1;31mjunk";
+        assert_snapshot_after(&screen, replay);
+    }
+
+    #[test]
+    fn bracketed_paste() {
+        let mut screen = screen(5, 20);
+        assert!(!screen.bracketed_paste());
+        screen.feed(b"$ \x1b[?2004h");
+        assert!(screen.bracketed_paste());
+        screen.feed(b"\x1b[?2004l\r\n");
+        assert!(!screen.bracketed_paste());
+    }
+
+    #[test]
+    fn bracketed_paste_split() {
+        let mut screen = screen(5, 20);
+        screen.feed(b"prompt\x1b[?20");
+        assert!(!screen.bracketed_paste());
+        screen.feed(b"04h");
+        assert!(screen.bracketed_paste());
+    }
+
+    #[test]
+    fn bracketed_paste_multiple_params() {
+        let mut screen = screen(5, 20);
+        screen.feed(b"\x1b[?1049;2004h");
+        assert!(screen.bracketed_paste());
+    }
+
+    #[test]
+    fn bracketed_paste_not_private() {
+        let mut screen = screen(5, 20);
+        // ANSI mode 2004, not the DEC private mode.
+        screen.feed(b"\x1b[2004h");
+        assert!(!screen.bracketed_paste());
+    }
+
+    #[test]
+    fn bracketed_paste_reset() {
+        let mut screen = screen(5, 20);
+        screen.feed(b"\x1b[?2004h");
+        assert!(screen.bracketed_paste());
+        screen.feed(b"\x1bc");
+        assert!(!screen.bracketed_paste());
+    }
+
+    #[test]
+    fn ignored_sequences() {
+        let mut screen = screen(3, 20);
+        screen.feed(b"a\x1b(Bb\x1bP+q544e\x1b\\c\x1b[>0cd\x1b[38:2::1:2:3me");
+        assert_eq!(text(&screen), "abcde");
+        assert_eq!(screen.cursor.attrs, Attrs::default());
+    }
+
+    #[test]
+    fn reset() {
+        let mut screen = screen(3, 20);
+        screen.feed(b"\x1b[?1049h\x1b[31mhello\x1b[?2004h\x1bc");
+        assert!(!screen.alternate_active);
+        assert_eq!(text(&screen), "");
+        assert!(screen.modes.is_empty());
+        assert_eq!(screen.cursor.attrs, Attrs::default());
+    }
+
+    #[test]
+    fn resize() {
+        let mut screen = screen(4, 10);
+        screen.feed(b"1\r\n2\r\n3\r\n4");
+        screen.resize(WindowSize {
+            rows: 2,
+            columns: 5,
+        });
+        assert_eq!(text(&screen), "3\n4");
+        assert_eq!(cursor(&screen), (1, 1));
+        screen.resize(WindowSize {
+            rows: 3,
+            columns: 8,
+        });
+        assert_eq!(text(&screen), "3\n4");
+        screen.feed(b"\r\n12345678");
+        assert_eq!(text(&screen), "3\n4\n12345678");
+        assert_snapshot_roundtrip(&screen);
+    }
+}
@@ -0,0 +1,105 @@
+//! Remember recent PTY output, for replaying to new clients.
+
+use std::collections::VecDeque;
+
+/// Ring buffer holding the most recent PTY output.
+pub(super) struct Scrollback {
+    capacity: usize,
+    buf: VecDeque<u8>,
+    /// Older output has been discarded.
+    truncated: bool,
+}
+
+impl Scrollback {
+    pub(super) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            buf: VecDeque::with_capacity(capacity),
+            truncated: false,
+        }
+    }
+
+    pub(super) fn push(&mut self, data: &[u8]) {
+        if self.capacity == 0 {
+            return;
+        }
+        let data = if data.len() > self.capacity {
+            self.truncated = true;
+            &data[data.len() - self.capacity..]
+        } else {
+            data
+        };
+        let overflow = (self.buf.len() + data.len()).saturating_sub(self.capacity);
+        if overflow > 0 {
+            self.truncated = true;
+            self.buf.drain(..overflow);
+        }
+        self.buf.extend(data);
+    }
+
+    /// Output to replay to a new client.
+    ///
+    /// If older output has been discarded, the result starts at a line boundary, to avoid replaying half of a control sequence or character.
+    pub(super) fn contents(&self) -> Vec<u8> {
+        let start = if self.truncated {
+            match self.buf.iter().position(|b| *b == b'\n') {
+                Some(idx) => idx + 1,
+                None => 0,
+            }
+        } else {
+            0
+        };
+        self.buf.iter().skip(start).copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Scrollback;
+
+    #[test]
+    fn empty() {
+        let scrollback = Scrollback::new(100);
+        assert_eq!(scrollback.contents(), b"");
+    }
+
+    #[test]
+    fn simple() {
+        let mut scrollback = Scrollback::new(100);
+        scrollback.push(b"hello, ");
+        scrollback.push(b"world\r\n");
+        assert_eq!(scrollback.contents(), b"hello, world\r\n");
+    }
+
+    #[test]
+    fn disabled() {
+        let mut scrollback = Scrollback::new(0);
+        scrollback.push(b"hello, world\r\n");
+        assert_eq!(scrollback.contents(), b"");
+    }
+
+    #[test]
+    fn wraparound() {
+        let mut scrollback = Scrollback::new(16);
+        scrollback.push(b"first\r\n");
+        scrollback.push(b"second\r\n");
+        scrollback.push(b"third\r\n");
+        // Only the end of the first line fits, skip it.
+        assert_eq!(scrollback.contents(), b"second\r\nthird\r\n");
+    }
+
+    #[test]
+    fn huge_push() {
+        let mut scrollback = Scrollback::new(8);
+        scrollback.push(b"0123456789\nabcdef");
+        assert_eq!(scrollback.contents(), b"abcdef");
+    }
+
+    #[test]
+    fn no_newline() {
+        let mut scrollback = Scrollback::new(8);
+        scrollback.push(b"0123456789abcdef");
+        // Better to show something than nothing.
+        assert_eq!(scrollback.contents(), b"89abcdef");
+    }
+}
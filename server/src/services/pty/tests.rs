@@ -4,11 +4,12 @@ use std::io::Read;
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
-use std::os::unix::net::UnixStream;
+use std::os::unix::net::{UnixDatagram, UnixStream};
 use thiserror::Error;
 
 use crate::ipc;
 use crate::ipc::seqpacket::SeqPacket;
+use crate::ipc::stream::Stream;
 use crate::ipc::IPC;
 use crate::proto::pty as p;
 use crate::pty_master::PtyMaster;
@@ -17,15 +18,11 @@ use crate::pty_master::PtyMaster;
 // use super as pty;
 use super::super::pty;
 
-#[test]
-fn init_then_eof() {
+/// Serve `pty_master`, acting as the sessions service in `client`.
+///
+/// The service is expected to end once `client` returns, dropping the connection.
+fn with_service(pty_master: PtyMaster, client: impl FnOnce(SeqPacket) + Send + 'static) {
     let (conn, server_socket) = SeqPacket::pair().expect("socketpair");
-    // TODO we're gonna need an actual PTY once more code is written
-    let (_fake_pty, fake_pty_master) = UnixStream::pair().expect("socketpair for fake_pty");
-    let fake_pty_master = {
-        let fd = fake_pty_master.into_raw_fd();
-        unsafe { PtyMaster::from_raw_fd(fd) }
-    };
     let server_task = std::thread::spawn(|| {
         let conn = SeqPacket::try_from(server_socket).unwrap();
         pty::serve(conn)
@@ -35,29 +32,70 @@ fn init_then_eof() {
             .expect("handshake as client");
         {
             let msg = p::Init {
-                _dummy: 0,
-                pty_master: fake_pty_master,
+                pty_master,
+                options: Default::default(),
             };
             conn.send_with_fds(&msg).expect("send Init");
         }
+        client(conn);
     });
     client_task.join().unwrap();
     let result = server_task.join().unwrap();
     match result {
-        Err(pty::Error::Receive(ipc::ReceiveError::Deserialize(error))) => match *error {
-            bincode::ErrorKind::Io(error) => {
-                assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
-            }
-            _ => panic!("expected eof, got inner error {:?}", error),
-        },
+        Err(pty::Error::Receive(ipc::ReceiveError::End)) => (),
         _ => {
             panic!("expected eof, got {:?}", result);
         }
     };
 }
 
+/// Attach a new pty_user client to the service, and handshake as it.
+fn attach(conn: &SeqPacket) -> SeqPacket {
+    let (user_conn, user_server_socket) = SeqPacket::pair().expect("socketpair");
+    {
+        let msg = p::Request::NewClient {
+            fd: user_server_socket,
+        };
+        conn.send_with_fds(&msg).expect("send Request");
+    }
+    {
+        use crate::proto::pty::user as p;
+        ipc::handshake::handshake_as_client(&user_conn, p::CLIENT_INTENT, p::SERVER_INTENT)
+            .expect("handshake as pty_user client");
+    }
+    user_conn
+}
+
+/// Receive output until it contains `want`.
+///
+/// Output also contains echoed input, so look for it in all of the output.
+fn receive_output_until(user_conn: &impl IPC, want: &str) {
+    use crate::proto::pty::user as p;
+
+    let mut output: Vec<u8> = Vec::new();
+    while !String::from_utf8_lossy(&output).contains(want) {
+        let message: p::Output = user_conn.receive_with_fds().expect("receive output");
+        match message {
+            p::Output::SessionOutput(b) => output.extend_from_slice(&b),
+            p::Output::SlowConsumer(policy) => panic!("slow consumer: {:?}", policy),
+            p::Output::SessionEnded { .. } => panic!("session ended early"),
+        }
+    }
+}
+
+#[test]
+fn init_then_eof() {
+    // TODO we're gonna need an actual PTY once more code is written
+    let (_fake_pty, fake_pty_master) = UnixStream::pair().expect("socketpair for fake_pty");
+    let fake_pty_master = {
+        let fd = fake_pty_master.into_raw_fd();
+        unsafe { PtyMaster::from_raw_fd(fd) }
+    };
+    with_service(fake_pty_master, |_conn| ());
+}
+
 #[derive(Error, Debug)]
-enum MakePtyError {
+pub(crate) enum MakePtyError {
     #[error("posix_openpt: {0}")]
     Open(#[source] std::io::Error),
 
@@ -81,7 +119,10 @@ fn path_from_nul_terminated_buf(buf: &[u8]) -> Option<&std::path::Path> {
     Some(std::path::Path::new(s))
 }
 
-fn make_pty() -> Result<(PtyMaster, std::fs::File), MakePtyError> {
+/// Open a PTY, returning both ends.
+///
+/// Also used by the sessions service tests.
+pub(crate) fn make_pty() -> Result<(PtyMaster, std::fs::File), MakePtyError> {
     let ret = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY) };
     if ret < 0 {
         return Err(MakePtyError::Open(std::io::Error::last_os_error()));
@@ -119,63 +160,266 @@ fn make_pty() -> Result<(PtyMaster, std::fs::File), MakePtyError> {
 
 #[test]
 fn pty_io() {
-    let (conn, server_socket) = SeqPacket::pair().expect("socketpair");
     let (pty_master, mut pty_child) = make_pty().expect("make_pty");
-    let (user_conn, user_server_socket) = SeqPacket::pair().expect("socketpair");
+    with_service(pty_master, move |conn| {
+        let user_conn = attach(&conn);
 
-    let server_task = std::thread::spawn(|| {
-        let conn = SeqPacket::try_from(server_socket).unwrap();
-        pty::serve(conn)
+        const GREETING: &[u8] = b"hello, world\n";
+        {
+            use crate::proto::pty::user as p;
+            let msg = p::Input::KeyboardInput(Vec::from(GREETING));
+            user_conn.send_with_fds(&msg).expect("send KeyboardInput");
+        }
+
+        // Read our input from the PTY child.
+        {
+            let mut buf = [0u8; GREETING.len()];
+            pty_child.read_exact(&mut buf).expect("PTY child read");
+            assert_eq!(&buf, GREETING);
+        }
     });
-    let client_task = std::thread::spawn(move || {
-        ipc::handshake::handshake_as_client(&conn, p::CLIENT_INTENT, p::SERVER_INTENT)
-            .expect("handshake as client");
+}
+
+#[test]
+fn resize() {
+    let (pty_master, mut pty_child) = make_pty().expect("make_pty");
+    with_service(pty_master, move |conn| {
+        let user_conn = attach(&conn);
+
+        const GREETING: &[u8] = b"hello, world\n";
         {
-            let msg = p::Init {
-                _dummy: 0,
-                pty_master,
+            use crate::proto::pty::user as p;
+            let msg = p::Input::Resize {
+                rows: 42,
+                columns: 113,
             };
-            conn.send_with_fds(&msg).expect("send Init");
+            user_conn.send_with_fds(&msg).expect("send Resize");
+            // Input is processed in order, so once this shows up the resize has happened.
+            let msg = p::Input::KeyboardInput(Vec::from(GREETING));
+            user_conn.send_with_fds(&msg).expect("send KeyboardInput");
+        }
+
+        {
+            let mut buf = [0u8; GREETING.len()];
+            pty_child.read_exact(&mut buf).expect("PTY child read");
+            assert_eq!(&buf, GREETING);
         }
+
+        // The child side sees the same window size as the master side.
         {
-            let msg = p::Request::NewClient {
-                _dummy: 0,
-                fd: user_server_socket,
+            let mut winsize = libc::winsize {
+                ws_row: 0,
+                ws_col: 0,
+                ws_xpixel: 0,
+                ws_ypixel: 0,
             };
+            let ret = unsafe { libc::ioctl(pty_child.as_raw_fd(), libc::TIOCGWINSZ, &mut winsize) };
+            assert!(ret >= 0, "TIOCGWINSZ: {}", std::io::Error::last_os_error());
+            assert_eq!((winsize.ws_row, winsize.ws_col), (42, 113));
+        }
+    });
+}
+
+#[test]
+fn broadcast() {
+    use std::io::Write;
+
+    let (pty_master, mut pty_child) = make_pty().expect("make_pty");
+    with_service(pty_master, move |conn| {
+        let user_conns: Vec<SeqPacket> = [&b"one\n"[..], &b"two\n"[..]]
+            .iter()
+            .map(|greeting| {
+                let user_conn = attach(&conn);
+                {
+                    use crate::proto::pty::user as p;
+                    let msg = p::Input::KeyboardInput(Vec::from(*greeting));
+                    user_conn.send_with_fds(&msg).expect("send KeyboardInput");
+                }
+                // Seeing the input on the PTY child means the client is set up to receive output.
+                {
+                    let mut buf = vec![0u8; greeting.len()];
+                    pty_child.read_exact(&mut buf).expect("PTY child read");
+                    assert_eq!(&buf, greeting);
+                }
+                user_conn
+            })
+            .collect();
+
+        pty_child
+            .write_all(b"hello, everyone")
+            .expect("PTY child write");
+        for user_conn in user_conns {
+            receive_output_until(&user_conn, "hello, everyone");
+        }
+    });
+}
+
+#[test]
+fn stream_client() {
+    use std::io::Write;
+
+    let (pty_master, mut pty_child) = make_pty().expect("make_pty");
+    with_service(pty_master, move |conn| {
+        let (user_socket, user_server_socket) = UnixStream::pair().expect("socketpair");
+        {
+            let fd = unsafe { UnixDatagram::from_raw_fd(user_server_socket.into_raw_fd()) };
+            let msg = p::Request::NewClient { fd };
             conn.send_with_fds(&msg).expect("send Request");
         }
 
-        // Now acting as pty_user client
-        const GREETING: &[u8] = b"hello, world\n";
+        // Now acting as pty_user client, over a SOCK_STREAM.
         {
             use crate::proto::pty::user as p;
 
+            let user_conn = Stream::new(user_socket, p::stream_limits());
             ipc::handshake::handshake_as_client(&user_conn, p::CLIENT_INTENT, p::SERVER_INTENT)
                 .expect("handshake as pty_user client");
+            const GREETING: &[u8] = b"hello, world\n";
             {
                 let msg = p::Input::KeyboardInput(Vec::from(GREETING));
                 user_conn.send_with_fds(&msg).expect("send KeyboardInput");
+                user_conn.flush().expect("flush");
+            }
+            {
+                let mut buf = [0u8; GREETING.len()];
+                pty_child.read_exact(&mut buf).expect("PTY child read");
+                assert_eq!(&buf, GREETING);
             }
-        }
 
-        // Read our input from the PTY child.
-        {
-            let mut buf = [0u8; GREETING.len()];
-            pty_child.read_exact(&mut buf).expect("PTY child read");
-            assert_eq!(&buf, GREETING);
+            pty_child
+                .write_all(b"hello, stream")
+                .expect("PTY child write");
+            receive_output_until(&user_conn, "hello, stream");
         }
     });
-    client_task.join().unwrap();
-    let result = server_task.join().unwrap();
-    match result {
-        Err(pty::Error::Receive(ipc::ReceiveError::Deserialize(error))) => match *error {
-            bincode::ErrorKind::Io(error) => {
-                assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn client_events() {
+    let (pty_master, pty_child) = make_pty().expect("make_pty");
+    with_service(pty_master, move |conn| {
+        let user_conn = attach(&conn);
+
+        let event: p::Event = conn.receive_with_fds().expect("receive event");
+        assert!(
+            matches!(event, p::Event::Clients { count: 1 }),
+            "unexpected event: {:?}",
+            event
+        );
+
+        // Client goes away.
+        drop(user_conn);
+        let event: p::Event = conn.receive_with_fds().expect("receive event");
+        assert!(
+            matches!(event, p::Event::Clients { count: 0 }),
+            "unexpected event: {:?}",
+            event
+        );
+
+        // Session ends.
+        drop(pty_child);
+        let event: p::Event = conn.receive_with_fds().expect("receive event");
+        assert!(
+            matches!(event, p::Event::Closed),
+            "unexpected event: {:?}",
+            event
+        );
+    });
+}
+
+#[test]
+fn session_id() {
+    use std::os::unix::process::CommandExt;
+
+    let (pty_master, pty_child) = make_pty().expect("make_pty");
+    let child_fd = pty_child.as_raw_fd();
+    let mut command = std::process::Command::new("sleep");
+    command.arg("60");
+    unsafe {
+        command.pre_exec(move || {
+            if libc::setsid() < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            if libc::ioctl(child_fd, libc::TIOCSCTTY, 0) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn().expect("spawn");
+    let result = pty_master.session_id();
+    child.kill().expect("kill");
+    child.wait().expect("wait");
+    assert_eq!(result.expect("session_id"), child.id() as libc::pid_t);
+}
+
+#[test]
+fn bad_client_fd() {
+    use std::io::Write;
+
+    let (pty_master, mut pty_child) = make_pty().expect("make_pty");
+    with_service(pty_master, move |conn| {
+        // Neither a SOCK_SEQPACKET nor a SOCK_STREAM.
+        let (datagram, _other) = UnixDatagram::pair().expect("socketpair");
+        conn.send_with_fds(&p::Request::NewClient { fd: datagram })
+            .expect("send Request");
+        // Not a socket at all.
+        let file = std::fs::File::open("/dev/null").expect("open /dev/null");
+        let fd = unsafe { UnixDatagram::from_raw_fd(file.into_raw_fd()) };
+        conn.send_with_fds(&p::Request::NewClient { fd })
+            .expect("send Request");
+
+        // The service dropped those, and still serves new clients.
+        let user_conn = attach(&conn);
+        pty_child.write_all(b"still here").expect("PTY child write");
+        receive_output_until(&user_conn, "still here");
+    });
+}
+
+#[test]
+fn attach_after_end() {
+    use crate::proto::pty::user as pu;
+    use crate::proto::ExitStatus;
+
+    fn receive_until_end(user_conn: &SeqPacket) -> Option<ExitStatus> {
+        loop {
+            let message: pu::Output = user_conn.receive_with_fds().expect("receive output");
+            match message {
+                pu::Output::SessionOutput(_) => continue,
+                pu::Output::SlowConsumer(policy) => panic!("slow consumer: {:?}", policy),
+                pu::Output::SessionEnded { exit_status } => return exit_status,
             }
-            _ => panic!("expected eof, got inner error {:?}", error),
-        },
-        _ => {
-            panic!("expected eof, got {:?}", result);
         }
-    };
+    }
+
+    let (pty_master, pty_child) = make_pty().expect("make_pty");
+    with_service(pty_master, move |conn| {
+        let early = attach(&conn);
+        drop(pty_child);
+        loop {
+            let event: p::Event = conn.receive_with_fds().expect("receive event");
+            if let p::Event::Closed = event {
+                break;
+            }
+        }
+        let msg = p::Request::SessionEnded {
+            exit_status: Some(ExitStatus::Exited(3)),
+        };
+        conn.send_with_fds(&msg).expect("send SessionEnded");
+        assert_eq!(receive_until_end(&early), Some(ExitStatus::Exited(3)));
+
+        // The session is over by now, but a new client is still told how it ended.
+        let late = attach(&conn);
+        let message: pu::Output = late.receive_with_fds().expect("receive output");
+        assert!(
+            matches!(
+                message,
+                pu::Output::SessionEnded {
+                    exit_status: Some(ExitStatus::Exited(3))
+                }
+            ),
+            "unexpected message: {:?}",
+            message
+        );
+    });
 }
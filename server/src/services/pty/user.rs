@@ -1,11 +1,15 @@
-use std::io::{Read, Write};
+use scopeguard::guard;
 use std::sync::Arc;
 use thiserror::Error;
 
 use crate::ipc;
 use crate::ipc::handshake;
 use crate::proto::pty::user as p;
-use crate::pty_master::PtyMaster;
+use crate::pty_master::WindowSize;
+
+use super::broadcast::{Item, Subscription};
+use super::paste;
+use super::Session;
 
 #[derive(Error, Debug)]
 pub(super) enum ServeUserError {
@@ -23,72 +27,115 @@ pub(super) enum ServeUserError {
 
     #[error("PTY I/O error: {0}")]
     PtyIo(#[source] std::io::Error),
+
+    #[error("error setting PTY window size: {0}")]
+    PtyResize(#[source] std::io::Error),
+}
+
+fn handle_input(session: &Session, conn: &impl ipc::IPC) -> Result<(), ServeUserError> {
+    loop {
+        let message: p::Input = match conn.receive_with_fds() {
+            Ok(message) => message,
+            // Client went away, or we shut down the socket because there's no more output.
+            Err(ipc::ReceiveError::End) => return Ok(()),
+            Err(error) => return Err(ServeUserError::Receive(error)),
+        };
+        match &message {
+            p::Input::KeyboardInput(input) => {
+                // TODO this currently blocks further input processing.
+                // Backpressure is good, but we probably need to handle resizes and control-C even when the process in the session is not consuming standard input.
+                session.write_input(input).map_err(ServeUserError::PtyIo)?;
+            }
+            p::Input::PasteInput(input) => {
+                let bracketed = session.output.bracketed_paste();
+                let input = paste::prepare(input, bracketed);
+                session.write_input(&input).map_err(ServeUserError::PtyIo)?;
+            }
+            p::Input::Resize { rows, columns } => {
+                let size = WindowSize {
+                    rows: *rows,
+                    columns: *columns,
+                };
+                session
+                    .set_window_size(size)
+                    .map_err(ServeUserError::PtyResize)?;
+            }
+        };
+    }
+}
+
+fn send_output(subscription: &Subscription, conn: &impl ipc::IPC) -> Result<(), ServeUserError> {
+    loop {
+        let item = match subscription.try_next() {
+            Some(item) => item,
+            None => {
+                // Caught up, let the client see what it has so far.
+                conn.flush().map_err(ServeUserError::Send)?;
+                match subscription.next() {
+                    Some(item) => item,
+                    None => break,
+                }
+            }
+        };
+        let message = match item {
+            Item::Output(chunk) => p::Output::SessionOutput(chunk.to_vec()),
+            Item::SlowConsumer(policy) => p::Output::SlowConsumer(policy),
+            Item::SessionEnded(exit_status) => p::Output::SessionEnded { exit_status },
+        };
+        conn.send_with_fds(&message).map_err(ServeUserError::Send)?;
+    }
+    Ok(())
 }
 
 pub(super) fn serve_user(
-    pty: Arc<PtyMaster>,
+    session: Arc<Session>,
     conn: impl ipc::IPC + Sync + Send + 'static,
 ) -> Result<(), ServeUserError> {
     handshake::handshake_as_server(&conn, p::CLIENT_INTENT, p::SERVER_INTENT)
         .map_err(ServeUserError::Handshake)?;
 
+    let subscription = match session.output.subscribe() {
+        Some(subscription) => subscription,
+        None => {
+            // The PTY is already gone, and how the session ended is already known.
+            let exit_status = session.wait_exit_status();
+            conn.send_with_fds(&p::Output::SessionEnded { exit_status })
+                .map_err(ServeUserError::Send)?;
+            conn.flush().map_err(ServeUserError::Send)?;
+            conn.shutdown(std::net::Shutdown::Read)
+                .map_err(ServeUserError::SocketShutdown)?;
+            return Ok(());
+        }
+    };
+    let client_id = subscription.id();
+    session.report_clients();
+
     let conn = Arc::new(conn);
 
     let input: std::thread::JoinHandle<Result<(), ServeUserError>> = std::thread::spawn({
-        let pty = pty.clone();
+        let session = session.clone();
         let conn = conn.clone();
         move || {
-            loop {
-                let message: p::Input = conn.receive_with_fds().map_err(ServeUserError::Receive)?;
-                match &message {
-                    p::Input::KeyboardInput(input) => {
-                        // TODO this currently blocks further input processing.
-                        // Backpressure is good, but we probably need to handle resizes and control-C even when the process in the session is not consuming standard input.
-                        (&*pty).write_all(input).map_err(ServeUserError::PtyIo)?;
-                    }
-                };
-            }
+            // Once the client stops talking to us, stop sending it output too.
+            let session = guard(session, |session| session.output.unsubscribe(client_id));
+            handle_input(&session, conn.as_ref())
         }
     });
 
-    let output: std::thread::JoinHandle<Result<(), ServeUserError>> = std::thread::spawn({
-        let conn = conn.clone();
-        move || loop {
-            let mut buf = vec![0; 1024];
-            let n = {
-                let result = (&*pty).read(&mut buf);
-                // Map EIO to a regular return.
-                // All PTY interactions end at EIO, there's no EOF.
-                if let Err(error) = &result {
-                    if let Some(errno) = error.raw_os_error() {
-                        if errno == libc::EIO {
-                            return Ok(());
-                        }
-                    }
-                }
-                result.map_err(ServeUserError::PtyIo)?
-            };
-            buf.truncate(n);
-            let message = p::Output::SessionOutput(buf);
-            conn.send_with_fds(&message).map_err(ServeUserError::Send)?;
-        }
-    });
+    let output_result = send_output(&subscription, conn.as_ref());
+    println!("output to client done: {:?}", output_result);
+    session.output.unsubscribe(client_id);
+    session.report_clients();
+    // Shutdown the IPC socket so the reading thread will exit.
+    //
+    // This will cause sending IPC clients to see EPIPE, but they'll just have to handle that.
+    // Either the PTY is gone and there's nothing this service can do for them anymore, or we couldn't send to them.
+    let how = std::net::Shutdown::Read;
+    conn.shutdown(how).map_err(ServeUserError::SocketShutdown)?;
 
-    match output.join() {
-        Err(panicked) => std::panic::resume_unwind(panicked),
-        Ok(result) => {
-            println!("pty closed: {:?}", result);
-            // Shutdown the IPC socket so the reading thread will exit.
-            //
-            // This will cause sending IPC clients to see EPIPE, but they'll just have to handle that.
-            // The PTY is gone, there's nothing this service can do for them anymore.
-            let how = std::net::Shutdown::Read;
-            conn.shutdown(how).map_err(ServeUserError::SocketShutdown)?;
-        }
-    };
     match input.join() {
         Err(panicked) => std::panic::resume_unwind(panicked),
         Ok(result) => result?,
     };
-    Ok(())
+    output_result
 }
@@ -0,0 +1,138 @@
+//! Relay between a non-interactive command and its client.
+//!
+//! Standard output and error are read in threads of their own, so a command writing a lot to one while we're blocked on the other can't stall.
+//! Input from the client is written to standard input in another thread.
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::io::FromRawFd;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::sync::Arc;
+
+use crate::ipc;
+use crate::ipc::seqpacket::SeqPacket;
+use crate::ipc::IPC;
+use crate::proto;
+use crate::proto::command as p;
+
+use super::CommandHandle;
+
+/// Create a pipe, returning the read and write ends.
+pub(super) fn pipe() -> Result<(File, File), std::io::Error> {
+    let mut fds: [libc::c_int; 2] = [0; 2];
+    let ret = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let read = unsafe { File::from_raw_fd(fds[0]) };
+    let write = unsafe { File::from_raw_fd(fds[1]) };
+    Ok((read, write))
+}
+
+pub(super) fn exit_status(status: ExitStatus) -> Option<proto::ExitStatus> {
+    if let Some(code) = status.code() {
+        return Some(proto::ExitStatus::Exited(code));
+    }
+    status.signal().map(proto::ExitStatus::Killed)
+}
+
+/// Send everything read from `pipe` to the client, until end of file or the client goes away.
+fn relay_output(client: &SeqPacket, mut pipe: File, wrap: fn(Vec<u8>) -> p::Output) {
+    let mut buf = vec![0u8; p::MAX_DATA];
+    loop {
+        let n = match pipe.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(error) if error.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(error) => {
+                // TODO Proper error logging.
+                eprintln!("error reading command output: {}", error);
+                break;
+            }
+        };
+        if client.send_with_fds(&wrap(buf[..n].to_vec())).is_err() {
+            // Closing the pipe lets the command know nobody is listening.
+            break;
+        }
+    }
+}
+
+/// Write input from the client to `stdin`, until the client is done.
+fn relay_input(client: &SeqPacket, stdin: File) {
+    let mut stdin = Some(stdin);
+    loop {
+        let input: p::Input = match client.receive_with_fds() {
+            Ok(input) => input,
+            Err(ipc::ReceiveError::End) => break,
+            Err(error) => {
+                // TODO Proper error logging.
+                eprintln!("error receiving command input: {}", error);
+                break;
+            }
+        };
+        match input {
+            p::Input::Stdin(data) => {
+                if let Some(pipe) = &mut stdin {
+                    if pipe.write_all(&data).is_err() {
+                        // The command is not reading input anymore; discard the rest.
+                        stdin = None;
+                    }
+                }
+            }
+            p::Input::StdinEnd => stdin = None,
+        }
+    }
+}
+
+/// Relay between the client and the command until the command ends, then report how it ended.
+pub(super) fn relay<H: CommandHandle>(
+    client: SeqPacket,
+    stdin: File,
+    stdout: File,
+    stderr: File,
+    command: H,
+) {
+    if let Err(error) =
+        ipc::handshake::handshake_as_server(&client, p::CLIENT_INTENT, p::SERVER_INTENT)
+    {
+        // TODO Proper error logging.
+        eprintln!("command client handshake error: {}", error);
+        drop((stdin, stdout, stderr));
+        if let Err(error) = command.wait() {
+            eprintln!("error waiting for command: {}", error);
+        }
+        return;
+    }
+
+    let client = Arc::new(client);
+    let input = std::thread::spawn({
+        let client = client.clone();
+        move || relay_input(&client, stdin)
+    });
+    let stdout = std::thread::spawn({
+        let client = client.clone();
+        move || relay_output(&client, stdout, p::Output::Stdout)
+    });
+    let stderr = std::thread::spawn({
+        let client = client.clone();
+        move || relay_output(&client, stderr, p::Output::Stderr)
+    });
+
+    let status = match command.wait() {
+        Ok(status) => exit_status(status),
+        Err(error) => {
+            // TODO Proper error logging.
+            eprintln!("error waiting for command: {}", error);
+            None
+        }
+    };
+    // Anything the command wrote before exiting is still in the pipes.
+    // Processes it left running can hold them open for longer, like with SSH.
+    let _ = stdout.join();
+    let _ = stderr.join();
+    let _ = client.send_with_fds(&p::Output::Finished(status));
+    // Wake up the input thread, the client may not have anything more to say.
+    let _ = client.shutdown(std::net::Shutdown::Read);
+    let _ = input.join();
+}
@@ -0,0 +1,266 @@
+//! Keep sessions alive over restarts of `tere-sessions`, by storing their FDs in systemd.
+//!
+//! Every running session stores three FDs, named `s_<session id>_<kind>`:
+//! the PTY master, the connection to its `tere-pty@` instance, and a memfd holding the session metadata.
+//! On startup, they come back to us via socket activation.
+
+use bincode::Options;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{Seek, SeekFrom};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+use std::os::unix::net::UnixDatagram;
+use std::sync::Arc;
+use thiserror::Error;
+
+use crate::ipc::ownedfd::OwnedFd;
+use crate::ipc::seqpacket::SeqPacket;
+use crate::proto::sessions as p;
+use crate::pty_master::PtyMaster;
+use crate::sd_notify;
+
+const PREFIX: &str = "s_";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum FdKind {
+    PtyMaster,
+    PtyService,
+    Info,
+}
+
+impl FdKind {
+    const ALL: [FdKind; 3] = [FdKind::PtyMaster, FdKind::PtyService, FdKind::Info];
+
+    fn suffix(&self) -> &'static str {
+        match self {
+            FdKind::PtyMaster => "pty",
+            FdKind::PtyService => "conn",
+            FdKind::Info => "info",
+        }
+    }
+}
+
+fn fd_name(id: &p::SessionId, kind: FdKind) -> String {
+    format!("{}{}_{}", PREFIX, id, kind.suffix())
+}
+
+/// Recognize names of FDs we have stored.
+pub(super) fn parse_fd_name(name: &str) -> Option<(p::SessionId, FdKind)> {
+    let rest = name.strip_prefix(PREFIX)?;
+    let (id, suffix) = rest.split_at(rest.find('_')?);
+    let kind = FdKind::ALL
+        .iter()
+        .copied()
+        .find(|kind| &suffix[1..] == kind.suffix())?;
+    let id = id.parse().ok()?;
+    Some((id, kind))
+}
+
+#[derive(Error, Debug)]
+pub(super) enum SaveError {
+    #[error("cannot create memfd: {0}")]
+    Memfd(#[source] std::io::Error),
+
+    #[error("cannot write session info: {0}")]
+    Encode(#[source] bincode::Error),
+
+    #[error("cannot store FDs: {0}")]
+    Notify(#[source] sd_notify::Error),
+}
+
+#[derive(Error, Debug)]
+pub(super) enum RestoreError {
+    #[error("missing FD: {0:?}")]
+    Missing(FdKind),
+
+    #[error("cannot read session info: {0}")]
+    Decode(#[source] bincode::Error),
+
+    #[error("session info is for a different session: {0}")]
+    WrongId(p::SessionId),
+
+    #[error("not a SOCK_SEQPACKET")]
+    NotSeqPacket,
+}
+
+fn memfd_create(name: &str) -> Result<File, std::io::Error> {
+    let name = CString::new(name).map_err(|_| std::io::ErrorKind::InvalidInput)?;
+    // Missing from the libc crate for glibc targets.
+    let ret = unsafe { libc::syscall(libc::SYS_memfd_create, name.as_ptr(), libc::MFD_CLOEXEC) };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let file = unsafe { File::from_raw_fd(ret as libc::c_int) };
+    Ok(file)
+}
+
+fn write_info(info: &p::SessionInfo) -> Result<File, SaveError> {
+    let file = memfd_create("tere-session-info").map_err(SaveError::Memfd)?;
+    bincode::DefaultOptions::new()
+        .serialize_into(&file, info)
+        .map_err(SaveError::Encode)?;
+    Ok(file)
+}
+
+fn read_info(mut file: File) -> Result<p::SessionInfo, bincode::Error> {
+    // The file offset is shared with the FD we wrote with.
+    file.seek(SeekFrom::Start(0))?;
+    bincode::DefaultOptions::new().deserialize_from(&file)
+}
+
+pub(super) struct FdStore {
+    notifier: Arc<sd_notify::Notifier>,
+}
+
+impl FdStore {
+    pub(super) fn new(notifier: Arc<sd_notify::Notifier>) -> Self {
+        Self { notifier }
+    }
+
+    /// Store a newly created session.
+    pub(super) fn save(
+        &self,
+        pty_master: &PtyMaster,
+        pty_service_conn: &SeqPacket,
+        info: &p::SessionInfo,
+    ) -> Result<(), SaveError> {
+        let info_file = write_info(info)?;
+        for kind in FdKind::ALL.iter().copied() {
+            let fd = match kind {
+                FdKind::PtyMaster => pty_master.as_raw_fd(),
+                FdKind::PtyService => pty_service_conn.as_raw_fd(),
+                FdKind::Info => info_file.as_raw_fd(),
+            };
+            self.notifier
+                .fd_store(&fd_name(&info.id, kind), &[fd])
+                .map_err(SaveError::Notify)?;
+        }
+        Ok(())
+    }
+
+    /// Forget a session, letting the PTY service see the end of our connection.
+    pub(super) fn remove(&self, id: &p::SessionId) -> Result<(), sd_notify::Error> {
+        for kind in FdKind::ALL.iter().copied() {
+            self.notifier.fd_store_remove(&fd_name(id, kind))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Found {
+    pty_master: Option<OwnedFd>,
+    pty_service: Option<OwnedFd>,
+    info: Option<OwnedFd>,
+}
+
+impl Found {
+    fn restore(self, id: &p::SessionId) -> Result<Restored, RestoreError> {
+        let pty_master = self
+            .pty_master
+            .ok_or(RestoreError::Missing(FdKind::PtyMaster))?;
+        let pty_service = self
+            .pty_service
+            .ok_or(RestoreError::Missing(FdKind::PtyService))?;
+        let info = self.info.ok_or(RestoreError::Missing(FdKind::Info))?;
+
+        let info = read_info(File::from(info)).map_err(RestoreError::Decode)?;
+        if info.id != *id {
+            return Err(RestoreError::WrongId(info.id));
+        }
+        let pty_master = unsafe { PtyMaster::from_raw_fd(pty_master.into_raw_fd()) };
+        let socket = unsafe { UnixDatagram::from_raw_fd(pty_service.into_raw_fd()) };
+        let pty_service_conn =
+            SeqPacket::try_from(socket).map_err(|_| RestoreError::NotSeqPacket)?;
+        Ok(Restored {
+            pty_master,
+            pty_service_conn,
+            info,
+        })
+    }
+}
+
+/// A session that was running before we restarted.
+pub(super) struct Restored {
+    pub(super) pty_master: PtyMaster,
+    pub(super) pty_service_conn: SeqPacket,
+    pub(super) info: p::SessionInfo,
+}
+
+/// Collect stored FDs passed to us at startup.
+#[derive(Default)]
+pub(super) struct Restore {
+    found: HashMap<p::SessionId, Found>,
+}
+
+impl Restore {
+    pub(super) fn add(&mut self, id: p::SessionId, kind: FdKind, fd: OwnedFd) {
+        let found = self.found.entry(id).or_default();
+        let slot = match kind {
+            FdKind::PtyMaster => &mut found.pty_master,
+            FdKind::PtyService => &mut found.pty_service,
+            FdKind::Info => &mut found.info,
+        };
+        // systemd doesn't pass the same FD twice, but a name could still repeat; we only need one.
+        slot.get_or_insert(fd);
+    }
+
+    /// Reassemble the sessions, dropping any that can't be used from the store.
+    pub(super) fn finish(self, fd_store: &FdStore) -> Vec<Restored> {
+        let mut sessions = Vec::new();
+        for (id, found) in self.found {
+            match found.restore(&id) {
+                Ok(restored) => sessions.push(restored),
+                Err(error) => {
+                    // TODO Proper error logging.
+                    eprintln!("cannot restore session {}: {}", id, error);
+                    if let Err(error) = fd_store.remove(&id) {
+                        eprintln!("error removing stored FDs of session {}: {}", id, error);
+                    }
+                }
+            }
+        }
+        sessions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+
+    #[test]
+    fn fd_name_roundtrip() {
+        let id = p::SessionId(rand::random());
+        for kind in FdKind::ALL.iter().copied() {
+            let name = fd_name(&id, kind);
+            assert_eq!(parse_fd_name(&name), Some((id, kind)));
+        }
+        assert_eq!(parse_fd_name("tere-sessions"), None);
+        assert_eq!(parse_fd_name(&format!("s_{}_bogus", id)), None);
+        assert_eq!(parse_fd_name("s_xyzzy_pty"), None);
+    }
+
+    #[test]
+    fn info_roundtrip() {
+        let info = p::SessionInfo {
+            id: p::SessionId(rand::random()),
+            machine: p::Machine::Container("xyzzy".parse().expect("container name")),
+            user: "testuser".to_string(),
+            program: None,
+            created: SystemTime::now(),
+            clients: 2,
+            state: p::SessionState::Running,
+        };
+        let file = write_info(&info).expect("write_info");
+        let got = read_info(file).expect("read_info");
+        assert_eq!(got.id, info.id);
+        assert_eq!(got.user, info.user);
+        assert_eq!(got.created, info.created);
+        assert_eq!(got.clients, info.clients);
+        assert!(matches!(got.machine, p::Machine::Container(name) if name.as_str() == "xyzzy"));
+    }
+}
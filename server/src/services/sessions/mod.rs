@@ -3,21 +3,32 @@ use std::convert::TryFrom;
 use std::ffi::OsStr;
 use std::os::unix::net::{UnixDatagram, UnixListener};
 use std::os::unix::prelude::{FromRawFd, IntoRawFd};
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::sync::mpsc;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
 use thiserror::Error;
 
 use crate::dbus_shell;
 use crate::dbus_shell::Dbus;
 use crate::ipc;
+use crate::ipc::ownedfd::OwnedFd;
 use crate::ipc::seqpacket::SeqPacket;
 use crate::ipc::IPC;
+use crate::local_shell;
 use crate::proto;
 use crate::proto::sessions as p;
 use crate::pty_master::PtyMaster;
+use crate::sd_notify;
 use crate::socket_activation;
 use crate::socket_activation::SocketActivation;
 
+mod command;
+mod fd_store;
+use fd_store::FdStore;
+
 #[derive(Error, Debug)]
 pub enum RunError {
     #[error("D-Bus connect error: {0}")]
@@ -28,13 +39,43 @@ pub enum RunError {
 
     #[error("socket for sessions service not found")]
     NoSocketForSessions,
+
+    #[error("service manager notification error: {0}")]
+    Notify(#[from] sd_notify::Error),
+
+    #[error("local shell error: {0}")]
+    LocalShell(#[from] local_shell::Error),
+
+    #[error("$XDG_RUNTIME_DIR is not set")]
+    NoRuntimeDir,
 }
 
+/// Serve sessions started via `systemd-machined`, as the system service.
 pub fn run() -> Result<(), RunError> {
+    let dbus = Dbus::new().map_err(RunError::Dbus)?;
+    let machine_watcher = dbus_shell::MachineWatcher::new().map_err(RunError::Dbus)?;
+    let pty_service = PtyServiceSocket::new(PTY_SERVICE_SOCKET);
+    run_with(dbus, Some(machine_watcher), pty_service)
+}
+
+/// Serve sessions started as child processes, as a user service of the user whose shells they are.
+pub fn run_local() -> Result<(), RunError> {
+    let local = local_shell::LocalShell::new().map_err(RunError::LocalShell)?;
+    let runtime_dir = std::env::var_os("XDG_RUNTIME_DIR").ok_or(RunError::NoRuntimeDir)?;
+    let pty_service = PtyServiceSocket::new(Path::new(&runtime_dir).join(PTY_SERVICE_SOCKET_USER));
+    run_with(local, None, pty_service)
+}
+
+fn run_with<S: ShellStarter>(
+    session_starter: S,
+    machine_watcher: Option<dbus_shell::MachineWatcher<'static>>,
+    pty_service: PtyServiceSocket,
+) -> Result<(), RunError> {
     let activation = SocketActivation::new();
     let sockets = activation.parse().map_err(RunError::SocketActivation)?;
     // TODO Iterator-based API is perhaps too annoying to consumers who care about specific names?
     let mut socket_sessions = None;
+    let mut restore = fd_store::Restore::default();
     for filedesc in sockets {
         if filedesc.name() == Some(OsStr::new("tere-sessions")) {
             // RUST-WART There's no SeqPacketListener.
@@ -43,41 +84,803 @@ pub fn run() -> Result<(), RunError> {
             socket_sessions.insert(fd);
             continue;
         }
-        // TODO handle `s_*` saved FDs.
+        // Sessions from before a restart.
+        if let Some((id, kind)) = filedesc
+            .name()
+            .and_then(OsStr::to_str)
+            .and_then(fd_store::parse_fd_name)
+        {
+            restore.add(id, kind, filedesc.take_fd());
+            continue;
+        }
+        // TODO Proper error logging.
+        eprintln!("ignoring unexpected FD: {:?}", filedesc.name());
+        let _: OwnedFd = filedesc.take_fd();
     }
     let listener = socket_sessions.ok_or(RunError::NoSocketForSessions)?;
-    let dbus = Dbus::new().map_err(RunError::Dbus)?;
-    serve(dbus, listener);
+    let notifier = Arc::new(sd_notify::Notifier::new()?);
+    let fd_store = Arc::new(FdStore::new(notifier.clone()));
+    let sessions: Arc<Sessions> = Arc::new(Mutex::new(HashMap::new()));
+    let restored_sessions = restore.finish(&fd_store);
+    let num_restored = restored_sessions.len();
+    for restored in restored_sessions {
+        let fd_store::Restored {
+            pty_master,
+            pty_service_conn,
+            info,
+        } = restored;
+        let session_id = info.id;
+        println!("restored session_id: {}", session_id);
+        let pty_conn = Arc::new(pty_service_conn);
+        // The client count is stale until the PTY service tells us otherwise.
+        let session_entry = Arc::new(Mutex::new(Session::Ready {
+            pty_master,
+            pty_service_conn: pty_conn.clone(),
+            info,
+        }));
+        sessions
+            .lock()
+            .expect("internal: sessions map mutex poison")
+            .insert(session_id, session_entry.clone());
+        // The shell is not ours to wait for anymore.
+        let (_, exit_status) = mpsc::channel();
+        spawn_monitor(
+            fd_store.clone(),
+            session_id,
+            session_entry,
+            pty_conn,
+            exit_status,
+        );
+    }
+    if let Some(machine_watcher) = machine_watcher {
+        std::thread::spawn({
+            let sessions = sessions.clone();
+            let fd_store = fd_store.clone();
+            move || {
+                let result = machine_watcher.run(move |name| {
+                    exit_container_sessions(&fd_store, &sessions, name);
+                });
+                // TODO Proper error logging.
+                eprintln!("error watching for removed machines: {:?}", result);
+            }
+        });
+    }
+    if let Some(interval) = sd_notify::watchdog_interval() {
+        spawn_watchdog(notifier.clone(), sessions.clone(), interval);
+    }
+    notifier.status(&format!("restored {} sessions", num_restored))?;
+    notifier.ready()?;
+    let service = Service {
+        session_starter,
+        pty_service,
+        fd_store,
+        sessions,
+    };
+    serve(Arc::new(service), listener);
     Ok(())
 }
 
+/// Keep the service manager's watchdog happy, for as long as the sessions are not stuck.
+fn spawn_watchdog(notifier: Arc<sd_notify::Notifier>, sessions: Arc<Sessions>, interval: Duration) {
+    std::thread::spawn(move || loop {
+        // A deadlock on the sessions map would stop every request; catch at least that.
+        drop(
+            sessions
+                .lock()
+                .expect("internal: sessions map mutex poison"),
+        );
+        if let Err(error) = notifier.watchdog() {
+            // TODO Proper error logging.
+            eprintln!("error notifying watchdog: {}", error);
+        }
+        // Leave plenty of room for scheduling delays.
+        std::thread::sleep(interval / 2);
+    });
+}
+
+/// Starts shell sessions, and acts on them later.
+///
+/// Implemented by [dbus_shell::Dbus] and [local_shell::LocalShell]; tests use a fake.
+pub trait ShellStarter: Send + Sync + 'static {
+    type Error: std::error::Error + Send + Sync + 'static;
+    type Command: CommandHandle<Error = Self::Error>;
+
+    /// Create a new shell session, returning the PTY master, and the shell when we can wait for it to exit.
+    fn create_shell(
+        &self,
+        spec: &dbus_shell::ShellSpec,
+    ) -> Result<(PtyMaster, Option<Self::Command>), Self::Error>;
+
+    /// End the session that process `pid` is the leader of.
+    fn terminate_session(&self, pid: u32) -> Result<(), Self::Error>;
+
+    /// Send a signal to all processes in the session that process `pid` is the leader of.
+    fn signal_session(&self, pid: u32, signal: i32) -> Result<(), Self::Error>;
+
+    /// List the machines sessions can be created on.
+    fn list_machines(&self) -> Result<Vec<dbus_shell::MachineInfo>, Self::Error>;
+
+    /// Start a non-interactive command on the host.
+    fn run_command(&self, spec: &dbus_shell::CommandSpec) -> Result<Self::Command, Self::Error>;
+
+    /// Classify an error, for telling the client.
+    fn error_kind(error: &Self::Error) -> dbus_shell::ErrorKind;
+}
+
+/// A shell or command started by a [ShellStarter].
+pub trait CommandHandle: Send + 'static {
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    /// Wait for the command to exit.
+    fn wait(self) -> Result<ExitStatus, Self::Error>;
+}
+
+impl ShellStarter for dbus_shell::Dbus<'static> {
+    type Error = dbus_shell::Error;
+    type Command = dbus_shell::RunningCommand;
+
+    fn create_shell(
+        &self,
+        spec: &dbus_shell::ShellSpec,
+    ) -> Result<(PtyMaster, Option<Self::Command>), Self::Error> {
+        dbus_shell::Dbus::create_shell(self, spec)
+    }
+
+    fn terminate_session(&self, pid: u32) -> Result<(), Self::Error> {
+        dbus_shell::Dbus::terminate_session(self, pid)
+    }
+
+    fn signal_session(&self, pid: u32, signal: i32) -> Result<(), Self::Error> {
+        dbus_shell::Dbus::signal_session(self, pid, signal)
+    }
+
+    fn list_machines(&self) -> Result<Vec<dbus_shell::MachineInfo>, Self::Error> {
+        dbus_shell::Dbus::list_machines(self)
+    }
+
+    fn run_command(&self, spec: &dbus_shell::CommandSpec) -> Result<Self::Command, Self::Error> {
+        dbus_shell::Dbus::run_command(self, spec)
+    }
+
+    fn error_kind(error: &Self::Error) -> dbus_shell::ErrorKind {
+        error.kind()
+    }
+}
+
+impl CommandHandle for dbus_shell::RunningCommand {
+    type Error = dbus_shell::Error;
+
+    fn wait(self) -> Result<ExitStatus, Self::Error> {
+        dbus_shell::RunningCommand::wait(self)
+    }
+}
+
+impl ShellStarter for local_shell::LocalShell {
+    type Error = local_shell::Error;
+    type Command = local_shell::RunningCommand;
+
+    fn create_shell(
+        &self,
+        spec: &dbus_shell::ShellSpec,
+    ) -> Result<(PtyMaster, Option<Self::Command>), Self::Error> {
+        let (pty_master, shell) = local_shell::LocalShell::create_shell(self, spec)?;
+        Ok((pty_master, Some(shell)))
+    }
+
+    fn terminate_session(&self, pid: u32) -> Result<(), Self::Error> {
+        local_shell::LocalShell::terminate_session(self, pid)
+    }
+
+    fn signal_session(&self, pid: u32, signal: i32) -> Result<(), Self::Error> {
+        local_shell::LocalShell::signal_session(self, pid, signal)
+    }
+
+    fn list_machines(&self) -> Result<Vec<dbus_shell::MachineInfo>, Self::Error> {
+        Ok(local_shell::LocalShell::list_machines(self))
+    }
+
+    fn run_command(&self, spec: &dbus_shell::CommandSpec) -> Result<Self::Command, Self::Error> {
+        local_shell::LocalShell::run_command(self, spec)
+    }
+
+    fn error_kind(error: &Self::Error) -> dbus_shell::ErrorKind {
+        error.kind()
+    }
+}
+
+impl CommandHandle for local_shell::RunningCommand {
+    type Error = local_shell::Error;
+
+    fn wait(self) -> Result<ExitStatus, Self::Error> {
+        local_shell::RunningCommand::wait(self)
+    }
+}
+
+/// Connects to a new instance of the PTY service, one per session.
+pub trait PtyServiceConnector: Send + Sync + 'static {
+    fn connect(&self) -> Result<SeqPacket, std::io::Error>;
+}
+
+/// Where to reach the PTY service, `tere-pty@`.
+const PTY_SERVICE_SOCKET: &str = "/run/tere/socket/pty.socket";
+
+/// Where to reach the PTY service when running as a user service, relative to `$XDG_RUNTIME_DIR`.
+const PTY_SERVICE_SOCKET_USER: &str = "tere/socket/pty.socket";
+
+/// Connect to `tere-pty@` via its socket, letting systemd start a new instance for each connection.
+pub struct PtyServiceSocket {
+    path: PathBuf,
+}
+
+impl PtyServiceSocket {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl PtyServiceConnector for PtyServiceSocket {
+    fn connect(&self) -> Result<SeqPacket, std::io::Error> {
+        SeqPacket::connect(&self.path)
+    }
+}
+
+/// State shared by all connections.
+struct Service<S, C> {
+    session_starter: S,
+    pty_service: C,
+    fd_store: Arc<FdStore>,
+    sessions: Arc<Sessions>,
+}
+
+/// How long to remember sessions after they have exited.
+const EXITED_RETENTION: Duration = Duration::from_secs(60 * 60);
+
+/// How long to wait for the exit status of a shell, once its PTY is closed.
+/// Shorter than the PTY service is willing to wait for it.
+const EXIT_STATUS_TIMEOUT: Duration = Duration::from_secs(3);
+
 enum Session {
     Creating,
     Ready {
         pty_master: PtyMaster,
         pty_service_conn: Arc<SeqPacket>,
+        info: p::SessionInfo,
     },
+    /// The session is over, and only kept around to be listed.
+    Exited {
+        info: p::SessionInfo,
+    },
+}
+
+impl Session {
+    /// Mark the session as over, releasing its PTY and connection to the PTY service.
+    fn set_exited(&mut self, fd_store: &FdStore, status: Option<proto::ExitStatus>) {
+        let old = std::mem::replace(self, Session::Creating);
+        *self = match old {
+            Session::Ready { mut info, .. } => {
+                if let Err(error) = fd_store.remove(&info.id) {
+                    // TODO Proper error logging.
+                    eprintln!(
+                        "error removing stored FDs of session {}: {}",
+                        info.id, error
+                    );
+                }
+                info.state = p::SessionState::Exited {
+                    status,
+                    at: SystemTime::now(),
+                };
+                Session::Exited { info }
+            }
+            other => other,
+        };
+    }
+}
+
+type Sessions = Mutex<HashMap<p::SessionId, Arc<Mutex<Session>>>>;
+
+/// The machine called `name` is gone, and so are any sessions in it.
+fn exit_container_sessions(fd_store: &FdStore, sessions: &Sessions, name: &str) {
+    let guard = sessions
+        .lock()
+        .expect("internal: sessions map mutex poison");
+    for session_entry in guard.values() {
+        let mut guard = session_entry
+            .lock()
+            .expect("internal: session mutex poison");
+        let in_machine = match &*guard {
+            Session::Ready { info, .. } => {
+                matches!(&info.machine, p::Machine::Container(container) if container.as_str() == name)
+            }
+            _ => false,
+        };
+        if in_machine {
+            guard.set_exited(fd_store, None);
+        }
+    }
+}
+
+/// Forget sessions that exited long enough ago.
+fn purge_exited(sessions: &Sessions) {
+    let now = SystemTime::now();
+    let mut guard = sessions
+        .lock()
+        .expect("internal: sessions map mutex poison");
+    guard.retain(|_id, session_entry| {
+        let guard = session_entry
+            .lock()
+            .expect("internal: session mutex poison");
+        match &*guard {
+            Session::Exited { info } => match info.state {
+                p::SessionState::Exited { at, .. } => now
+                    .duration_since(at)
+                    // Clock went backwards, try again later.
+                    .map_or(true, |age| age < EXITED_RETENTION),
+                p::SessionState::Running => true,
+            },
+            _ => true,
+        }
+    });
+}
+
+#[derive(Error, Debug)]
+enum MonitorError {
+    #[error("socket receive error: {0}")]
+    Receive(#[source] ipc::ReceiveError),
+}
+
+/// Keep session metadata up to date with events from the PTY service, until the session ends.
+fn monitor_pty_service(
+    fd_store: &FdStore,
+    session_entry: &Mutex<Session>,
+    pty_service_conn: &impl ipc::IPC,
+    exit_status: &mpsc::Receiver<Option<proto::ExitStatus>>,
+) -> Result<(), MonitorError> {
+    // Whatever happens, we're not going to hear about the session anymore.
+    let mut exited = scopeguard::guard(None, |exit_status| {
+        let mut guard = session_entry
+            .lock()
+            .expect("internal: session mutex poison");
+        guard.set_exited(fd_store, exit_status);
+    });
+    loop {
+        let event: proto::pty::Event = match pty_service_conn.receive_with_fds() {
+            Ok(event) => event,
+            Err(ipc::ReceiveError::End) => return Ok(()),
+            Err(error) => return Err(MonitorError::Receive(error)),
+        };
+        match event {
+            proto::pty::Event::Clients { count } => {
+                let mut guard = session_entry
+                    .lock()
+                    .expect("internal: session mutex poison");
+                if let Session::Ready { info, .. } = &mut *guard {
+                    info.clients = count;
+                }
+            }
+            proto::pty::Event::Closed => {
+                // Errors mean nobody is waiting for the shell.
+                let exit_status = exit_status
+                    .recv_timeout(EXIT_STATUS_TIMEOUT)
+                    .unwrap_or(None);
+                let message = proto::pty::Request::SessionEnded { exit_status };
+                if let Err(error) = pty_service_conn.send_with_fds(&message) {
+                    // TODO Proper error logging.
+                    eprintln!("error sending exit status to PTY service: {}", error);
+                }
+                *exited = exit_status;
+                // Stop listening, so the PTY service sees the end of our connection once the session is forgotten, and exits.
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn spawn_monitor(
+    fd_store: Arc<FdStore>,
+    session_id: p::SessionId,
+    session_entry: Arc<Mutex<Session>>,
+    pty_conn: Arc<SeqPacket>,
+    exit_status: mpsc::Receiver<Option<proto::ExitStatus>>,
+) {
+    std::thread::spawn(move || {
+        let result =
+            monitor_pty_service(&fd_store, &session_entry, pty_conn.as_ref(), &exit_status);
+        if let Err(error) = result {
+            // TODO Proper error logging.
+            eprintln!("error monitoring session {}: {}", session_id, error);
+        }
+    });
+}
+
+/// Check the parts of a session creation request that get passed on as is.
+fn validate_create(create: &p::CreateShellSession) -> Result<(), p::InvalidRequest> {
+    validate_user(&create.user)?;
+    if let Some(options) = &create.pty_options {
+        if !options.is_valid() {
+            return Err(p::InvalidRequest::BadPtyOptions);
+        }
+    }
+    validate_program(
+        create.program.as_deref(),
+        create.args.as_deref(),
+        create.env.as_deref(),
+    )
+}
+
+/// Check the parts of a command request that get passed on as is.
+fn validate_run(run: &p::RunCommand) -> Result<(), p::InvalidRequest> {
+    validate_user(&run.user)?;
+    validate_program(Some(&run.program), Some(&run.args), run.env.as_deref())
 }
 
-const SESSION_ID_BYTES: usize = 24;
-type SessionId = [u8; SESSION_ID_BYTES];
+/// The username is remembered in [p::SessionInfo], so it must not be too long to list.
+fn validate_user(user: &str) -> Result<(), p::InvalidRequest> {
+    if user.len() > p::MAX_USER_LEN {
+        return Err(p::InvalidRequest::TooLong);
+    }
+    Ok(())
+}
+
+fn validate_program(
+    program: Option<&str>,
+    args: Option<&[String]>,
+    env: Option<&[String]>,
+) -> Result<(), p::InvalidRequest> {
+    if program.map_or(false, |program| program.len() > p::MAX_PROGRAM_LEN) {
+        return Err(p::InvalidRequest::TooLong);
+    }
+    for list in args.into_iter().chain(env) {
+        if list.len() > p::MAX_ARGS || list.iter().any(|s| s.len() > p::MAX_ARG_LEN) {
+            return Err(p::InvalidRequest::TooLong);
+        }
+    }
+    let mut strings = program
+        .into_iter()
+        .chain(args.into_iter().flatten().map(String::as_str))
+        .chain(env.into_iter().flatten().map(String::as_str));
+    if strings.any(|s| s.contains('\0')) {
+        return Err(p::InvalidRequest::ContainsNul);
+    }
+    match (program, args) {
+        (None, None) => (),
+        (None, Some(_)) => return Err(p::InvalidRequest::ArgsWithoutProgram),
+        (Some(program), args) => {
+            if !program.starts_with('/') {
+                return Err(p::InvalidRequest::ProgramNotAbsolute);
+            }
+            if args.map_or(true, |args| args.is_empty()) {
+                return Err(p::InvalidRequest::MissingArgs);
+            }
+        }
+    }
+    for var in env.into_iter().flatten() {
+        match var.find('=') {
+            Some(pos) if pos > 0 => (),
+            _ => return Err(p::InvalidRequest::BadEnvironment),
+        }
+    }
+    Ok(())
+}
+
+/// Explain why a request could not be decoded, cut short to fit in [p::InvalidRequest::Malformed].
+fn malformed(error: impl std::fmt::Display) -> p::InvalidRequest {
+    let mut explanation = error.to_string();
+    if explanation.len() > p::MAX_MALFORMED_LEN {
+        let mut end = p::MAX_MALFORMED_LEN;
+        while !explanation.is_char_boundary(end) {
+            end -= 1;
+        }
+        explanation.truncate(end);
+    }
+    p::InvalidRequest::Malformed(explanation)
+}
+
+/// Find the session leader of a host session, for acting on the session via logind.
+///
+/// On failure, returns the response to send.
+fn host_session_leader(sessions: &Sessions, id: &p::SessionId) -> Result<u32, p::Response> {
+    let session_entry = {
+        let guard = sessions
+            .lock()
+            .expect("internal: sessions map mutex poison");
+        guard.get(id).cloned()
+    }
+    .ok_or(p::Response::NoSuchSession)?;
+    let guard = session_entry
+        .lock()
+        .expect("internal: session mutex poison");
+    match &*guard {
+        Session::Creating | Session::Exited { .. } => Err(p::Response::NoSuchSession),
+        Session::Ready {
+            pty_master, info, ..
+        } => {
+            if !matches!(info.machine, p::Machine::Host) {
+                // The host logind doesn't know about sessions inside containers, and going through machined would act on the whole container.
+                return Err(p::Response::Unsupported);
+            }
+            // This fails once the session has ended.
+            let sid = pty_master
+                .session_id()
+                .map_err(|_error| p::Response::NoSuchSession)?;
+            Ok(sid as u32)
+        }
+    }
+}
+
+/// Tell the client why starting or acting on a session failed, without going into details.
+fn starter_error_response<S: ShellStarter>(error: &S::Error) -> p::Response {
+    match S::error_kind(error) {
+        dbus_shell::ErrorKind::Denied => p::Response::Denied,
+        dbus_shell::ErrorKind::NoSuchMachine => p::Response::NoSuchMachine,
+        dbus_shell::ErrorKind::NoSuchUser => p::Response::NoSuchUser,
+        dbus_shell::ErrorKind::NoSuchSession => p::Response::NoSuchSession,
+        dbus_shell::ErrorKind::Other => p::Response::Internal,
+    }
+}
+
+/// Convert machines as listed by the session starter for the client, leaving out any we could not address.
+fn machine_infos(machines: Vec<dbus_shell::MachineInfo>) -> Vec<p::MachineInfo> {
+    let mut infos: Vec<p::MachineInfo> = machines
+        .into_iter()
+        .filter_map(|machine| {
+            let name = if machine.name == ".host" {
+                p::Machine::Host
+            } else {
+                match machine.name.parse() {
+                    Ok(name) => p::Machine::Container(name),
+                    Err(error) => {
+                        // TODO Proper error logging.
+                        eprintln!("ignoring machine {:?}: {}", machine.name, error);
+                        return None;
+                    }
+                }
+            };
+            if [&machine.class, &machine.service, &machine.state]
+                .iter()
+                .any(|s| s.len() > p::MAX_MACHINE_INFO_LEN)
+            {
+                // TODO Proper error logging.
+                eprintln!("ignoring machine {:?}: description too long", machine.name);
+                return None;
+            }
+            Some(p::MachineInfo {
+                machine: name,
+                class: machine.class,
+                service: machine.service,
+                state: machine.state,
+            })
+        })
+        .collect();
+    infos.sort_by(|a, b| match (&a.machine, &b.machine) {
+        (p::Machine::Host, p::Machine::Host) => std::cmp::Ordering::Equal,
+        (p::Machine::Host, _) => std::cmp::Ordering::Less,
+        (_, p::Machine::Host) => std::cmp::Ordering::Greater,
+        (p::Machine::Container(a), p::Machine::Container(b)) => a.as_str().cmp(b.as_str()),
+    });
+    if infos.len() > p::MAX_MACHINES {
+        // TODO Proper error logging.
+        eprintln!(
+            "listing only {} of {} machines",
+            p::MAX_MACHINES,
+            infos.len()
+        );
+        infos.truncate(p::MAX_MACHINES);
+    }
+    infos
+}
+
+/// Start a command, and relay between it and the client in the background.
+///
+/// On failure, returns the response to send.
+fn run_command<S: ShellStarter, C: PtyServiceConnector>(
+    service: &Service<S, C>,
+    run: p::RunCommand,
+) -> Result<(), p::Response> {
+    validate_run(&run).map_err(p::Response::InvalidRequest)?;
+    if let p::Machine::Container(_) = run.machine {
+        // TODO Start the unit through the container's own service manager.
+        return Err(p::Response::Unsupported);
+    }
+    let client = SeqPacket::try_from(run.fd)
+        .map_err(|error| p::Response::InvalidRequest(malformed(error)))?;
+    let pipes = || -> Result<_, std::io::Error> {
+        Ok((command::pipe()?, command::pipe()?, command::pipe()?))
+    };
+    let ((stdin_read, stdin_write), (stdout_read, stdout_write), (stderr_read, stderr_write)) =
+        pipes().map_err(|error| {
+            // TODO Proper error logging.
+            eprintln!("error creating pipes for command: {}", error);
+            p::Response::Internal
+        })?;
+    let args: Vec<&str> = run.args.iter().map(String::as_str).collect();
+    let environment: Vec<&str> = run.env.iter().flatten().map(String::as_str).collect();
+    let spec = dbus_shell::CommandSpec {
+        user: &run.user,
+        program: &run.program,
+        args: &args,
+        environment: &environment,
+        stdin: &stdin_read,
+        stdout: &stdout_write,
+        stderr: &stderr_write,
+    };
+    let running = service
+        .session_starter
+        .run_command(&spec)
+        .map_err(|error| {
+            // TODO Proper error logging.
+            eprintln!("error running command {:?}: {}", spec.program, error);
+            starter_error_response::<S>(&error)
+        })?;
+    // Only the command may hold these, or we'd never see the end of its output.
+    drop((stdin_read, stdout_write, stderr_write));
+    std::thread::spawn(move || {
+        command::relay(client, stdin_write, stdout_read, stderr_read, running);
+    });
+    Ok(())
+}
 
-fn serve(session_starter: dbus_shell::Dbus<'static>, listener: UnixListener) {
-    // Even with `Arc`, passing this to threads forces us to insist on `'static` for the argument.
-    // Good thing that happens to be true!
-    let session_starter = Arc::new(session_starter);
-    let sessions: Arc<Mutex<HashMap<SessionId, Arc<Mutex<Session>>>>> =
-        Arc::new(Mutex::new(HashMap::new()));
+/// Start a new session and attach the client to it.
+///
+/// On failure, returns the response to send.
+fn create_session<S: ShellStarter, C: PtyServiceConnector>(
+    service: &Service<S, C>,
+    create: p::CreateShellSession,
+) -> Result<p::SessionId, p::Response> {
+    let sessions = &service.sessions;
+    validate_create(&create).map_err(p::Response::InvalidRequest)?;
+    let machine = match &create.machine {
+        p::Machine::Host => ".host",
+        p::Machine::Container(name) => name.as_str(),
+    };
+    let args: Vec<&str> = create.args.iter().flatten().map(String::as_str).collect();
+    let environment: Vec<&str> = create.env.iter().flatten().map(String::as_str).collect();
+    let spec = dbus_shell::ShellSpec {
+        machine,
+        user: &create.user,
+        program: create.program.as_deref().unwrap_or(""),
+        args: &args,
+        environment: &environment,
+    };
+    let (session_id, session_entry) = {
+        let mut guard = sessions
+            .lock()
+            .expect("internal: sessions map mutex poison");
+        loop {
+            let session_id = p::SessionId(rand::random());
+            let entry = guard.entry(session_id);
+            use std::collections::hash_map::Entry;
+            match entry {
+                Entry::Occupied(_) => continue,
+                Entry::Vacant(vacant) => {
+                    let session_entry = Arc::new(Mutex::new(Session::Creating));
+                    vacant.insert(session_entry.clone());
+                    break (session_id, session_entry);
+                }
+            }
+        }
+    };
+    println!("session_id: {}", session_id);
+    // Give up the session ID if we don't get to the end.
+    let creating = scopeguard::guard((), |()| {
+        sessions
+            .lock()
+            .expect("internal: sessions map mutex poison")
+            .remove(&session_id);
+    });
+
+    let (pty_master, shell) = service
+        .session_starter
+        .create_shell(&spec)
+        .map_err(|error| {
+            // TODO Proper error logging.
+            eprintln!("error creating shell session {}: {}", session_id, error);
+            starter_error_response::<S>(&error)
+        })?;
+    // Wait for the shell even if the session doesn't get off the ground, so it gets reaped.
+    let (exit_status_sender, exit_status) = mpsc::channel();
+    if let Some(shell) = shell {
+        std::thread::spawn(move || {
+            let status = match shell.wait() {
+                Ok(status) => command::exit_status(status),
+                Err(error) => {
+                    // TODO Proper error logging.
+                    eprintln!("error waiting for shell of {}: {}", session_id, error);
+                    None
+                }
+            };
+            let _ = exit_status_sender.send(status);
+        });
+    }
+
+    let pty_unavailable = |error: &dyn std::fmt::Display| {
+        // TODO Proper error logging.
+        eprintln!("error starting PTY service for {}: {}", session_id, error);
+        p::Response::PtyServiceUnavailable
+    };
+    let pty_conn = service
+        .pty_service
+        .connect()
+        .map_err(|error| pty_unavailable(&error))?;
+    let pty_conn = Arc::new(pty_conn);
+    ipc::handshake::handshake_as_client(
+        pty_conn.as_ref(),
+        proto::pty::CLIENT_INTENT,
+        proto::pty::SERVER_INTENT,
+    )
+    .map_err(|error| pty_unavailable(&error))?;
+
+    // Jump through hoops to get ownership of `pty_master` back.
+    let pty_master = {
+        let message = proto::pty::Init {
+            pty_master,
+            options: create.pty_options.clone().unwrap_or_default(),
+        };
+        pty_conn
+            .send_with_fds(&message)
+            .map_err(|error| pty_unavailable(&error))?;
+        message.pty_master
+    };
+
+    {
+        let mut guard = session_entry
+            .lock()
+            .expect("internal: session mutex poison");
+        match *guard {
+            Session::Creating => (),
+            _ => panic!("internal: someone stole our session id"),
+        }
+        let info = p::SessionInfo {
+            id: session_id,
+            machine: create.machine.clone(),
+            user: create.user.clone(),
+            program: create.program.clone(),
+            created: SystemTime::now(),
+            clients: 0,
+            state: p::SessionState::Running,
+        };
+        // Store while holding the lock, so the session can't be removed from the store before it's added.
+        if let Err(error) = service.fd_store.save(&pty_master, pty_conn.as_ref(), &info) {
+            // The session works, it just won't survive a restart of this service.
+            // TODO Proper error logging.
+            eprintln!("error storing FDs of session {}: {}", session_id, error);
+        }
+        *guard = Session::Ready {
+            pty_master,
+            pty_service_conn: pty_conn.clone(),
+            info,
+        };
+    }
+    // From here on, the session exists even if attaching the client fails.
+    scopeguard::ScopeGuard::into_inner(creating);
+
+    spawn_monitor(
+        service.fd_store.clone(),
+        session_id,
+        session_entry,
+        pty_conn.clone(),
+        exit_status,
+    );
+
+    let message = proto::pty::Request::NewClient { fd: create.fd };
+    pty_conn
+        .send_with_fds(&message)
+        .map_err(|error| pty_unavailable(&error))?;
+    Ok(session_id)
+}
+
+fn serve<S: ShellStarter, C: PtyServiceConnector>(
+    service: Arc<Service<S, C>>,
+    listener: UnixListener,
+) {
     for stream in listener.incoming() {
         match stream {
             Ok(stream) => {
                 let fd = stream.into_raw_fd();
                 let socket = unsafe { UnixDatagram::from_raw_fd(fd) };
                 let conn = SeqPacket::try_from(socket).expect("stdin is not a SOCK_SEQPACKET");
-                let session_starter = session_starter.clone();
-                let sessions = sessions.clone();
+                let service = service.clone();
                 std::thread::spawn(move || {
-                    let result = serve_conn(session_starter, sessions, conn);
+                    let result = serve_conn(&service, conn);
                     if let Err(error) = result {
                         // TODO Proper error logging.
                         eprintln!("error serving connection: {0}", error);
@@ -99,118 +902,167 @@ pub enum ConnError {
 
     #[error("socket receive error: {0}")]
     Receive(#[source] ipc::ReceiveError),
+
+    #[error("socket send error: {0}")]
+    Send(#[source] ipc::SendError),
 }
 
-fn serve_conn(
-    session_starter: Arc<dbus_shell::Dbus<'_>>,
-    sessions: Arc<Mutex<HashMap<SessionId, Arc<Mutex<Session>>>>>,
+fn serve_conn<S: ShellStarter, C: PtyServiceConnector>(
+    service: &Service<S, C>,
     conn: impl ipc::IPC,
 ) -> Result<(), ConnError> {
-    // TODO Configuration for unit testability:
-    //
-    // - dbus_shell via trait
-    // - pty service via trait
-
+    let sessions = &service.sessions;
     ipc::handshake::handshake_as_server(&conn, p::CLIENT_INTENT, p::SERVER_INTENT)
         .map_err(ConnError::Handshake)?;
 
     loop {
-        let request: p::Request = conn.receive_with_fds().map_err(ConnError::Receive)?;
+        let request: p::Request = match conn.receive_with_fds() {
+            Ok(request) => request,
+            // Tell the client what was wrong, instead of just hanging up.
+            Err(ipc::ReceiveError::Deserialize(error)) => {
+                let response = p::Response::InvalidRequest(malformed(error));
+                conn.send_with_fds(&response).map_err(ConnError::Send)?;
+                continue;
+            }
+            Err(error) => return Err(ConnError::Receive(error)),
+        };
+        purge_exited(sessions);
         // Handle incoming requests on one connection as run-to-completion, since they are coming from a single `policy@` instance and thus from a single user.
         println!("request: {:?}", &request);
         match request {
             p::Request::CreateShellSession(create) => {
-                let machine = match &create.machine {
-                    p::Machine::Host => ".host",
-                    p::Machine::Container(name) => name,
-                };
-                let spec = dbus_shell::ShellSpec {
-                    // TODO Enforce input doesn't start start with ".", force use of `Machine::Container`.
-                    // Try to put that on the deserialization layer.
-                    machine,
-                    user: &create.user,
-                    program: "",      // TODO fill
-                    args: &[],        // TODO fill
-                    environment: &[], // TODO fill
+                let response = match create_session(service, create) {
+                    Ok(id) => p::Response::Created { id },
+                    Err(response) => response,
                 };
-                let (session_id, session_entry) = {
-                    let mut guard = sessions
+                conn.send_with_fds(&response).map_err(ConnError::Send)?;
+            }
+
+            p::Request::AttachSession(attach) => {
+                let session_entry = {
+                    let guard = sessions
                         .lock()
                         .expect("internal: sessions map mutex poison");
-                    loop {
-                        let session_id: SessionId = rand::random();
-                        let entry = guard.entry(session_id);
-                        use std::collections::hash_map::Entry;
-                        match entry {
-                            Entry::Occupied(_) => continue,
-                            Entry::Vacant(vacant) => {
-                                let session_entry = Arc::new(Mutex::new(Session::Creating));
-                                vacant.insert(session_entry.clone());
-                                break (session_id, session_entry);
+                    guard.get(&attach.id).cloned()
+                };
+                let pty_conn = session_entry.and_then(|session_entry| {
+                    let guard = session_entry
+                        .lock()
+                        .expect("internal: session mutex poison");
+                    match &*guard {
+                        // Nobody has been told the ID yet, they're guessing.
+                        Session::Creating => None,
+                        Session::Exited { .. } => None,
+                        Session::Ready {
+                            pty_service_conn, ..
+                        } => Some(pty_service_conn.clone()),
+                    }
+                });
+                let response = match pty_conn {
+                    None => p::Response::NoSuchSession,
+                    Some(pty_conn) => {
+                        let message = proto::pty::Request::NewClient { fd: attach.fd };
+                        match pty_conn.send_with_fds(&message) {
+                            Ok(()) => p::Response::Attached,
+                            Err(error) => {
+                                // TODO Proper error logging.
+                                eprintln!("error attaching to session {}: {}", attach.id, error);
+                                p::Response::PtyServiceUnavailable
                             }
                         }
                     }
                 };
-                println!("session_id: {:?}", session_id);
-
-                let pty_master = session_starter
-                    .create_shell(&spec)
-                    // TODO Should we report errors on client FD?
-                    // It hasn't done handshake yet.
-                    // At the very least, log and go back to loop.
-                    .expect("TODO handle dbus create shell session error");
-                let pty_service_location = "/run/tere/socket/pty.socket";
-                let pty_conn = SeqPacket::connect(pty_service_location)
-                    // TODO non-fatal error handling
-                    .expect("TODO handle pty service error");
-                let pty_conn = Arc::new(pty_conn);
-
-                ipc::handshake::handshake_as_client(
-                    pty_conn.as_ref(),
-                    proto::pty::CLIENT_INTENT,
-                    proto::pty::SERVER_INTENT,
-                )
-                // TODO non-fatal error handling
-                .expect("TODO handle pty service error");
-
-                // Jump through hoops to get ownership of `pty_master` back.
-                let pty_master = {
-                    let message = proto::pty::Init {
-                        _dummy: 0,
-                        pty_master,
-                    };
-                    pty_conn
-                        .send_with_fds(&message)
-                        // TODO non-fatal error handling
-                        .expect("TODO handle pty service error");
-                    message.pty_master
-                };
+                conn.send_with_fds(&response).map_err(ConnError::Send)?;
+            }
 
-                {
-                    let mut guard = session_entry
+            p::Request::ListSessions(list) => {
+                let mut infos: Vec<p::SessionInfo> = {
+                    let guard = sessions
                         .lock()
-                        .expect("internal: session mutex poison");
-                    match *guard {
-                        Session::Creating => (),
-                        _ => panic!("internal: someone stole our session id"),
-                    }
-                    *guard = Session::Ready {
-                        pty_master,
-                        pty_service_conn: pty_conn.clone(),
-                    };
-                }
+                        .expect("internal: sessions map mutex poison");
+                    guard
+                        .values()
+                        .filter_map(|session_entry| {
+                            let guard = session_entry
+                                .lock()
+                                .expect("internal: session mutex poison");
+                            match &*guard {
+                                Session::Creating => None,
+                                Session::Ready { info, .. } | Session::Exited { info } => {
+                                    Some(info.clone())
+                                }
+                            }
+                        })
+                        .collect()
+                };
+                // Break ties by ID, so pages agree on the order.
+                infos.sort_by_key(|info| (info.created, info.id.0));
+                let start = (list.start as usize).min(infos.len());
+                let end = (start + p::SESSIONS_PER_PAGE).min(infos.len());
+                let next = if end < infos.len() {
+                    Some(end as u32)
+                } else {
+                    None
+                };
+                let response = p::Response::Sessions {
+                    sessions: infos.drain(start..end).collect(),
+                    next,
+                };
+                conn.send_with_fds(&response).map_err(ConnError::Send)?;
+            }
 
-                {
-                    let message = proto::pty::Request::NewClient {
-                        _dummy: 0,
-                        fd: create.fd,
-                    };
-                    pty_conn
-                        .send_with_fds(&message)
-                        // TODO non-fatal error handling
-                        .expect("TODO handle pty service error");
-                }
+            p::Request::TerminateSession(terminate) => {
+                let response = match host_session_leader(sessions, &terminate.id) {
+                    Err(response) => response,
+                    Ok(pid) => match service.session_starter.terminate_session(pid) {
+                        Ok(()) => p::Response::Terminated,
+                        Err(error) => {
+                            // TODO Proper error logging.
+                            eprintln!("error terminating session {}: {}", terminate.id, error);
+                            starter_error_response::<S>(&error)
+                        }
+                    },
+                };
+                conn.send_with_fds(&response).map_err(ConnError::Send)?;
+            }
+
+            p::Request::SignalSession(signal) => {
+                let response = match host_session_leader(sessions, &signal.id) {
+                    Err(response) => response,
+                    Ok(pid) => match service.session_starter.signal_session(pid, signal.signal) {
+                        Ok(()) => p::Response::Signaled,
+                        Err(error) => {
+                            // TODO Proper error logging.
+                            eprintln!("error signaling session {}: {}", signal.id, error);
+                            starter_error_response::<S>(&error)
+                        }
+                    },
+                };
+                conn.send_with_fds(&response).map_err(ConnError::Send)?;
+            }
+
+            p::Request::RunCommand(run) => {
+                let response = match run_command(service, run) {
+                    Ok(()) => p::Response::CommandStarted,
+                    Err(response) => response,
+                };
+                conn.send_with_fds(&response).map_err(ConnError::Send)?;
+            }
+
+            p::Request::ListMachines => {
+                let response = match service.session_starter.list_machines() {
+                    Ok(machines) => p::Response::Machines(machine_infos(machines)),
+                    Err(error) => {
+                        // TODO Proper error logging.
+                        eprintln!("error listing machines: {}", error);
+                        starter_error_response::<S>(&error)
+                    }
+                };
+                conn.send_with_fds(&response).map_err(ConnError::Send)?;
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,721 @@
+use std::convert::TryFrom;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixDatagram;
+use std::os::unix::process::CommandExt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+use crate::dbus_shell;
+use crate::ipc;
+use crate::ipc::seqpacket::SeqPacket;
+use crate::ipc::IPC;
+use crate::proto;
+use crate::proto::sessions as p;
+use crate::pty_master::PtyMaster;
+use crate::sd_notify;
+use crate::services::pty;
+
+use super::{
+    malformed, serve_conn, validate_create, CommandHandle, FdStore, PtyServiceConnector, Service,
+    ShellStarter,
+};
+
+#[derive(Error, Debug)]
+#[error("fake shell starter: {0:?}")]
+struct FakeError(dbus_shell::ErrorKind);
+
+/// Run shells as local processes, instead of asking machined.
+struct FakeShellStarter {
+    /// Command to run when the request doesn't name a program.
+    default_command: &'static [&'static str],
+    /// Fail every request, like this.
+    fail: Option<dbus_shell::ErrorKind>,
+}
+
+impl FakeShellStarter {
+    fn new() -> Self {
+        Self {
+            default_command: &["sleep", "60"],
+            fail: None,
+        }
+    }
+
+    fn failing(kind: dbus_shell::ErrorKind) -> Self {
+        Self {
+            fail: Some(kind),
+            ..Self::new()
+        }
+    }
+
+    fn check(&self) -> Result<(), FakeError> {
+        match self.fail {
+            Some(kind) => Err(FakeError(kind)),
+            None => Ok(()),
+        }
+    }
+}
+
+struct FakeCommand(std::process::Child);
+
+impl CommandHandle for FakeCommand {
+    type Error = FakeError;
+
+    fn wait(mut self) -> Result<std::process::ExitStatus, Self::Error> {
+        Ok(self.0.wait().expect("wait for command"))
+    }
+}
+
+impl ShellStarter for FakeShellStarter {
+    type Error = FakeError;
+    type Command = FakeCommand;
+
+    fn create_shell(
+        &self,
+        spec: &dbus_shell::ShellSpec,
+    ) -> Result<(PtyMaster, Option<Self::Command>), Self::Error> {
+        self.check()?;
+        let (pty_master, pty_child) = pty::tests::make_pty().expect("make_pty");
+        let mut command = if spec.program.is_empty() {
+            let mut command = std::process::Command::new(self.default_command[0]);
+            command.args(&self.default_command[1..]);
+            command
+        } else {
+            let mut command = std::process::Command::new(spec.program);
+            command.args(&spec.args[1..]);
+            command
+        };
+        command
+            .env_clear()
+            .envs(spec.environment.iter().filter_map(|var| {
+                let pos = var.find('=')?;
+                Some((&var[..pos], &var[pos + 1..]))
+            }))
+            .stdin(pty_child.try_clone().expect("dup PTY child"))
+            .stdout(pty_child.try_clone().expect("dup PTY child"))
+            .stderr(pty_child.try_clone().expect("dup PTY child"));
+        let child_fd = pty_child.as_raw_fd();
+        unsafe {
+            command.pre_exec(move || {
+                if libc::setsid() < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                if libc::ioctl(child_fd, libc::TIOCSCTTY, 0) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let child = command.spawn().expect("spawn shell");
+        Ok((pty_master, Some(FakeCommand(child))))
+    }
+
+    fn terminate_session(&self, pid: u32) -> Result<(), Self::Error> {
+        self.signal_session(pid, libc::SIGTERM)
+    }
+
+    fn signal_session(&self, pid: u32, signal: i32) -> Result<(), Self::Error> {
+        self.check()?;
+        // The session leader is also the process group leader.
+        let ret = unsafe { libc::kill(-(pid as libc::pid_t), signal) };
+        if ret < 0 {
+            return Err(FakeError(dbus_shell::ErrorKind::NoSuchSession));
+        }
+        Ok(())
+    }
+
+    fn list_machines(&self) -> Result<Vec<dbus_shell::MachineInfo>, Self::Error> {
+        self.check()?;
+        let machine = |name: &str, class: &str, service: &str| dbus_shell::MachineInfo {
+            name: name.to_string(),
+            class: class.to_string(),
+            service: service.to_string(),
+            state: "running".to_string(),
+        };
+        Ok(vec![
+            machine("thud", "container", "systemd-nspawn"),
+            machine(".host", "host", ""),
+            machine("xyzzy", "vm", "libvirt-qemu"),
+            // Not something we could ever create a session on.
+            machine("bad/name", "container", "systemd-nspawn"),
+            // Too long to list.
+            machine(
+                "waldo",
+                "container",
+                &"x".repeat(p::MAX_MACHINE_INFO_LEN + 1),
+            ),
+        ])
+    }
+
+    fn run_command(&self, spec: &dbus_shell::CommandSpec) -> Result<Self::Command, Self::Error> {
+        self.check()?;
+        let stdio = |file: &std::fs::File| file.try_clone().expect("dup command stdio");
+        let child = std::process::Command::new(spec.program)
+            .args(&spec.args[1..])
+            .env_clear()
+            .envs(spec.environment.iter().filter_map(|var| {
+                let pos = var.find('=')?;
+                Some((&var[..pos], &var[pos + 1..]))
+            }))
+            .stdin(stdio(spec.stdin))
+            .stdout(stdio(spec.stdout))
+            .stderr(stdio(spec.stderr))
+            .spawn()
+            .expect("spawn command");
+        Ok(FakeCommand(child))
+    }
+
+    fn error_kind(error: &Self::Error) -> dbus_shell::ErrorKind {
+        error.0
+    }
+}
+
+/// Run the PTY service in a thread of this process.
+struct InProcessPtyService;
+
+impl PtyServiceConnector for InProcessPtyService {
+    fn connect(&self) -> Result<SeqPacket, std::io::Error> {
+        let (conn, server_socket) = SeqPacket::pair()?;
+        std::thread::spawn(move || {
+            let conn = SeqPacket::try_from(server_socket).expect("convert to SeqPacket");
+            if let Err(error) = pty::serve(conn) {
+                println!("PTY service: {}", error);
+            }
+        });
+        Ok(conn)
+    }
+}
+
+/// A PTY service that can't be reached.
+struct UnavailablePtyService;
+
+impl PtyServiceConnector for UnavailablePtyService {
+    fn connect(&self) -> Result<SeqPacket, std::io::Error> {
+        Err(std::io::ErrorKind::ConnectionRefused.into())
+    }
+}
+
+/// Start serving one connection, and handshake with it.
+fn connect<S: ShellStarter, C: PtyServiceConnector>(
+    session_starter: S,
+    pty_service: C,
+) -> SeqPacket {
+    let service = Service {
+        session_starter,
+        pty_service,
+        fd_store: Arc::new(FdStore::new(Arc::new(sd_notify::Notifier::disabled()))),
+        sessions: Default::default(),
+    };
+    let (conn, server_socket) = SeqPacket::pair().expect("socketpair");
+    std::thread::spawn(move || {
+        let conn = SeqPacket::try_from(server_socket).expect("convert to SeqPacket");
+        let _ = serve_conn(&service, conn);
+    });
+    ipc::handshake::handshake_as_client(&conn, p::CLIENT_INTENT, p::SERVER_INTENT)
+        .expect("handshake");
+    conn
+}
+
+fn request(conn: &SeqPacket, request: &p::Request) -> p::Response {
+    conn.send_with_fds(request).expect("send request");
+    conn.receive_with_fds().expect("receive response")
+}
+
+/// List all sessions, one page at a time.
+fn list(conn: &SeqPacket) -> Vec<p::SessionInfo> {
+    let mut infos = Vec::new();
+    let mut start = 0;
+    loop {
+        match request(conn, &p::Request::ListSessions(p::ListSessions { start })) {
+            p::Response::Sessions { sessions, next } => {
+                infos.extend(sessions);
+                match next {
+                    Some(next) => start = next,
+                    None => return infos,
+                }
+            }
+            response => panic!("unexpected response: {:?}", response),
+        }
+    }
+}
+
+/// Create a session for a new client, returning the session ID and the client end.
+fn create(conn: &SeqPacket, create: p::CreateShellSession) -> (p::SessionId, SeqPacket) {
+    let (client_socket, server_socket) = ipc::seqpacket::pair().expect("socketpair");
+    let create = p::CreateShellSession {
+        fd: server_socket,
+        ..create
+    };
+    let id = match request(conn, &p::Request::CreateShellSession(create)) {
+        p::Response::Created { id } => id,
+        response => panic!("unexpected response: {:?}", response),
+    };
+    let client = SeqPacket::try_from(client_socket).expect("convert to SeqPacket");
+    (id, client)
+}
+
+/// Read session output until `want` shows up in it.
+fn expect_output(client: &SeqPacket, want: &[u8]) {
+    use crate::proto::pty::user as p;
+    let mut seen = Vec::new();
+    while !seen.windows(want.len()).any(|w| w == want) {
+        let output: p::Output = client.receive_with_fds().expect("receive output");
+        match output {
+            p::Output::SessionOutput(data) => seen.extend(data),
+            _ => panic!("unexpected output: {:?}", output),
+        }
+    }
+}
+
+fn create_request(
+    program: Option<&str>,
+    args: Option<&[&str]>,
+    env: Option<&[&str]>,
+) -> p::CreateShellSession {
+    let to_vec = |v: &[&str]| v.iter().map(|s| s.to_string()).collect();
+    p::CreateShellSession {
+        fd: UnixDatagram::unbound().expect("socket"),
+        machine: p::Machine::Host,
+        user: "testuser".to_string(),
+        program: program.map(str::to_string),
+        args: args.map(to_vec),
+        env: env.map(to_vec),
+        pty_options: None,
+    }
+}
+
+#[test]
+fn validate_default_shell() {
+    assert!(validate_create(&create_request(None, None, None)).is_ok());
+    assert!(validate_create(&create_request(None, None, Some(&["TERM=xterm", "EMPTY="]))).is_ok());
+}
+
+#[test]
+fn validate_program() {
+    assert!(validate_create(&create_request(Some("/bin/sh"), Some(&["sh", "-l"]), None)).is_ok());
+    assert!(matches!(
+        validate_create(&create_request(Some("sh"), Some(&["sh"]), None)),
+        Err(p::InvalidRequest::ProgramNotAbsolute)
+    ));
+    assert!(matches!(
+        validate_create(&create_request(Some("/bin/sh"), None, None)),
+        Err(p::InvalidRequest::MissingArgs)
+    ));
+    assert!(matches!(
+        validate_create(&create_request(Some("/bin/sh"), Some(&[]), None)),
+        Err(p::InvalidRequest::MissingArgs)
+    ));
+    assert!(matches!(
+        validate_create(&create_request(None, Some(&["sh"]), None)),
+        Err(p::InvalidRequest::ArgsWithoutProgram)
+    ));
+}
+
+#[test]
+fn validate_pty_options() {
+    let mut create = create_request(None, None, None);
+    create.pty_options = Some(proto::pty::Options {
+        slow_consumer: proto::pty::SlowConsumerPolicy::Disconnect,
+        ..Default::default()
+    });
+    assert!(validate_create(&create).is_ok());
+    create.pty_options = Some(proto::pty::Options {
+        max_queued_bytes: proto::pty::MAX_QUEUED_BYTES_LIMIT + 1,
+        ..Default::default()
+    });
+    assert!(matches!(
+        validate_create(&create),
+        Err(p::InvalidRequest::BadPtyOptions)
+    ));
+    create.pty_options = Some(proto::pty::Options {
+        scrollback_bytes: proto::pty::SCROLLBACK_BYTES_LIMIT + 1,
+        ..Default::default()
+    });
+    assert!(matches!(
+        validate_create(&create),
+        Err(p::InvalidRequest::BadPtyOptions)
+    ));
+}
+
+#[test]
+fn validate_lengths() {
+    let mut create = create_request(None, None, None);
+    create.user = "x".repeat(p::MAX_USER_LEN + 1);
+    assert!(matches!(
+        validate_create(&create),
+        Err(p::InvalidRequest::TooLong)
+    ));
+    let program = format!("/{}", "x".repeat(p::MAX_PROGRAM_LEN));
+    assert!(matches!(
+        validate_create(&create_request(Some(&program), Some(&["x"]), None)),
+        Err(p::InvalidRequest::TooLong)
+    ));
+
+    let many = vec!["x"; p::MAX_ARGS + 1];
+    assert!(matches!(
+        validate_create(&create_request(Some("/bin/x"), Some(&many), None)),
+        Err(p::InvalidRequest::TooLong)
+    ));
+    let long = format!("X={}", "x".repeat(p::MAX_ARG_LEN));
+    assert!(matches!(
+        validate_create(&create_request(None, None, Some(&[long.as_str()]))),
+        Err(p::InvalidRequest::TooLong)
+    ));
+    let most = vec!["X=x"; p::MAX_ARGS];
+    assert!(validate_create(&create_request(None, None, Some(&most))).is_ok());
+}
+
+#[test]
+fn malformed_is_cut_short() {
+    // Multi-byte characters, so the cut must avoid splitting one.
+    let explanation = "ä".repeat(p::MAX_MALFORMED_LEN);
+    match malformed(&explanation) {
+        p::InvalidRequest::Malformed(cut) => {
+            assert!(cut.len() <= p::MAX_MALFORMED_LEN);
+            assert!(explanation.starts_with(&cut));
+            assert!(cut.len() >= p::MAX_MALFORMED_LEN - 1);
+        }
+        other => panic!("unexpected: {:?}", other),
+    }
+}
+
+#[test]
+fn validate_env() {
+    assert!(matches!(
+        validate_create(&create_request(None, None, Some(&["TERM"]))),
+        Err(p::InvalidRequest::BadEnvironment)
+    ));
+    assert!(matches!(
+        validate_create(&create_request(None, None, Some(&["=xterm"]))),
+        Err(p::InvalidRequest::BadEnvironment)
+    ));
+}
+
+#[test]
+fn validate_nul() {
+    assert!(matches!(
+        validate_create(&create_request(
+            Some("/bin/sh"),
+            Some(&["sh", "-c\0"]),
+            None
+        )),
+        Err(p::InvalidRequest::ContainsNul)
+    ));
+    assert!(matches!(
+        validate_create(&create_request(None, None, Some(&["TERM=x\0term"]))),
+        Err(p::InvalidRequest::ContainsNul)
+    ));
+}
+
+#[test]
+fn create_attach_list() {
+    let conn = connect(FakeShellStarter::new(), InProcessPtyService);
+    let (id, client) = create(
+        &conn,
+        create_request(Some("/bin/cat"), Some(&["cat"]), Some(&["TERM=dumb"])),
+    );
+
+    // The client is attached, and sees the PTY echo its input.
+    {
+        use crate::proto::pty::user as p;
+        ipc::handshake::handshake_as_client(&client, p::CLIENT_INTENT, p::SERVER_INTENT)
+            .expect("handshake as pty_user client");
+        let msg = p::Input::KeyboardInput(b"xyzzy\n".to_vec());
+        client.send_with_fds(&msg).expect("send KeyboardInput");
+        expect_output(&client, b"xyzzy");
+    }
+
+    let (_other_client, other_server_socket) = ipc::seqpacket::pair().expect("socketpair");
+    let response = request(
+        &conn,
+        &p::Request::AttachSession(p::AttachSession {
+            id,
+            fd: other_server_socket,
+        }),
+    );
+    assert!(matches!(response, p::Response::Attached));
+
+    let infos = list(&conn);
+    assert_eq!(infos.len(), 1);
+    assert_eq!(infos[0].id, id);
+    assert_eq!(infos[0].program.as_deref(), Some("/bin/cat"));
+    assert!(matches!(infos[0].state, p::SessionState::Running));
+}
+
+#[test]
+fn list_pages() {
+    let conn = connect(FakeShellStarter::new(), InProcessPtyService);
+    let ids: Vec<p::SessionId> = (0..p::SESSIONS_PER_PAGE + 2)
+        .map(|_| create(&conn, create_request(None, None, None)).0)
+        .collect();
+
+    match request(
+        &conn,
+        &p::Request::ListSessions(p::ListSessions { start: 0 }),
+    ) {
+        p::Response::Sessions { sessions, next } => {
+            assert_eq!(sessions.len(), p::SESSIONS_PER_PAGE);
+            assert_eq!(next, Some(p::SESSIONS_PER_PAGE as u32));
+        }
+        response => panic!("unexpected response: {:?}", response),
+    }
+    let listed: Vec<p::SessionId> = list(&conn).iter().map(|info| info.id).collect();
+    assert_eq!(listed, ids);
+
+    // Past the end.
+    match request(
+        &conn,
+        &p::Request::ListSessions(p::ListSessions { start: 1000 }),
+    ) {
+        p::Response::Sessions { sessions, next } => {
+            assert!(sessions.is_empty());
+            assert_eq!(next, None);
+        }
+        response => panic!("unexpected response: {:?}", response),
+    }
+}
+
+#[test]
+fn terminate() {
+    let conn = connect(FakeShellStarter::new(), InProcessPtyService);
+    let (id, _client) = create(&conn, create_request(None, None, None));
+    let response = request(
+        &conn,
+        &p::Request::TerminateSession(p::TerminateSession { id }),
+    );
+    assert!(matches!(response, p::Response::Terminated));
+
+    // The PTY service notices the end of the session, and tells us.
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let infos = list(&conn);
+        if matches!(infos[0].state, p::SessionState::Exited { .. }) {
+            break;
+        }
+        assert!(Instant::now() < deadline, "session did not exit");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    let response = request(
+        &conn,
+        &p::Request::TerminateSession(p::TerminateSession { id }),
+    );
+    assert!(matches!(response, p::Response::NoSuchSession));
+}
+
+#[test]
+fn exit_status() {
+    let conn = connect(FakeShellStarter::new(), InProcessPtyService);
+    let (id, client) = create(
+        &conn,
+        create_request(
+            Some("/bin/sh"),
+            Some(&["sh", "-c", "read line; exit 5"]),
+            None,
+        ),
+    );
+
+    // Attached clients see the exit status as the last message.
+    {
+        use crate::proto::pty::user as p;
+        ipc::handshake::handshake_as_client(&client, p::CLIENT_INTENT, p::SERVER_INTENT)
+            .expect("handshake as pty_user client");
+        let msg = p::Input::KeyboardInput(b"xyzzy\n".to_vec());
+        client.send_with_fds(&msg).expect("send KeyboardInput");
+        loop {
+            let output: p::Output = client.receive_with_fds().expect("receive output");
+            match output {
+                p::Output::SessionOutput(_) => continue,
+                p::Output::SessionEnded { exit_status } => {
+                    assert_eq!(exit_status, Some(crate::proto::ExitStatus::Exited(5)));
+                    break;
+                }
+                _ => panic!("unexpected output: {:?}", output),
+            }
+        }
+    }
+
+    // The listing catches up once the PTY service has been told.
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let infos = list(&conn);
+        assert_eq!(infos[0].id, id);
+        if let p::SessionState::Exited { status, .. } = infos[0].state {
+            assert_eq!(status, Some(crate::proto::ExitStatus::Exited(5)));
+            break;
+        }
+        assert!(Instant::now() < deadline, "session did not exit");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn attach_unknown() {
+    let conn = connect(FakeShellStarter::new(), InProcessPtyService);
+    let (_client, server_socket) = ipc::seqpacket::pair().expect("socketpair");
+    let response = request(
+        &conn,
+        &p::Request::AttachSession(p::AttachSession {
+            id: p::SessionId([0; p::SESSION_ID_BYTES]),
+            fd: server_socket,
+        }),
+    );
+    assert!(matches!(response, p::Response::NoSuchSession));
+}
+
+#[test]
+fn create_denied() {
+    let conn = connect(
+        FakeShellStarter::failing(dbus_shell::ErrorKind::Denied),
+        InProcessPtyService,
+    );
+    let response = request(
+        &conn,
+        &p::Request::CreateShellSession(create_request(None, None, None)),
+    );
+    assert!(matches!(response, p::Response::Denied));
+    // The session ID was given up.
+    assert!(list(&conn).is_empty());
+}
+
+#[test]
+fn create_pty_service_unavailable() {
+    let conn = connect(FakeShellStarter::new(), UnavailablePtyService);
+    let response = request(
+        &conn,
+        &p::Request::CreateShellSession(create_request(None, None, None)),
+    );
+    assert!(matches!(response, p::Response::PtyServiceUnavailable));
+    assert!(list(&conn).is_empty());
+}
+
+#[test]
+fn create_invalid() {
+    let conn = connect(FakeShellStarter::new(), InProcessPtyService);
+    let response = request(
+        &conn,
+        &p::Request::CreateShellSession(create_request(Some("cat"), Some(&["cat"]), None)),
+    );
+    assert!(matches!(
+        response,
+        p::Response::InvalidRequest(p::InvalidRequest::ProgramNotAbsolute)
+    ));
+}
+
+#[test]
+fn list_machines() {
+    let conn = connect(FakeShellStarter::new(), InProcessPtyService);
+    let machines = match request(&conn, &p::Request::ListMachines) {
+        p::Response::Machines(machines) => machines,
+        response => panic!("unexpected response: {:?}", response),
+    };
+    let names: Vec<String> = machines
+        .iter()
+        .map(|info| match &info.machine {
+            p::Machine::Host => ".host".to_string(),
+            p::Machine::Container(name) => name.to_string(),
+        })
+        .collect();
+    assert_eq!(names, vec![".host", "thud", "xyzzy"]);
+    assert_eq!(machines[1].class, "container");
+    assert_eq!(machines[1].service, "systemd-nspawn");
+    assert_eq!(machines[1].state, "running");
+}
+
+#[test]
+fn list_machines_denied() {
+    let conn = connect(
+        FakeShellStarter::failing(dbus_shell::ErrorKind::Denied),
+        InProcessPtyService,
+    );
+    let response = request(&conn, &p::Request::ListMachines);
+    assert!(matches!(response, p::Response::Denied));
+}
+
+fn run_request(machine: p::Machine, program: &str, args: &[&str]) -> (p::RunCommand, SeqPacket) {
+    let (client_socket, server_socket) = ipc::seqpacket::pair().expect("socketpair");
+    let run = p::RunCommand {
+        fd: server_socket,
+        machine,
+        user: "testuser".to_string(),
+        program: program.to_string(),
+        args: args.iter().map(|s| s.to_string()).collect(),
+        env: Some(vec!["XYZZY=thud".to_string()]),
+    };
+    let client = SeqPacket::try_from(client_socket).expect("convert to SeqPacket");
+    (run, client)
+}
+
+#[test]
+fn run_command() {
+    use crate::proto::command as c;
+    let conn = connect(FakeShellStarter::new(), InProcessPtyService);
+    let (run, client) = run_request(
+        p::Machine::Host,
+        "/bin/sh",
+        &["sh", "-c", "cat; echo \"$XYZZY\" >&2; exit 3"],
+    );
+    let response = request(&conn, &p::Request::RunCommand(run));
+    assert!(matches!(response, p::Response::CommandStarted));
+
+    ipc::handshake::handshake_as_client(&client, c::CLIENT_INTENT, c::SERVER_INTENT)
+        .expect("handshake");
+    client
+        .send_with_fds(&c::Input::Stdin(b"xyzzy".to_vec()))
+        .expect("send input");
+    client
+        .send_with_fds(&c::Input::StdinEnd)
+        .expect("send input");
+    let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+    let status = loop {
+        let output: c::Output = client.receive_with_fds().expect("receive output");
+        match output {
+            c::Output::Stdout(data) => stdout.extend(data),
+            c::Output::Stderr(data) => stderr.extend(data),
+            c::Output::Finished(status) => break status,
+        }
+    };
+    assert_eq!(stdout, b"xyzzy");
+    assert_eq!(stderr, b"thud\n");
+    assert_eq!(status, Some(crate::proto::ExitStatus::Exited(3)));
+}
+
+#[test]
+fn run_command_killed() {
+    use crate::proto::command as c;
+    let conn = connect(FakeShellStarter::new(), InProcessPtyService);
+    let (run, client) = run_request(p::Machine::Host, "/bin/sh", &["sh", "-c", "kill -9 $$"]);
+    let response = request(&conn, &p::Request::RunCommand(run));
+    assert!(matches!(response, p::Response::CommandStarted));
+    ipc::handshake::handshake_as_client(&client, c::CLIENT_INTENT, c::SERVER_INTENT)
+        .expect("handshake");
+    // Never sending StdinEnd, the command doesn't read its input anyway.
+    let output: c::Output = client.receive_with_fds().expect("receive output");
+    assert!(matches!(
+        output,
+        c::Output::Finished(Some(crate::proto::ExitStatus::Killed(libc::SIGKILL)))
+    ));
+}
+
+#[test]
+fn run_command_container() {
+    let conn = connect(FakeShellStarter::new(), InProcessPtyService);
+    let (run, _client) = run_request(
+        p::Machine::Container("xyzzy".parse().expect("container name")),
+        "/bin/true",
+        &["true"],
+    );
+    let response = request(&conn, &p::Request::RunCommand(run));
+    assert!(matches!(response, p::Response::Unsupported));
+}
+
+#[test]
+fn run_command_invalid() {
+    let conn = connect(FakeShellStarter::new(), InProcessPtyService);
+    let (run, _client) = run_request(p::Machine::Host, "/bin/true", &[]);
+    let response = request(&conn, &p::Request::RunCommand(run));
+    assert!(matches!(
+        response,
+        p::Response::InvalidRequest(p::InvalidRequest::MissingArgs)
+    ));
+}
@@ -81,6 +81,141 @@ fn pty_dynamic_user_isolation() {
     assert!(duplicates.is_empty(), "duplicate UIDs");
 }
 
+#[test]
+fn sessions_attach_unknown() {
+    use tere_server::ipc::IPC;
+    use tere_server::proto::sessions as p;
+    let path = Path::new("/run/tere/socket/sessions.socket");
+    let conn = SeqPacket::connect(path).expect("connect");
+    ipc::handshake::handshake_as_client(&conn, p::CLIENT_INTENT, p::SERVER_INTENT)
+        .expect("handshake");
+    let (_client_socket, server_socket) = ipc::seqpacket::pair().expect("socketpair");
+    let message = p::Request::AttachSession(p::AttachSession {
+        id: p::SessionId([0; p::SESSION_ID_BYTES]),
+        fd: server_socket,
+    });
+    conn.send_with_fds(&message).expect("send request");
+    let response: p::Response = conn.receive_with_fds().expect("receive response");
+    assert!(matches!(response, p::Response::NoSuchSession));
+}
+
+#[test]
+fn sessions_create_relative_program() {
+    use tere_server::ipc::IPC;
+    use tere_server::proto::sessions as p;
+    let path = Path::new("/run/tere/socket/sessions.socket");
+    let conn = SeqPacket::connect(path).expect("connect");
+    ipc::handshake::handshake_as_client(&conn, p::CLIENT_INTENT, p::SERVER_INTENT)
+        .expect("handshake");
+    let (_client_socket, server_socket) = ipc::seqpacket::pair().expect("socketpair");
+    let message = p::Request::CreateShellSession(p::CreateShellSession {
+        fd: server_socket,
+        machine: p::Machine::Host,
+        user: "testuser".to_string(),
+        program: Some("sh".to_string()),
+        args: Some(vec!["sh".to_string()]),
+        env: None,
+        pty_options: None,
+    });
+    conn.send_with_fds(&message).expect("send request");
+    let response: p::Response = conn.receive_with_fds().expect("receive response");
+    assert!(matches!(
+        response,
+        p::Response::InvalidRequest(p::InvalidRequest::ProgramNotAbsolute)
+    ));
+}
+
+#[test]
+fn sessions_create_no_such_machine() {
+    use tere_server::ipc::IPC;
+    use tere_server::proto::sessions as p;
+    let path = Path::new("/run/tere/socket/sessions.socket");
+    let conn = SeqPacket::connect(path).expect("connect");
+    ipc::handshake::handshake_as_client(&conn, p::CLIENT_INTENT, p::SERVER_INTENT)
+        .expect("handshake");
+    let (_client_socket, server_socket) = ipc::seqpacket::pair().expect("socketpair");
+    let message = p::Request::CreateShellSession(p::CreateShellSession {
+        fd: server_socket,
+        machine: p::Machine::Container("tere-test-nonexistent".parse().expect("container name")),
+        user: "testuser".to_string(),
+        program: None,
+        args: None,
+        env: None,
+        pty_options: None,
+    });
+    conn.send_with_fds(&message).expect("send request");
+    let response: p::Response = conn.receive_with_fds().expect("receive response");
+    assert!(matches!(response, p::Response::NoSuchMachine));
+}
+
+#[test]
+fn sessions_list_machines() {
+    use tere_server::ipc::IPC;
+    use tere_server::proto::sessions as p;
+    let path = Path::new("/run/tere/socket/sessions.socket");
+    let conn = SeqPacket::connect(path).expect("connect");
+    ipc::handshake::handshake_as_client(&conn, p::CLIENT_INTENT, p::SERVER_INTENT)
+        .expect("handshake");
+    conn.send_with_fds(&p::Request::ListMachines)
+        .expect("send request");
+    let response: p::Response = conn.receive_with_fds().expect("receive response");
+    match response {
+        p::Response::Machines(machines) => {
+            let host = machines.first().expect("no machines listed");
+            assert!(matches!(host.machine, p::Machine::Host));
+            assert_eq!(host.class, "host");
+            assert_eq!(host.state, "running");
+        }
+        _ => panic!("unexpected response: {:?}", response),
+    }
+}
+
+#[test]
+fn sessions_run_command() {
+    use tere_server::ipc::IPC;
+    use tere_server::proto::command as c;
+    use tere_server::proto::sessions as p;
+    let path = Path::new("/run/tere/socket/sessions.socket");
+    let conn = SeqPacket::connect(path).expect("connect");
+    ipc::handshake::handshake_as_client(&conn, p::CLIENT_INTENT, p::SERVER_INTENT)
+        .expect("handshake");
+    let (client_socket, server_socket) = ipc::seqpacket::pair().expect("socketpair");
+    let message = p::Request::RunCommand(p::RunCommand {
+        fd: server_socket,
+        machine: p::Machine::Host,
+        user: "testuser".to_string(),
+        program: "/bin/sh".to_string(),
+        args: vec![
+            "sh".to_string(),
+            "-c".to_string(),
+            "id -un; echo oops >&2; exit 7".to_string(),
+        ],
+        env: None,
+    });
+    conn.send_with_fds(&message).expect("send request");
+    let response: p::Response = conn.receive_with_fds().expect("receive response");
+    assert!(matches!(response, p::Response::CommandStarted));
+
+    let client = SeqPacket::try_from(client_socket).expect("convert client socket to SeqPacket");
+    ipc::handshake::handshake_as_client(&client, c::CLIENT_INTENT, c::SERVER_INTENT)
+        .expect("handshake");
+    client
+        .send_with_fds(&c::Input::StdinEnd)
+        .expect("send input");
+    let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+    let status = loop {
+        let output: c::Output = client.receive_with_fds().expect("receive output");
+        match output {
+            c::Output::Stdout(data) => stdout.extend(data),
+            c::Output::Stderr(data) => stderr.extend(data),
+            c::Output::Finished(status) => break status,
+        }
+    };
+    assert_eq!(String::from_utf8_lossy(&stdout), "testuser\n");
+    assert_eq!(String::from_utf8_lossy(&stderr), "oops\n");
+    assert_eq!(status, Some(c::ExitStatus::Exited(7)));
+}
+
 #[test]
 fn sessions_create() {
     use tere_server::ipc::IPC;
@@ -99,8 +234,41 @@ fn sessions_create() {
             program: None,
             args: None,
             env: None,
+            pty_options: None,
+        });
+        conn.send_with_fds(&message).expect("send request");
+        let response: p::Response = conn.receive_with_fds().expect("receive response");
+        let id = match response {
+            p::Response::Created { id } => id,
+            _ => panic!("unexpected response: {:?}", response),
+        };
+
+        // A second client can attach to the same session.
+        let (_other_client_socket, other_server_socket) =
+            ipc::seqpacket::pair().expect("socketpair");
+        let message = p::Request::AttachSession(p::AttachSession {
+            id,
+            fd: other_server_socket,
         });
         conn.send_with_fds(&message).expect("send request");
+        let response: p::Response = conn.receive_with_fds().expect("receive response");
+        assert!(matches!(response, p::Response::Attached));
+
+        conn.send_with_fds(&p::Request::ListSessions(p::ListSessions { start: 0 }))
+            .expect("send request");
+        let response: p::Response = conn.receive_with_fds().expect("receive response");
+        match response {
+            p::Response::Sessions { sessions, .. } => {
+                let info = sessions
+                    .iter()
+                    .find(|info| info.id == id)
+                    .expect("session not listed");
+                assert_eq!(info.user, "testuser");
+                assert!(matches!(info.state, p::SessionState::Running));
+            }
+            _ => panic!("unexpected response: {:?}", response),
+        }
+
         client_socket
     };
 
@@ -128,6 +296,11 @@ fn sessions_create() {
                     println!("output: {}", String::from_utf8_lossy(&b));
                     output.extend_from_slice(&b);
                 }
+                p::Output::SlowConsumer(policy) => panic!("slow consumer: {:?}", policy),
+                p::Output::SessionEnded { exit_status } => {
+                    assert_eq!(exit_status, Some(tere_server::proto::ExitStatus::Exited(0)));
+                    break;
+                }
             }
             // It seems control-D sent too early (before bash is reading?) is just simply ignored.
             // If that worked, we'd send one right after sending the input, before the loop.